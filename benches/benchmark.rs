@@ -2,7 +2,7 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::prelude::SliceRandom;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
-use bachelorthesis::{sort, sort_parallel};
+use bachelorthesis::{sort, SortContext};
 
 fn benchmark_quicksort(c: &mut Criterion) {
     let mut data: Vec<u64> = (0..134217728/2).collect(); // Example data
@@ -32,10 +32,11 @@ fn benchmark_ips2ra_par(c: &mut Criterion) {
     let mut data: Vec<u64> = (0..134217728/2).collect(); // Example data
     let mut rng = StdRng::seed_from_u64(12345);
     data.shuffle(&mut rng);
+    let mut context = SortContext::new().unwrap();
     c.bench_function("IPS2Ra 1/2 GiB", |b| {
         data.shuffle(&mut rng);
         b.iter(|| {
-            sort_parallel(black_box(&mut data));
+            context.sort_parallel(black_box(&mut data));
         })
     });
 }
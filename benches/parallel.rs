@@ -1,10 +1,6 @@
-use rand::prelude::*;
 use std::{env};
-use std::time::Duration;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
 use rayon::prelude::ParallelSliceMut;
-use bachelorthesis::{sort_parallel, BLOCKSIZE, THRESHOLD};
+use bachelorthesis::{BenchReport, BenchResult, Distribution, SortContext, Workload};
 
 
 pub fn main() {
@@ -47,50 +43,38 @@ pub fn main() {
         }
     };
 
+    let mut context = SortContext::new().unwrap();
+
     // warm-up
     {
         let max_size = *sizes.iter().max().unwrap();
-        let mut data = generate_uniform(&mut StdRng::seed_from_u64(seed), max_size);
-        sort_parallel(&mut data);
+        let mut data = Workload::new(Distribution::Uniform, max_size, seed).generate();
+        context.sort_parallel(&mut data);
     }
 
-    println!("Warm up complete, staring benchmark");
+    eprintln!("Warm up complete, staring benchmark");
 
-    let mut measurements: Vec<Duration> = Vec::with_capacity(sizes.len());
-    let mut rng = StdRng::seed_from_u64(seed);
+    let algorithm = match mode {
+        0 => "ips2ra_parallel",
+        1 => "rayon_par_sort",
+        2 => "rayon_par_sort_unstable",
+        _ => panic!("Invalid mode"),
+    };
+    let mut report = BenchReport::from_env().unwrap();
     for i in 0..sizes.len() {
-        let mut local_measurements: Vec<Duration> = Vec::with_capacity(iterations);
-        for _ in 0..iterations {
-            let mut data = generate_uniform(&mut rng, sizes[i]);
-            let mut start = std::time::Instant::now();
+        let mut result = BenchResult::new("parallel", algorithm, &Distribution::Uniform.to_string(), sizes[i], context.num_threads());
+        for it in 0..iterations {
+            let mut data = Workload::new(Distribution::Uniform, sizes[i], seed + (i * iterations + it) as u64).generate();
+            let start = std::time::Instant::now();
             match mode {
-                0 => sort_parallel(&mut data),
+                0 => { context.sort_parallel(&mut data); }
                 1 => data.par_sort(),
                 2 => data.par_sort_unstable(),
                 _ => panic!("Invalid mode"),
             }
-            let duration = start.elapsed();
-            local_measurements.push(duration);
+            result.durations.push(start.elapsed());
         }
-        let avg = local_measurements.iter().sum::<Duration>() / iterations as u32;
-        measurements.push(avg);
-    }
-    // print as table
-    match mode {
-        0 => println!("IPS2Ra: BLOCKSIZE = {}, THRESHOLD = {}", BLOCKSIZE, THRESHOLD),
-        1 => println!("Rayon par_sort()"),
-        2 => println!("Rayon par_sort_unstable()"),
-        _ => {}
+        report.push(result);
     }
-    println!("Sizes: {:?}", sizes);
-    // print times in seconds
-    println!("{:?}", measurements.iter().map(|d| d.as_secs_f64()).collect::<Vec<f64>>());
-
-}
-
-// uniform distribution
-fn generate_uniform(rng: &mut StdRng, length: usize) -> Vec<u64> {
-    (0..length)
-        .map(|_| rng.gen::<u64>())
-        .collect()
+    report.finish().unwrap();
 }
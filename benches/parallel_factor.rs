@@ -1,9 +1,6 @@
-use rand::prelude::*;
 use std::{env};
 use std::time::Duration;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
-use bachelorthesis::{sort_parallel};
+use bachelorthesis::{Distribution, SortContext, Workload};
 
 pub fn main() {
     let mut args = env::args();
@@ -31,31 +28,26 @@ pub fn main() {
         }
     };
 
+    let mut context = SortContext::new().unwrap();
+
     // warm up
     {
-        let mut data = generate_uniform(&mut StdRng::seed_from_u64(seed), size);
-        sort_parallel(&mut data);
+        let mut data = Workload::new(Distribution::Uniform, size, seed).generate();
+        context.sort_parallel(&mut data);
     }
     println!("Starting benchmark");
     let mut measurements: Vec<Duration> = Vec::new();
 
     for i in 0..iterations {
-        let mut data = generate_uniform(&mut StdRng::seed_from_u64(seed), size);
+        let mut data = Workload::new(Distribution::Uniform, size, seed).generate();
         println!("Iteration {}", i);
         let mut start = std::time::Instant::now();
-        sort_parallel(&mut data);
+        context.sort_parallel(&mut data);
         let duration = start.elapsed();
         measurements.push(duration);
     }
 
     let avg = measurements.iter().sum::<Duration>() / iterations as u32;
-    println!("Parallel Sort using {} threads: Avg {:?}", rayon::current_num_threads(), avg);
-
-}
+    println!("Parallel Sort using {} threads: Avg {:?}", context.num_threads(), avg);
 
-// uniform distribution
-fn generate_uniform(rng: &mut StdRng, length: usize) -> Vec<u64> {
-    (0..length)
-        .map(|_| rng.gen::<u64>())
-        .collect()
 }
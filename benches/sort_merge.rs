@@ -1,11 +1,6 @@
-use rand::prelude::*;
-use std::{env, io};
+use std::env;
 use std::error::Error;
-use std::time::Duration;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
-use rayon::prelude::ParallelSliceMut;
-use bachelorthesis::{BLOCKSIZE, sort, THRESHOLD, initialize_thread_pool, NUM_THREADS, prepare_benchmark, rolling_sort, HUGE_PAGE_SIZE_1G, sort_merge_initialize_thread_local, sort_merge, sort_parallel};
+use bachelorthesis::{BenchReport, BenchResult, Distribution, NUM_THREADS, HUGE_PAGE_SIZE_1G, SortContext};
 
 
 pub fn main() -> Result<(), Box<dyn Error>>{
@@ -42,37 +37,19 @@ pub fn main() -> Result<(), Box<dyn Error>>{
         }
     };
 
-    let mut nvme = vroom::init(&pci_addr)?;
-    let mut measurements: Vec<Vec<Duration>> = Vec::with_capacity(hugepages.len());
+    let mut context = SortContext::with_device(vroom::init(&pci_addr)?)?;
+    let mut report = BenchReport::from_env()?;
 
     for i in 0..hugepages.len() {
-        let mut local_measurements: Vec<Duration> = Vec::with_capacity(iterations);
+        let len = hugepages[i] * HUGE_PAGE_SIZE_1G / 8;
+        let mut result = BenchResult::new("sort_merge", "parallel_sort_merge", &Distribution::Uniform.to_string(), len, NUM_THREADS);
         for _ in 0..iterations {
-            nvme = prepare_benchmark(nvme, hugepages[i], seed as usize);
-            let mut start = std::time::Instant::now();
-            nvme = sort_merge(nvme, hugepages[i] * HUGE_PAGE_SIZE_1G / 8, true)?;
-            let duration = start.elapsed();
-            local_measurements.push(duration);
+            context.prepare_benchmark(hugepages[i], seed as usize)?;
+            let start = std::time::Instant::now();
+            context.sort_merge(len, true)?;
+            result.durations.push(start.elapsed());
         }
-        measurements.push(local_measurements);
+        report.push(result);
     }
-    for i in 0..hugepages.len() {
-        let avg = (measurements[i].iter().sum::<Duration>() / iterations as u32).as_secs_f64();
-        let min = measurements[i].iter().min().unwrap().as_secs_f64();
-        let max = measurements[i].iter().max().unwrap().as_secs_f64();
-        println!("Number of hugepages: {:?}: Avg {:?}, Min: {:?}, Max: {:?}", hugepages[i], avg, min, max);
-    }
-
-    println!("all results: {:?}", measurements);
-
-    Ok(())
+    report.finish()
 }
-
-fn generate_uniform(rng: &mut StdRng, length: usize) -> Vec<u64> {
-    (0..length)
-        .map(|_| rng.gen::<u64>())
-        .collect()
-}
-
-
-
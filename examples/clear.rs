@@ -1,8 +1,10 @@
-use bachelorthesis::{clear_chunks, CHUNKS_PER_HUGE_PAGE_1G};
+use bachelorthesis::{clear_lbas, ClearStrategy, CHUNKS_PER_HUGE_PAGE_1G, LBA_PER_CHUNK};
 
 pub fn main(){
     let mut nvme = vroom::init("0000:03:00.0").unwrap();
+    let strategy = ClearStrategy::detect(&nvme);
+    println!("Clearing with {:?}", strategy);
     let mut qpair = nvme.create_io_queue_pair(vroom::QUEUE_LENGTH).unwrap();
-    clear_chunks(CHUNKS_PER_HUGE_PAGE_1G*9, &mut qpair);
+    clear_lbas(&mut qpair, strategy, 0, CHUNKS_PER_HUGE_PAGE_1G*LBA_PER_CHUNK*9).unwrap();
     println!("Cleared 9 hugepages");
 }
\ No newline at end of file
@@ -34,7 +34,7 @@ pub fn main() {
     let mut buffer = Dma::allocate(HUGE_PAGE_SIZE_1G).unwrap();
 
     println!("Clearing chunks");
-    clear_chunks((num_hugepages+2)*CHUNKS_PER_HUGE_PAGE_1G, &mut qpair);
+    clear_chunks((num_hugepages+2)*CHUNKS_PER_HUGE_PAGE_1G, &mut qpair).unwrap();
     println!("Done");
 
     for i in 0..num_hugepages{
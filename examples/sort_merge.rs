@@ -31,7 +31,7 @@ fn main() -> Result<(), Box<dyn Error>>{
 
     let mut nvme = vroom::init(&pci_addr)?;
     let start = Instant::now();
-    nvme = sort_merge(nvme, num_hugepages* HUGE_PAGE_SIZE_1G/8, false)?;
+    nvme = sort_merge(nvme, num_hugepages* HUGE_PAGE_SIZE_1G/8, false)?.0;
     let duration = start.elapsed();
     println!("Duration: {:?}", duration);
 
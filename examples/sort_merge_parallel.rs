@@ -32,7 +32,7 @@ fn main() -> Result<(), Box<dyn Error>>{
     let len = num_hugepages * HUGE_PAGE_SIZE_1G/8;
 
     let mut nvme = vroom::init(&pci_addr)?;
-    nvme = sort_merge(nvme, len, true)?;
+    nvme = sort_merge(nvme, len, true)?.0;
 
     Ok(())
 }
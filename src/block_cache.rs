@@ -0,0 +1,280 @@
+use crate::config::*;
+use crate::conversion::*;
+use vroom::{NvmeQueuePair, QUEUE_LENGTH};
+use vroom::memory::{Dma, DmaSlice};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::error::Error;
+use log::{debug, warn};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    dirty: bool,
+    in_flight: bool,
+}
+
+/// Clock (second chance) replacement over a fixed number of slots holding one line each.
+/// Only tracks which line is in which slot, the I/O of the lines is up to the caller.
+#[derive(Debug, Clone)]
+pub struct ClockPolicy {
+    slots: Vec<Option<usize>>,
+    referenced: Vec<bool>,
+    lines: HashMap<usize, usize>,
+    hand: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl ClockPolicy {
+    pub fn new(num_slots: usize) -> Self {
+        assert!(num_slots > 0, "Clock needs at least one slot");
+        Self {
+            slots: vec![None; num_slots],
+            referenced: vec![false; num_slots],
+            lines: HashMap::with_capacity(num_slots),
+            hand: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Slot holding `line`, counted as hit or miss. A hit gives the line another chance.
+    pub fn lookup(&mut self, line: usize) -> Option<usize> {
+        match self.lines.get(&line) {
+            Some(&slot) => {
+                self.hits += 1;
+                self.referenced[slot] = true;
+                Some(slot)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        self.lines.contains_key(&line)
+    }
+
+    pub fn line(&self, slot: usize) -> Option<usize> {
+        self.slots[slot]
+    }
+
+    /// Next slot to replace, a free one or the first without a second chance left after the hand
+    pub fn victim(&mut self) -> usize {
+        loop {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            if self.slots[slot].is_some() && self.referenced[slot] {
+                self.referenced[slot] = false;
+                continue;
+            }
+            return slot;
+        }
+    }
+
+    /// Puts `line` into `slot`, returns the line it replaces
+    pub fn insert(&mut self, slot: usize, line: usize) -> Option<usize> {
+        let old = self.slots[slot].replace(line);
+        if let Some(old) = old {
+            self.lines.remove(&old);
+        }
+        self.lines.insert(line, slot);
+        self.referenced[slot] = true;
+        old
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
+/// Write-back cache of chunk-sized lines over the 2MiB DMA buffers of a sorter, addressed by element position on the device.
+/// Each line is read and written with a single command, dirty lines are written back in batches
+/// and `prefetch` reads lines ahead without waiting for them.
+/// A failed command fails the access that waits for it, the cache content is undefined afterwards.
+pub(crate) struct BlockCache<'a> {
+    qpair: &'a mut NvmeQueuePair,
+    buffers: &'a mut [Dma<u8>],
+    slots: Vec<Slot>,
+    clock: ClockPolicy,
+    in_flight: Vec<usize>,
+}
+
+impl<'a> BlockCache<'a> {
+    pub(crate) fn new(qpair: &'a mut NvmeQueuePair, buffers: &'a mut [Dma<u8>]) -> Self {
+        let num_slots = min(buffers.len() * CHUNKS_PER_HUGE_PAGE_2M, BLOCK_CACHE_SIZE / CHUNK_SIZE);
+        assert!(num_slots >= 4, "Block cache needs at least four lines");
+        Self {
+            qpair,
+            buffers,
+            slots: vec![Slot::default(); num_slots],
+            clock: ClockPolicy::new(num_slots),
+            in_flight: Vec::with_capacity(QUEUE_LENGTH),
+        }
+    }
+
+    /// Copies the elements starting at `position` into `out`
+    pub(crate) fn read(&mut self, position: usize, out: &mut [u64]) -> Result<(), Box<dyn Error>> {
+        let mut done = 0;
+        while done < out.len() {
+            let (line, idx) = Self::locate(position + done);
+            let n = min(out.len() - done, ELEMENTS_PER_CHUNK - idx);
+            let slot = self.slot(line)?;
+            out[done..done + n].copy_from_slice(&u8_to_u64_slice(self.bytes(slot))[idx..idx + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to the elements starting at `position`, the lines are written back on eviction or `flush`
+    pub(crate) fn write(&mut self, position: usize, data: &[u64]) -> Result<(), Box<dyn Error>> {
+        let mut done = 0;
+        while done < data.len() {
+            let (line, idx) = Self::locate(position + done);
+            let n = min(data.len() - done, ELEMENTS_PER_CHUNK - idx);
+            let slot = self.slot(line)?;
+            self.slots[slot].dirty = true;
+            u8_to_u64_slice(self.bytes(slot))[idx..idx + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&mut self, position: usize) -> Result<u64, Box<dyn Error>> {
+        let mut element = [0u64];
+        self.read(position, &mut element)?;
+        Ok(element[0])
+    }
+
+    /// Starts reading the lines of `len` elements at `position` without waiting for them
+    pub(crate) fn prefetch(&mut self, position: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        if len == 0 {
+            return Ok(());
+        }
+        let (first, _) = Self::locate(position);
+        let (last, _) = Self::locate(position + len - 1);
+        for line in first..=last {
+            if !self.clock.contains(line) {
+                let slot = self.allocate(line)?;
+                self.submit(slot, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty lines and waits for them
+    pub(crate) fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        for slot in 0..self.slots.len() {
+            if self.slots[slot].dirty {
+                if self.slots[slot].in_flight {
+                    self.wait()?;
+                }
+                self.slots[slot].dirty = false;
+                self.submit(slot, true)?;
+            }
+        }
+        self.wait()
+    }
+
+    fn locate(position: usize) -> (usize, usize) {
+        (position / ELEMENTS_PER_CHUNK, position % ELEMENTS_PER_CHUNK)
+    }
+
+    fn bytes(&mut self, slot: usize) -> &mut [u8] {
+        let chunk = slot % CHUNKS_PER_HUGE_PAGE_2M;
+        &mut self.buffers[slot / CHUNKS_PER_HUGE_PAGE_2M][chunk * CHUNK_SIZE..(chunk + 1) * CHUNK_SIZE]
+    }
+
+    // slot holding `line`, read from the device on a miss
+    fn slot(&mut self, line: usize) -> Result<usize, Box<dyn Error>> {
+        if let Some(slot) = self.clock.lookup(line) {
+            if self.slots[slot].in_flight {
+                self.wait()?;
+            }
+            return Ok(slot);
+        }
+        let slot = self.allocate(line)?;
+        self.submit(slot, false)?;
+        self.wait()?;
+        Ok(slot)
+    }
+
+    // frees the victim of the clock, writing it back if dirty, and assigns it to `line`
+    fn allocate(&mut self, line: usize) -> Result<usize, Box<dyn Error>> {
+        let slot = self.clock.victim();
+        if self.slots[slot].dirty {
+            self.write_back(slot)?;
+        }
+        if self.slots[slot].in_flight {
+            self.wait()?;
+        }
+        self.clock.insert(slot, line);
+        self.slots[slot] = Slot::default();
+        Ok(slot)
+    }
+
+    // writes back a batch of dirty lines starting at `first`, so later evictions find clean lines
+    fn write_back(&mut self, first: usize) -> Result<(), Box<dyn Error>> {
+        let mut submitted = 0;
+        for i in 0..self.slots.len() {
+            let slot = (first + i) % self.slots.len();
+            if self.slots[slot].dirty && !self.slots[slot].in_flight {
+                self.slots[slot].dirty = false;
+                self.submit(slot, true)?;
+                submitted += 1;
+                if submitted == QUEUE_LENGTH - 1 {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, slot: usize, write: bool) -> Result<(), Box<dyn Error>> {
+        // the submission queue holds QUEUE_LENGTH - 1 commands
+        if self.in_flight.len() == QUEUE_LENGTH - 1 {
+            self.wait()?;
+        }
+        let line = self.clock.line(slot).unwrap();
+        let chunk = slot % CHUNKS_PER_HUGE_PAGE_2M;
+        let buffer = &self.buffers[slot / CHUNKS_PER_HUGE_PAGE_2M];
+        let submitted = self.qpair.submit_io(&buffer.slice(chunk * CHUNK_SIZE..(chunk + 1) * CHUNK_SIZE), (line * LBA_PER_CHUNK) as u64, write);
+        if submitted != 1 {
+            return Err(format!("Block cache could not submit the {} of line {}", if write { "write" } else { "read" }, line).into());
+        }
+        self.slots[slot].in_flight = true;
+        self.in_flight.push(slot);
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.in_flight.is_empty() {
+            return Ok(());
+        }
+        let completed = self.qpair.complete_io_checked(self.in_flight.len());
+        let lines = self.in_flight.len();
+        for slot in self.in_flight.drain(..) {
+            self.slots[slot].in_flight = false;
+        }
+        if completed.is_none() {
+            return Err(format!("Block cache I/O of {} lines failed", lines).into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BlockCache<'_> {
+    // dirty lines left by an early return are written back, `flush` reports the errors
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("{}", e);
+        }
+        debug!("Block cache: {} hits, {} misses", self.clock.hits(), self.clock.misses());
+    }
+}
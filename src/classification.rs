@@ -90,7 +90,7 @@ impl IPS2RaSorter {
             }
 
             let element = u8_to_u64(&(&buffer[cur_hugepage % num_buffers])[idx * 8..idx * 8 + 8]);
-            let block_idx = find_bucket_ips2ra(element, task.level);
+            let block_idx = task.bucket(element);
             unsafe {
                 debug!("i = {}, idx = {}, cur_hugepage = {}, cur_chunk = {}, element = {}, bucket = {}", i, idx, cur_hugepage, cur_chunk, element, block_idx);
 
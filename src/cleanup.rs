@@ -1,9 +1,8 @@
 use crate::config::*;
-use crate::conversion::*;
 use crate::sorter::*;
 use crate::base_case::{insertion_sort};
-use vroom::memory::{Dma, DmaSlice};
-use vroom::{NvmeQueuePair, QUEUE_LENGTH};
+use crate::block_cache::BlockCache;
+use std::error::Error;
 use log::{debug, info};
 
 impl IPS2RaSorter {
@@ -112,13 +111,7 @@ impl IPS2RaSorter {
         bucket
     }
 
-    pub fn cleanup_ext(&mut self, task: &mut ExtTask){
-        assert!(self.qpair.is_some(), "Cannot classify_in_out without qpair");
-        assert!(self.buffers.is_some(), "Cannot classify_in_out without buffers");
-
-        let qpair = self.qpair.as_mut().unwrap();
-        let buffer = self.buffers.as_mut().unwrap();
-
+    pub(crate) fn cleanup_ext(&mut self, task: &mut ExtTask, cache: &mut BlockCache) -> Result<(), Box<dyn Error>> {
         let first_bucket = 0;
         let last_bucket = K;
 
@@ -138,28 +131,24 @@ impl IPS2RaSorter {
 
             debug!("i={}: bstart: {}, bend: {}, bwrite: {}, dst: {}, remaining: {}", i, bstart, bend, bwrite, dst, remaining);
 
+            // readahead of the head of the next bucket
+            if i + 1 < last_bucket && bend < self.boundaries[i + 2] {
+                cache.prefetch(task.position(bend as usize), 1)?;
+            }
+
             if i == overflow_bucket && self.overflow {
                 debug!("Overflow bucket");
                 let tail_size = BLOCKSIZE - remaining;
                 let mut src = 0;
                 // head
-                // read remaining elements from ssd
-                let (start_lba, start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-                read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, remaining, false);
-                buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + remaining) * 8].copy_from_slice(u64_to_u8_slice(&mut self.overflow_buffer[..remaining]));
-                // write elements back to ssd
-                read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, remaining, true);
+                cache.write(task.position(dst), &self.overflow_buffer[..remaining])?;
 
                 src += remaining;
                 remaining = usize::MAX;
                 dst = bwrite as usize - BLOCKSIZE;
 
-                // read tailsize elements from ssd
-                let (start_lba, start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-                read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, tail_size, false);
-                buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + tail_size) * 8].copy_from_slice(u64_to_u8_slice(&mut self.overflow_buffer[src..src + tail_size]));
-                // write elements back to ssd
-                read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, tail_size, true);
+                // tail
+                cache.write(task.position(dst), &self.overflow_buffer[src..src + tail_size])?;
 
                 dst += tail_size;
 
@@ -169,25 +158,14 @@ impl IPS2RaSorter {
                 unimplemented!();
             } else if bwrite > bend as i64 && bend - bstart > BLOCKSIZE as u64 {
                 debug!("bwrite ({}) > bend ({}) && bend - bstart ({}) > BLOCKSIZE", bwrite, bend, bend - bstart);
-                let mut src = bend as usize;
-                let mut head_size = bwrite as usize - bend as usize;
+                let src = bend as usize;
+                let head_size = bwrite as usize - bend as usize;
 
                 //task.arr[dst..dst + head_size].copy_from_slice(&task.arr[src..src + head_size]);
-                // read head_size elements from ssd
-                let (src_start_lba, src_start_offset) = calculate_lba_offset(src, task.start_lba, task.offset);
-                let (dst_start_lba, dst_start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-
-                read_write_elements(qpair, &mut buffer[0], src_start_lba, src % BLOCKSIZE + src_start_offset, head_size, false);
-                read_write_elements(qpair, &mut buffer[1], dst_start_lba, dst % BLOCKSIZE + dst_start_offset, head_size, false);
-
-                let (src_buffer, dst_buffer) = buffer.split_at_mut(1); // Split into two non-overlapping parts
-
-                debug!("Copying {:?} to {:?}", &src_buffer[0][(src % (LBA_SIZE / 8) + src_start_offset) * 8..(src % (LBA_SIZE / 8) + src_start_offset + head_size) * 8], &dst_buffer[0][(dst % (LBA_SIZE / 8) + dst_start_offset) * 8..(dst % (LBA_SIZE / 8) + dst_start_offset + head_size) * 8]);
-
-                let target_slice = &mut dst_buffer[0][(dst % (LBA_SIZE / 8) + dst_start_offset) * 8..(dst % (LBA_SIZE / 8) + dst_start_offset + head_size) * 8];
-                target_slice.copy_from_slice(&src_buffer[0][(src % (LBA_SIZE / 8) + src_start_offset) * 8..(src % (LBA_SIZE / 8) + src_start_offset + head_size) * 8]);
-
-                read_write_elements(qpair, &mut buffer[1], dst_start_lba, dst % BLOCKSIZE + dst_start_offset, head_size, true);
+                let mut head = vec![0u64; head_size];
+                cache.read(task.position(src), &mut head)?;
+                debug!("Copying {:?} from {} to {}", head, src, dst);
+                cache.write(task.position(dst), &head)?;
 
                 dst += head_size;
                 remaining -= head_size;
@@ -199,25 +177,15 @@ impl IPS2RaSorter {
 
             if count <= remaining {
                 if count > 0 {
-                    // read count elements from ssd
-                    let (start_lba, start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, count, false);
-                    debug!("Copying blocks[{i}][{}..{}] to {:?}", src, src+count, &mut buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + count) * 8]);
-                    buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + count) * 8].copy_from_slice(u64_to_u8_slice(&mut self.blocks[i][src..src + count]));
-                    // write elements back to ssd
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, count, true);
+                    debug!("Copying blocks[{i}][{}..{}] to {}", src, src+count, dst);
+                    cache.write(task.position(dst), &self.blocks[i][src..src + count])?;
                 }
                 dst += count;
                 remaining -= count;
             } else {
                 if remaining > 0 {
-                    // read remaining elements from ssd
-                    let (start_lba, start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, remaining, false);
-                    debug!("Copying blocks[{i}][{}..{}] to {:?}", src, src+remaining, &mut buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + remaining) * 8]);
-                    buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + remaining) * 8].copy_from_slice(u64_to_u8_slice(&mut self.blocks[i][src..src + remaining]));
-                    // write elements back to ssd
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, remaining, true);
+                    debug!("Copying blocks[{i}][{}..{}] to {}", src, src+remaining, dst);
+                    cache.write(task.position(dst), &self.blocks[i][src..src + remaining])?;
                 }
                 src += remaining;
                 count -= remaining;
@@ -225,13 +193,8 @@ impl IPS2RaSorter {
 
                 dst = bwrite as usize;
                 if count > 0 {
-                    // read count elements from ssd
-                    let (start_lba, start_offset) = calculate_lba_offset(dst, task.start_lba, task.offset);
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, count, false);
-                    debug!("Copying blocks[{i}][{}..{}] to {:?}", src, src+count, &mut buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + count) * 8]);
-                    buffer[0][(dst % (LBA_SIZE / 8) + start_offset) * 8..(dst % (LBA_SIZE / 8) + start_offset + count) * 8].copy_from_slice(u64_to_u8_slice(&mut self.blocks[i][src..src + count]));
-                    // write elements back to ssd
-                    read_write_elements(qpair, &mut buffer[0], start_lba, dst % BLOCKSIZE + start_offset, count, true);
+                    debug!("Copying blocks[{i}][{}..{}] to {}", src, src+count, dst);
+                    cache.write(task.position(dst), &self.blocks[i][src..src + count])?;
                 }
 
                 dst += count;
@@ -239,36 +202,19 @@ impl IPS2RaSorter {
             }
             self.block_counts[i] = 0;
             if !is_last_level {
-                let diff = bend - bstart;
-                if diff <= THRESHOLD as u64 && diff > 1 {
-                    let (start_lba, start_offset) = calculate_lba_offset(bstart as usize, task.start_lba, task.offset);
-                    read_write_elements(qpair, &mut buffer[0], start_lba, bstart as usize % BLOCKSIZE + start_offset, (bend-bstart) as usize, false);
-                    insertion_sort(u8_to_u64_slice(&mut buffer[0][(bstart as usize % (LBA_SIZE / 8) + start_offset) * 8..(bstart as usize % (LBA_SIZE / 8) + start_offset + (bend-bstart) as usize) * 8]));
-                    read_write_elements(qpair, &mut buffer[0], start_lba, bstart as usize % BLOCKSIZE + start_offset, (bend-bstart) as usize, true);
+                let diff = (bend - bstart) as usize;
+                if diff <= THRESHOLD && diff > 1 {
+                    let mut small = [0u64; THRESHOLD];
+                    cache.read(task.position(bstart as usize), &mut small[..diff])?;
+                    insertion_sort(&mut small[..diff]);
+                    cache.write(task.position(bstart as usize), &small[..diff])?;
                 }
             }
         }
+        Ok(())
     }
 }
 
-// read num_elements elements from target_lba (+target_offset elements) to buffer. Wait for completion.
-fn read_write_elements(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, target_lba: usize, target_offset: usize, num_elements: usize, write: bool) {
-    let num_lba = (target_offset * 8 + num_elements * 8 + LBA_SIZE - 1) / LBA_SIZE;
-    debug!("Reading {} elements (=> {} lbas) from lba {} with offset {} to buffer", num_elements, num_lba, target_lba, target_offset);
-    let tmp = qpair.submit_io(&mut buffer.slice(0..num_lba * LBA_SIZE), target_lba as u64, write);
-    qpair.complete_io(tmp);
-    debug!("Read: {:?}", u8_to_u64_slice(&mut buffer[0..num_lba * LBA_SIZE]));
-}
-
-pub fn calculate_lba_offset(index: usize, start_lba: usize, task_offset: usize) -> (usize, usize) {
-    let lba = index * 8 / LBA_SIZE + start_lba;
-    let offset = task_offset;
-
-    debug!("Index: {}, LBA: {}, Offset: {}", index, lba, offset);
-
-    (lba, offset)
-}
-
 /*
 #[cfg(test)]
 mod tests {
@@ -1,3 +1,5 @@
+use vroom::CompletionMode;
+
 pub const K: usize = 256; // number of buckets
 pub const BLOCKSIZE: usize = 128; // number of elements that belong to same bucket
 pub const THRESHOLD: usize = 128; // Threshold from which samplesort is used
@@ -14,6 +16,14 @@ pub const CHUNKS_PER_HUGE_PAGE_2M: usize = HUGE_PAGE_SIZE_2M / CHUNK_SIZE;
 pub const CHUNKS_PER_HUGE_PAGE_1G: usize = HUGE_PAGE_SIZE_1G / CHUNK_SIZE;
 pub const ELEMENTS_PER_CHUNK: usize = CHUNK_SIZE / 8;
 pub const LBA_PER_CHUNK: usize = CHUNK_SIZE / LBA_SIZE;
+pub const COMPLETION_MODE: CompletionMode = CompletionMode::Poll; // Hybrid sleeps while waiting for I/O instead of busy-polling a core
+pub const VERIFY_SORT_MERGE: bool = false; // Check order and checksum of the sort-merge output with an additional read pass
+pub const BLOCK_CACHE_SIZE: usize = 256 * 1024 * 1024; // Bytes of the 2 MiB buffers used as block cache by the external permutation and cleanup
+pub const DISTRIBUTION_EXTENT_SIZE: usize = HUGE_PAGE_SIZE_2M; // Bytes per bucket write buffer and per on-device extent of the distribution sort
+pub const EXT_SAMPLE_SIZE: usize = 64 * 1024; // Elements sampled for the digit histogram of the streaming statistics pass of the external sorts
+pub const IN_PLACE_BLOCK_SIZE: usize = HUGE_PAGE_SIZE_2M; // Bytes per block of the in-place merge, the unit in which read input is reused for the output
+pub const OUTPUT_READ_AHEAD: usize = HUGE_PAGE_SIZE_2M; // Bytes an OutputReader reads ahead of the consumed elements of the sorted output
+pub const ADAPTIVE_MIN_STRETCH: usize = HUGE_PAGE_SIZE_2M / 8; // Elements a presorted stretch needs to become a run of its own in the adaptive run generation
 
 
 const fn is_power_of_two(x: usize) -> bool {
@@ -28,6 +38,10 @@ const _: () = {
     assert!(NUM_THREADS > 0, "NUM_THREADS must be at least one");
     assert!(HUGE_PAGE_SIZE_1G % CHUNK_SIZE == 0, "LBA SIZE must be a divisor of HUGE_PAGE_SIZE");
     assert!(CHUNK_SIZE % LBA_SIZE == 0, "LBA SIZE must be a divisor of CHUNK_SIZE");
+    assert!(DISTRIBUTION_EXTENT_SIZE % LBA_SIZE == 0 && DISTRIBUTION_EXTENT_SIZE <= HUGE_PAGE_SIZE_2M, "DISTRIBUTION_EXTENT_SIZE must be a multiple of LBA_SIZE and fit into a 2 MiB hugepage");
+    assert!(OUTPUT_READ_AHEAD % LBA_SIZE == 0 && OUTPUT_READ_AHEAD <= HUGE_PAGE_SIZE_2M, "OUTPUT_READ_AHEAD must be a multiple of LBA_SIZE and fit into a 2 MiB hugepage");
+    assert!(IN_PLACE_BLOCK_SIZE % LBA_SIZE == 0 && IN_PLACE_BLOCK_SIZE <= HUGE_PAGE_SIZE_2M, "IN_PLACE_BLOCK_SIZE must be a multiple of LBA_SIZE and fit into a 2 MiB hugepage");
+    assert!(ADAPTIVE_MIN_STRETCH % (LBA_SIZE / 8) == 0 && ADAPTIVE_MIN_STRETCH > 0, "ADAPTIVE_MIN_STRETCH must be a positive multiple of the elements per lba");
     //assert!(CHUNKS_PER_HUGE_PAGE < 1024, "CHUNKS_PER_HUGE_PAGE must be smaller than 1024");
     // TODO: check that at least one element buffer gets full during classification (need enough DMA buffers)
 
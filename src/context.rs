@@ -0,0 +1,291 @@
+use crate::config::*;
+use crate::sorter::{IPS2RaSorter, Task};
+use crate::sort::{release_resources, rolling_sort_ext, SortOptions};
+use crate::sequential_sort_merge::sequential_sort_merge;
+use crate::parallel_sort_merge::{bench_parallel_sort_merge, parallel_sort_merge, prepare_benchmark_parallel};
+use crate::parallel::parallel_rec;
+use crate::distribution_sort::distribution_sort;
+use crate::planner::{memory_budget, Hugepages, SortPlan};
+use crate::metrics::SortMetrics;
+use crate::cancel::CancellationToken;
+use crate::output::{OutputReader, SortedOutput};
+use vroom::{NvmeDevice, QUEUE_LENGTH};
+use vroom::memory::Dma;
+use std::error::Error;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use log::info;
+use tracing::instrument;
+
+/// Thread pool, per-thread sorters and device of the parallel sorts.
+/// A context can be reused for many sorts, dropping it deletes its queue pairs and frees its buffers.
+pub struct SortContext {
+    pool: Arc<ThreadPool>,
+    sorters: Workers,
+    // created by the first parallel sort-merge, declared before `nvme` so its queue pairs are dropped first
+    ext_sorters: Option<Workers>,
+    nvme: Option<NvmeDevice>,
+}
+
+impl SortContext {
+    /// Context for in-memory sorts with its own pool of `NUM_THREADS` threads
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        info!("Initializing thread pool with {} threads", NUM_THREADS);
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(NUM_THREADS).build()?);
+        Ok(Self {
+            sorters: Workers::new(Arc::clone(&pool)),
+            pool,
+            ext_sorters: None,
+            nvme: None,
+        })
+    }
+
+    /// Context that owns `nvme` for the external sorts
+    pub fn with_device(nvme: NvmeDevice) -> Result<Self, Box<dyn Error>> {
+        let mut context = Self::new()?;
+        context.nvme = Some(nvme);
+        Ok(context)
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    pub fn device(&mut self) -> Option<&mut NvmeDevice> {
+        self.nvme.as_mut()
+    }
+
+    /// Releases the queue pairs and buffers of the context and returns its device
+    pub fn into_device(mut self) -> Result<NvmeDevice, Box<dyn Error>> {
+        self.release_buffers()?;
+        self.nvme.take().ok_or_else(|| "SortContext has no device".into())
+    }
+
+    pub fn sort_parallel(&mut self, arr: &mut [u64]) -> SortMetrics {
+        self.sort_parallel_with(arr, &SortOptions::new()).expect("Sort without cancellation token failed")
+    }
+
+    #[instrument(level = "debug", skip_all, fields(len = arr.len()))]
+    pub fn sort_parallel_with(&mut self, arr: &mut [u64], options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+        let start = Instant::now();
+        let mut initial_task = Task::new(arr, 0, 8);
+        let sampled = initial_task.sample();
+        let sampling = start.elapsed();
+        if !sampled {
+            return Ok(SortMetrics { sampling, total: start.elapsed(), ..SortMetrics::new() });
+        }
+        let sorters = &self.sorters;
+        sorters.set_cancellation(options.cancel.clone());
+        sorters.install(|| parallel_rec(sorters, &mut initial_task));
+        sorters.set_cancellation(None);
+        let mut metrics = sorters.take_metrics();
+        options.check_cancelled()?;
+        metrics.sampling += sampling;
+        metrics.total = start.elapsed();
+        Ok(metrics)
+    }
+
+    pub fn sort_merge(&mut self, len: usize, parallel: bool) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        self.sort_merge_with(len, parallel, &SortOptions::new())
+    }
+
+    /// Sorts the first `len` elements of the device, the result starts at lba 0
+    #[instrument(level = "debug", skip(self, options))]
+    pub fn sort_merge_with(&mut self, len: usize, parallel: bool, options: &SortOptions) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        if !parallel {
+            let nvme = self.nvme.as_mut().ok_or("SortContext has no device")?;
+            let metrics = sequential_sort_merge(nvme, &self.sorters, len, options)?;
+            return Ok((SortedOutput::new(0, len), metrics));
+        }
+        let plan = self.parallel_plan(len, options)?;
+        self.init_ext_sorters(plan.fan_in)?;
+        let nvme = self.nvme.as_mut().unwrap();
+        let result = parallel_sort_merge(nvme, &self.sorters, self.ext_sorters.as_ref().unwrap(), len, &plan, options);
+        if result.is_err() {
+            info!("Sort-merge failed, releasing queue pairs and buffers");
+            self.release_buffers()?;
+        }
+        Ok((SortedOutput::new(0, len), result?))
+    }
+
+    pub fn rolling_sort(&mut self, len: usize) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        self.rolling_sort_with(len, &SortOptions::new())
+    }
+
+    /// Sorts the first `len` elements of the device in place, the levels are taken from a statistics pass over the elements
+    pub fn rolling_sort_with(&mut self, len: usize, options: &SortOptions) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        let metrics = rolling_sort_ext(self.nvme()?, len, options)?;
+        Ok((SortedOutput::new(0, len), metrics))
+    }
+
+    pub fn distribution_sort(&mut self, len: usize) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        self.distribution_sort_with(len, &SortOptions::new())
+    }
+
+    /// Sorts the first `len` elements of the device with one distribution pass into on-device buckets
+    /// and an in-memory sort of each bucket, the result starts at lba 0
+    pub fn distribution_sort_with(&mut self, len: usize, options: &SortOptions) -> Result<(SortedOutput, SortMetrics), Box<dyn Error>> {
+        let metrics = distribution_sort(self.nvme()?, len, options)?;
+        Ok((SortedOutput::new(0, len), metrics))
+    }
+
+    /// Streams the sorted `output` of an earlier sort from the device of the context
+    pub fn reader(&mut self, output: &SortedOutput) -> Result<OutputReader<'_>, Box<dyn Error>> {
+        output.reader(self.nvme()?)
+    }
+
+    /// Deletes the queue pairs and frees the buffers of the parallel sort-merge, the next one allocates them again
+    pub fn release_buffers(&mut self) -> Result<(), Box<dyn Error>> {
+        if let (Some(sorters), Some(nvme)) = (self.ext_sorters.take(), self.nvme.as_mut()) {
+            info!("Releasing sort-merge sorters");
+            sorters.release(nvme)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `num_hugepages` 1GiB hugepages of uniform elements to the device. Use for benchmarking only!
+    pub fn prepare_benchmark(&mut self, num_hugepages: usize, seed: usize) -> Result<(), Box<dyn Error>> {
+        self.init_ext_sorters(1)?;
+        prepare_benchmark_parallel(self.ext_sorters.as_ref().unwrap(), num_hugepages, seed);
+        Ok(())
+    }
+
+    // like sort_merge_with, only with time measurements
+    // Mode 0: only sort
+    // Mode 1: merge (sort required)
+    pub fn benchmark_parallel_sort_merge(&mut self, len: usize, mode: usize) -> Result<Duration, Box<dyn Error>> {
+        let plan = self.parallel_plan(len, &SortOptions::new())?;
+        self.init_ext_sorters(plan.fan_in)?;
+        let nvme = self.nvme.as_mut().unwrap();
+        bench_parallel_sort_merge(nvme, self.ext_sorters.as_ref().unwrap(), len, &plan, mode)
+    }
+
+    fn nvme(&mut self) -> Result<&mut NvmeDevice, Box<dyn Error>> {
+        self.nvme.as_mut().ok_or_else(|| "SortContext has no device".into())
+    }
+
+    // the buffers of the current sorters are released for a new plan, so they count as free
+    fn parallel_plan(&self, len: usize, options: &SortOptions) -> Result<SortPlan, Box<dyn Error>> {
+        let held = self.ext_sorters.as_ref().map_or(Hugepages::default(), |sorters| sorters.hugepages());
+        let (memory, pages) = memory_budget(options, held);
+        let plan = SortPlan::parallel_sort_merge_with(len, memory, &pages, self.num_threads(), options.run_generation)?;
+        info!("Sort plan: {:?}", plan);
+        Ok(plan)
+    }
+
+    // sorters with at least `num_buffer` 2MiB buffers each, fewer are allocated again
+    fn init_ext_sorters(&mut self, num_buffer: usize) -> Result<(), Box<dyn Error>> {
+        if self.ext_sorters.as_ref().is_some_and(|sorters| sorters.num_buffers() < num_buffer) {
+            self.release_buffers()?;
+        }
+        if self.ext_sorters.is_none() {
+            let pool = Arc::clone(&self.pool);
+            self.ext_sorters = Some(Workers::with_device(pool, self.nvme()?, num_buffer)?);
+        }
+        Ok(())
+    }
+}
+
+/// One sorter per thread of a pool, each thread only locks the sorter at its own index
+pub(crate) struct Workers {
+    pool: Arc<ThreadPool>,
+    sorters: Vec<Mutex<Box<IPS2RaSorter>>>,
+}
+
+impl Workers {
+    fn new(pool: Arc<ThreadPool>) -> Self {
+        let sorters = (0..pool.current_num_threads()).map(|_| Mutex::new(IPS2RaSorter::new_parallel())).collect();
+        Self { pool, sorters }
+    }
+
+    /// Sorters with a queue pair, `num_buffer` 2MiB buffers and a 1GiB sort buffer each
+    fn with_device(pool: Arc<ThreadPool>, nvme: &mut NvmeDevice, num_buffer: usize) -> Result<Self, Box<dyn Error>> {
+        let num_threads = pool.current_num_threads();
+        let pages = Hugepages::detect();
+        if num_threads * num_buffer > pages.free_2m || num_threads > pages.free_1g {
+            return Err(format!("{} threads need {} free 2MiB and {} free 1GiB hugepages, {:?} are free", num_threads, num_threads * num_buffer, num_threads, pages).into());
+        }
+        info!("Initializing sort-merge sorters");
+        let nvme = Mutex::new(nvme);
+
+        // allocated in the pool, so the buffers are zeroed in parallel
+        let sorters = pool.install(|| (0..num_threads).into_par_iter().map(|thread_id| {
+            let mut qpair = nvme.lock().unwrap().create_io_queue_pair(QUEUE_LENGTH).map_err(|e| e.to_string())?;
+            qpair.set_completion_mode(COMPLETION_MODE);
+            let buffers = (0..num_buffer)
+                .map(|_| Dma::allocate(HUGE_PAGE_SIZE_2M))
+                .collect::<Result<Vec<Dma<u8>>, _>>()
+                .map_err(|e| e.to_string())?;
+            let sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G).map_err(|e| e.to_string())?;
+            info!("Thread {} initialized sorter", thread_id);
+            Ok(Mutex::new(IPS2RaSorter::new_ext_sequential(qpair, buffers, sort_buffer)))
+        }).collect::<Result<Vec<_>, String>>())?;
+        Ok(Self { pool, sorters })
+    }
+
+    pub(crate) fn num_threads(&self) -> usize {
+        self.sorters.len()
+    }
+
+    /// 2MiB buffers of each sorter
+    pub(crate) fn num_buffers(&self) -> usize {
+        self.sorters[0].lock().unwrap().buffers.as_ref().map_or(0, |buffers| buffers.len())
+    }
+
+    /// Hugepages held by the buffers of the sorters
+    pub(crate) fn hugepages(&self) -> Hugepages {
+        let sort_buffers = self.sorters.iter().filter(|sorter| sorter.lock().unwrap().sort_buffer.is_some()).count();
+        Hugepages { free_2m: self.num_threads() * self.num_buffers(), free_1g: sort_buffers }
+    }
+
+    pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(op)
+    }
+
+    /// Runs `f` with the sorter of the calling thread, which has to belong to the pool
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut IPS2RaSorter) -> R) -> R {
+        let index = self.pool.current_thread_index().expect("Sorters are only available inside their thread pool");
+        f(&mut self.sorters[index].lock().unwrap())
+    }
+
+    /// Runs `f` with the first sorter from outside the pool, for work that needs the buffers of a single sorter
+    pub(crate) fn with_first<R>(&self, f: impl FnOnce(&mut IPS2RaSorter) -> R) -> R {
+        f(&mut self.sorters[0].lock().unwrap())
+    }
+
+    /// Collects and resets the metrics and queue pair statistics of all sorters
+    pub(crate) fn take_metrics(&self) -> SortMetrics {
+        let mut metrics = SortMetrics::new();
+        for sorter in &self.sorters {
+            let mut sorter = sorter.lock().unwrap();
+            metrics.combine(&mem::take(&mut sorter.metrics));
+            if let Some(qpair) = sorter.qpair.as_mut() {
+                metrics.add_queue_pair(mem::take(&mut qpair.stats));
+            }
+        }
+        metrics
+    }
+
+    /// Sets the cancellation token checked by all sorters
+    pub(crate) fn set_cancellation(&self, cancel: Option<CancellationToken>) {
+        for sorter in &self.sorters {
+            sorter.lock().unwrap().cancel = cancel.clone();
+        }
+    }
+
+    /// Deletes the queue pairs and frees the buffers right away instead of on drop, so failures are reported
+    fn release(self, nvme: &mut NvmeDevice) -> Result<(), Box<dyn Error>> {
+        for sorter in self.sorters {
+            let mut sorter = sorter.into_inner().unwrap();
+            if let Some(qpair) = sorter.qpair.take() {
+                let mut buffers = sorter.buffers.take().unwrap_or_default();
+                buffers.extend(sorter.sort_buffer.take());
+                release_resources(nvme, qpair, buffers)?;
+            }
+        }
+        Ok(())
+    }
+}
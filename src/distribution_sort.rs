@@ -0,0 +1,279 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::base_case::insertion_sort;
+use crate::sort::{deallocate_lbas, find_bucket_ips2ra, read_write_elements, release_resources, SortOptions};
+use crate::sampling::ext_stats;
+use crate::sorter::{ExtTask, IPS2RaSorter, Task};
+use crate::metrics::SortMetrics;
+use crate::progress::{Phase, ProgressTracker};
+use vroom::memory::{Dma, DmaSlice};
+use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
+use std::cmp::min;
+use std::error::Error;
+use std::mem;
+use std::time::Instant;
+use log::{debug, info};
+use tracing::{debug_span, instrument};
+
+// elements sorted in memory at once, one lba of the sort buffer is kept free for the carry of the output
+const WINDOW: usize = (HUGE_PAGE_SIZE_1G - LBA_SIZE) / 8;
+const EXTENT_ELEMENTS: usize = DISTRIBUTION_EXTENT_SIZE / 8;
+const LBA_PER_EXTENT: usize = DISTRIBUTION_EXTENT_SIZE / LBA_SIZE;
+
+/// Elements of a bucket on the device, as (lba, number of elements) segments.
+/// All segments but the last one start and end on an lba boundary.
+#[derive(Debug, Default)]
+struct Bucket {
+    segments: Vec<(usize, usize)>,
+    len: usize,
+}
+
+/// Hands out the extents of the scratch region behind the input, freed extents are reused first
+struct Extents {
+    first_lba: usize,
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl Extents {
+    fn allocate(&mut self) -> usize {
+        self.free.pop().unwrap_or_else(|| {
+            self.next += 1;
+            self.first_lba + (self.next - 1) * LBA_PER_EXTENT
+        })
+    }
+
+    fn release(&mut self, bucket: &Bucket) {
+        self.free.extend(bucket.segments.iter().map(|&(lba, _)| lba).filter(|&lba| lba >= self.first_lba));
+    }
+}
+
+struct DistributionSort<'a> {
+    qpair: NvmeQueuePair,
+    sort_buffer: Dma<u8>,
+    // one per bucket, allocated by the first distribution pass
+    write_buffers: Vec<Dma<u8>>,
+    sorter: Box<IPS2RaSorter>,
+    extents: Extents,
+    level_end: usize,
+    key_offset: u64,
+    // elements per bucket of the first distribution pass, estimated from the sampled histogram
+    expected: Option<Vec<usize>>,
+    // elements written to the output, the carry holds those of its last partial lba
+    written: usize,
+    carry: Vec<u64>,
+    options: &'a SortOptions,
+    progress: ProgressTracker,
+}
+
+/// Sorts the first `len` elements of the device by distributing them by their most significant digit
+/// into on-device buckets and sorting each bucket in memory, the result starts at lba 0.
+/// Buckets larger than the sort buffer are distributed again by the next digit.
+#[instrument(level = "debug", skip(nvme, options))]
+pub(crate) fn distribution_sort(nvme: &mut NvmeDevice, len: usize, options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    let total_start = Instant::now();
+    // the elements are written once to the buckets and once to the output
+    let progress = ProgressTracker::new(options.progress.clone(), 0, 0, (len * 8 * 2) as u64);
+    progress.set_phase(Phase::Partitioning);
+
+    let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+    qpair.set_completion_mode(COMPLETION_MODE);
+    let mut sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G)?;
+
+    let start = Instant::now();
+    let stats = ext_stats(&mut qpair, &mut sort_buffer, &ExtTask::new(0, 0, len, 0, 8));
+    let (level, level_end, key_offset) = stats.levels();
+    info!("Distribution sort of {} elements, level: {}..{}, key offset: {}", len, level, level_end, key_offset);
+    let expected = (level_end != 0).then(|| {
+        let histogram = stats.histogram(level, key_offset);
+        debug!("Sampled buckets: {}", histogram.iter().filter(|&&count| count > 0).count());
+        histogram.iter().map(|&count| count * len / stats.samples.len()).collect()
+    });
+
+    let mut sorter = IPS2RaSorter::new_sequential();
+    sorter.cancel = options.cancel.clone();
+    sorter.metrics.sampling += start.elapsed();
+    let mut state = DistributionSort {
+        qpair,
+        sort_buffer,
+        write_buffers: Vec::new(),
+        sorter,
+        extents: Extents { first_lba: (len * 8).div_ceil(DISTRIBUTION_EXTENT_SIZE) * LBA_PER_EXTENT, next: 0, free: Vec::new() },
+        level_end,
+        key_offset,
+        expected,
+        written: 0,
+        carry: Vec::with_capacity(LBA_SIZE / 8),
+        options,
+        progress,
+    };
+
+    // all elements equal, nothing to sort
+    if level_end != 0 {
+        let input = Bucket { segments: vec![(0, len)], len };
+        if let Err(e) = state.sort_bucket(input, level) {
+            state.write_buffers.push(state.sort_buffer);
+            release_resources(nvme, state.qpair, state.write_buffers)?;
+            return Err(e);
+        }
+    }
+
+    if options.trim_scratch && state.extents.next > 0 {
+        info!("Deallocating scratch region");
+        deallocate_lbas(&mut state.qpair, &mut state.sort_buffer, state.extents.first_lba, state.extents.next * LBA_PER_EXTENT)?;
+    }
+    state.progress.set_phase(Phase::Done);
+    let mut metrics = mem::take(&mut state.sorter.metrics);
+    metrics.add_queue_pair(state.qpair.stats);
+    metrics.total = total_start.elapsed();
+    Ok(metrics)
+}
+
+impl DistributionSort<'_> {
+    // the elements of `bucket` agree in all digits before `level`
+    fn sort_bucket(&mut self, bucket: Bucket, level: usize) -> Result<(), Box<dyn Error>> {
+        self.options.check_cancelled()?;
+        if bucket.len == 0 {
+            return Ok(());
+        }
+        if level >= self.level_end {
+            // all digits are equal, the bucket is copied to the output as it is
+            for &(lba, len) in &bucket.segments {
+                for done in (0..len).step_by(WINDOW) {
+                    let n = min(len - done, WINDOW);
+                    self.read(0, lba + done / (LBA_SIZE / 8), n)?;
+                    self.emit(n);
+                    self.progress.written((n * 8) as u64);
+                }
+            }
+        } else if bucket.len <= WINDOW {
+            self.progress.set_phase(Phase::RunGeneration);
+            let _span = debug_span!("bucket", level = level, len = bucket.len).entered();
+            let mut position = 0;
+            for &(lba, len) in &bucket.segments {
+                self.read(position, lba, len)?;
+                position += len;
+            }
+            self.sort_in_memory(bucket.len, level);
+            self.emit(bucket.len);
+            self.progress.run_sorted((bucket.len * 8) as u64);
+        } else {
+            let buckets = self.distribute(&bucket, level)?;
+            // the extents of the bucket can be reused by the next distribution passes
+            self.extents.release(&bucket);
+            for bucket in buckets {
+                self.sort_bucket(bucket, level + 1)?;
+            }
+            return Ok(());
+        }
+        self.extents.release(&bucket);
+        Ok(())
+    }
+
+    // distributes the elements of `bucket` by their digit on `level` into extents of the scratch region
+    fn distribute(&mut self, bucket: &Bucket, level: usize) -> Result<Vec<Bucket>, Box<dyn Error>> {
+        let _span = debug_span!("distribute", level = level, len = bucket.len).entered();
+        self.progress.set_phase(Phase::Partitioning);
+        let start = Instant::now();
+        if self.write_buffers.is_empty() {
+            for _ in 0..K {
+                self.write_buffers.push(Dma::allocate(DISTRIBUTION_EXTENT_SIZE)?);
+            }
+        }
+        let mut buckets: Vec<Bucket> = (0..K).map(|_| Bucket::default()).collect();
+        // the segments of the first pass are reserved for the sampled bucket sizes
+        if let Some(expected) = self.expected.take() {
+            for (bucket, elements) in buckets.iter_mut().zip(expected) {
+                bucket.segments.reserve(elements.div_ceil(EXTENT_ELEMENTS) + 1);
+            }
+        }
+        let mut fill = [0usize; K];
+        for &(lba, len) in &bucket.segments {
+            for done in (0..len).step_by(WINDOW) {
+                self.options.check_cancelled()?;
+                let n = min(len - done, WINDOW);
+                self.read(0, lba + done / (LBA_SIZE / 8), n)?;
+                for &element in u8_to_u64_slice(&mut self.sort_buffer[0..n * 8]).iter() {
+                    let b = find_bucket_ips2ra(element - self.key_offset, level);
+                    u8_to_u64_slice(&mut self.write_buffers[b][fill[b] * 8..fill[b] * 8 + 8])[0] = element;
+                    fill[b] += 1;
+                    if fill[b] == EXTENT_ELEMENTS {
+                        let extent = self.extents.allocate();
+                        read_write_elements(&mut self.qpair, &mut self.write_buffers[b], extent, 0, EXTENT_ELEMENTS, true);
+                        self.progress.written(DISTRIBUTION_EXTENT_SIZE as u64);
+                        buckets[b].segments.push((extent, EXTENT_ELEMENTS));
+                        buckets[b].len += EXTENT_ELEMENTS;
+                        fill[b] = 0;
+                    }
+                }
+            }
+        }
+        for b in 0..K {
+            if fill[b] > 0 {
+                let extent = self.extents.allocate();
+                read_write_elements(&mut self.qpair, &mut self.write_buffers[b], extent, 0, fill[b], true);
+                self.progress.written((fill[b] * 8) as u64);
+                buckets[b].segments.push((extent, fill[b]));
+                buckets[b].len += fill[b];
+            }
+        }
+        debug!("Distributed {} elements into {} buckets", bucket.len, buckets.iter().filter(|b| b.len > 0).count());
+        self.sorter.metrics.classification += start.elapsed();
+        Ok(buckets)
+    }
+
+    fn sort_in_memory(&mut self, len: usize, level: usize) {
+        let arr = u8_to_u64_slice(&mut self.sort_buffer[0..len * 8]);
+        // the digits are taken from the keys without the offset, like in the distribution
+        if self.key_offset != 0 {
+            arr.iter_mut().for_each(|x| *x -= self.key_offset);
+        }
+        if len > THRESHOLD {
+            let mut task = Task::new(arr, level, self.level_end);
+            self.sorter.clear();
+            self.sorter.sequential_rec(&mut task);
+        } else {
+            insertion_sort(arr);
+        }
+        if self.key_offset != 0 {
+            u8_to_u64_slice(&mut self.sort_buffer[0..len * 8]).iter_mut().for_each(|x| *x += self.key_offset);
+        }
+    }
+
+    // reads `len` elements at `lba` into the sort buffer at element `position`, which is on an lba boundary,
+    // fails if a command cannot be submitted or fails
+    fn read(&mut self, position: usize, lba: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let bytes = (len * 8).div_ceil(LBA_SIZE) * LBA_SIZE;
+        for done in (0..bytes).step_by(DISTRIBUTION_EXTENT_SIZE) {
+            let piece = min(bytes - done, DISTRIBUTION_EXTENT_SIZE);
+            let piece_lba = lba + done / LBA_SIZE;
+            let tmp = self.qpair.submit_io(&self.sort_buffer.slice(position * 8 + done..position * 8 + done + piece), piece_lba as u64, false);
+            if tmp == 0 {
+                return Err(format!("Reading lba {} could not be submitted, the queue is full", piece_lba).into());
+            }
+            if self.qpair.complete_io_checked(tmp).is_none() {
+                return Err(format!("Reading {} bytes at lba {} failed", piece, piece_lba).into());
+            }
+        }
+        self.sorter.metrics.run_io += start.elapsed();
+        Ok(())
+    }
+
+    // appends the first `len` elements of the sort buffer to the output
+    fn emit(&mut self, len: usize) {
+        let start = Instant::now();
+        let lead = self.carry.len();
+        self.sort_buffer[0..(lead + len) * 8].copy_within(0..len * 8, lead * 8);
+        u8_to_u64_slice(&mut self.sort_buffer[0..lead * 8]).copy_from_slice(&self.carry);
+        read_write_elements(&mut self.qpair, &mut self.sort_buffer, (self.written - lead) / (LBA_SIZE / 8), 0, lead + len, true);
+        self.written += len;
+
+        let tail = self.written % (LBA_SIZE / 8);
+        let mut carry = mem::take(&mut self.carry);
+        carry.clear();
+        carry.extend_from_slice(u8_to_u64_slice(&mut self.sort_buffer[(lead + len - tail) * 8..(lead + len) * 8]));
+        self.carry = carry;
+        self.sorter.metrics.run_io += start.elapsed();
+    }
+}
@@ -0,0 +1,236 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::metrics::SortMetrics;
+use crate::progress::{Phase, ProgressTracker};
+use crate::sort::{sort, SortOptions};
+use crate::context::SortContext;
+use vroom::NvmeQueuePair;
+use vroom::memory::{Dma, DmaSlice};
+use std::cmp::{min, Reverse};
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+use log::info;
+
+// Files contain native endian u64 elements, same as the layout on the device
+
+const FILE_BUFFER_SIZE: usize = 1024 * 1024;
+// elements merged between two progress reports (1GiB)
+const PROGRESS_INTERVAL: usize = HUGE_PAGE_SIZE_1G / 8;
+
+/// Fills `out` with elements from `reader`, returns the number of elements read (less than `out.len()` at EOF)
+pub fn read_elements(reader: &mut impl Read, out: &mut [u64]) -> io::Result<usize> {
+    let bytes = u64_to_u8_slice(out);
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match reader.read(&mut bytes[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    if filled % 8 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "file size is not a multiple of 8 bytes"));
+    }
+    Ok(filled / 8)
+}
+
+pub fn write_elements(writer: &mut impl Write, elements: &mut [u64]) -> io::Result<()> {
+    writer.write_all(u64_to_u8_slice(elements))
+}
+
+/// Sorts `input` into `output` with runs of `run_len` elements sorted in memory and a k-way merge.
+/// The runs are stored in a new scratch file next to `output`. Returns the number of elements and the metrics,
+/// the merge reads and writes through buffered files, so its I/O is part of `merge_compute`.
+pub fn sort_file(input: &Path, output: &Path, run_len: usize, parallel: bool) -> Result<(usize, SortMetrics), Box<dyn Error>> {
+    sort_file_with(input, output, run_len, parallel, &SortOptions::new())
+}
+
+pub fn sort_file_with(input: &Path, output: &Path, run_len: usize, parallel: bool, options: &SortOptions) -> Result<(usize, SortMetrics), Box<dyn Error>> {
+    assert!(run_len > 0, "Run length must be at least one element");
+    let total_start = Instant::now();
+    let mut metrics = SortMetrics::new();
+    let mut reader = File::open(input)?;
+    let (scratch_path, scratch) = create_scratch(output)?;
+    let mut scratch = BufWriter::with_capacity(FILE_BUFFER_SIZE, scratch);
+
+    let size = fs::metadata(input)?.len() as usize;
+    let num_runs = (size / 8).div_ceil(run_len);
+    let merge_levels = if num_runs > 1 { 1 } else { 0 };
+    let progress = ProgressTracker::new(options.progress.clone(), num_runs, merge_levels, (size * (1 + merge_levels)) as u64);
+    progress.set_phase(Phase::RunGeneration);
+
+    let mut context = if parallel { Some(SortContext::new()?) } else { None };
+    let mut run = vec![0u64; min(run_len, size / 8).max(1)];
+    let mut runs = Vec::new();
+    let mut len = 0;
+    loop {
+        let start = Instant::now();
+        let n = read_elements(&mut reader, &mut run)?;
+        metrics.run_io += start.elapsed();
+        if n == 0 {
+            break;
+        }
+        info!("Sorting run {} with {} elements", runs.len(), n);
+        let run_metrics = match context.as_mut() {
+            Some(context) => context.sort_parallel(&mut run[..n]),
+            None => sort(&mut run[..n]),
+        };
+        metrics.combine(&run_metrics);
+        let start = Instant::now();
+        write_elements(&mut scratch, &mut run[..n])?;
+        metrics.run_io += start.elapsed();
+        progress.run_sorted((n * 8) as u64);
+        runs.push((len, n));
+        len += n;
+        if n < run.len() {
+            break;
+        }
+    }
+    let start = Instant::now();
+    scratch.flush()?;
+    drop(scratch);
+    metrics.run_io += start.elapsed();
+    drop(run);
+    drop(context);
+
+    if runs.len() <= 1 {
+        fs::rename(&scratch_path, output)?;
+        progress.set_phase(Phase::Done);
+        metrics.total = total_start.elapsed();
+        return Ok((len, metrics));
+    }
+
+    info!("Merging {} runs", runs.len());
+    let start = Instant::now();
+    progress.start_merge_level(0);
+    merge_runs(&scratch_path, &runs, output, &progress)?;
+    metrics.merge_compute += start.elapsed();
+    fs::remove_file(&scratch_path)?;
+    progress.set_phase(Phase::Done);
+    metrics.total = total_start.elapsed();
+    Ok((len, metrics))
+}
+
+// creates a scratch file next to `output` that did not exist before, so neither `output` nor the scratch file
+// of another sort is overwritten
+fn create_scratch(output: &Path) -> io::Result<(PathBuf, File)> {
+    let name = output.file_name().map_or("output".into(), |name| name.to_string_lossy());
+    for attempt in 0.. {
+        let path = output.with_file_name(format!(".{}.{}-{}.runs", name, process::id(), attempt));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Merges the sorted `runs` (start, length) of the file at `path` into `output`
+fn merge_runs(path: &Path, runs: &[(usize, usize)], output: &Path, progress: &ProgressTracker) -> Result<(), Box<dyn Error>> {
+    let mut readers = Vec::with_capacity(runs.len());
+    for &(start, len) in runs {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start((start * 8) as u64))?;
+        readers.push(BufReader::with_capacity(FILE_BUFFER_SIZE / 4, file.take((len * 8) as u64)));
+    }
+
+    let next = |reader: &mut BufReader<io::Take<File>>| -> io::Result<Option<u64>> {
+        let mut bytes = [0u8; 8];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(u64::from_ne_bytes(bytes))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    };
+
+    let mut heap = BinaryHeap::with_capacity(readers.len());
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(value) = next(reader)? {
+            heap.push(Reverse((value, i)));
+        }
+    }
+
+    let mut writer = BufWriter::with_capacity(FILE_BUFFER_SIZE, File::create(output)?);
+    let mut written = 0;
+    while let Some(Reverse((value, i))) = heap.pop() {
+        writer.write_all(&value.to_ne_bytes())?;
+        written += 1;
+        if written % PROGRESS_INTERVAL == 0 {
+            progress.written((PROGRESS_INTERVAL * 8) as u64);
+        }
+        if let Some(value) = next(&mut readers[i])? {
+            heap.push(Reverse((value, i)));
+        }
+    }
+    writer.flush()?;
+    progress.written((written % PROGRESS_INTERVAL * 8) as u64);
+    Ok(())
+}
+
+/// Writes the elements of `input` to the device starting at lba 0.
+/// Returns the number of elements and the maximum element, fails if a write cannot be submitted or fails.
+pub fn stage_file(qpair: &mut NvmeQueuePair, input: &Path) -> Result<(usize, u64), Box<dyn Error>> {
+    let mut reader = File::open(input)?;
+    let mut buffer: Dma<u8> = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
+    let mut lba = 0;
+    let mut len = 0;
+    let mut max = 0;
+    loop {
+        let n = read_elements(&mut reader, u8_to_u64_slice(&mut buffer[0..HUGE_PAGE_SIZE_2M]))?;
+        if n == 0 {
+            break;
+        }
+        max = max.max(*u8_to_u64_slice(&mut buffer[0..n * 8]).iter().max().unwrap());
+
+        let num_lba = (n * 8).div_ceil(LBA_SIZE);
+        buffer[n * 8..num_lba * LBA_SIZE].fill(0);
+        let tmp = qpair.submit_io(&buffer.slice(0..num_lba * LBA_SIZE), lba as u64, true);
+        if tmp == 0 {
+            return Err(format!("Writing lba {} could not be submitted, the queue is full", lba).into());
+        }
+        if qpair.complete_io_checked(tmp).is_none() {
+            return Err(format!("Writing {} lbas at lba {} failed", num_lba, lba).into());
+        }
+
+        lba += num_lba;
+        len += n;
+        if n < HUGE_PAGE_SIZE_2M / 8 {
+            break;
+        }
+    }
+    buffer.free()?;
+    Ok((len, max))
+}
+
+/// Reads `len` elements starting at lba 0 from the device into `output`, fails if a read cannot be submitted or fails
+pub fn unstage_file(qpair: &mut NvmeQueuePair, output: &Path, len: usize) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::with_capacity(FILE_BUFFER_SIZE, File::create(output)?);
+    let mut buffer: Dma<u8> = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
+    let mut lba = 0;
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = min(remaining, HUGE_PAGE_SIZE_2M / 8);
+        let num_lba = (n * 8).div_ceil(LBA_SIZE);
+        let tmp = qpair.submit_io(&buffer.slice(0..num_lba * LBA_SIZE), lba as u64, false);
+        if tmp == 0 {
+            return Err(format!("Reading lba {} could not be submitted, the queue is full", lba).into());
+        }
+        if qpair.complete_io_checked(tmp).is_none() {
+            return Err(format!("Reading {} lbas at lba {} failed", num_lba, lba).into());
+        }
+        write_elements(&mut writer, u8_to_u64_slice(&mut buffer[0..n * 8]))?;
+
+        lba += num_lba;
+        remaining -= n;
+    }
+    writer.flush()?;
+    buffer.free()?;
+    Ok(())
+}
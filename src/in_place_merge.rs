@@ -0,0 +1,269 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::sort::{deallocate_lbas, read_write_elements, SortOptions};
+use crate::planner::{merge_passes, SortPlan};
+use crate::progress::ProgressTracker;
+use vroom::memory::Dma;
+use vroom::NvmeQueuePair;
+use std::cmp::{min, Reverse};
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::mem;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use tracing::{debug_span, instrument};
+
+const BLOCK_ELEMENTS: usize = IN_PLACE_BLOCK_SIZE / 8;
+const LBA_PER_BLOCK: usize = IN_PLACE_BLOCK_SIZE / LBA_SIZE;
+
+// next unread element of a run in the logical order of the pass and the elements of it in the read buffer
+struct Cursor {
+    next: usize,
+    end: usize,
+    idx: usize,
+    filled: usize,
+}
+
+/// Blocks of the device during an in-place merge. The elements of a pass are in logical blocks,
+/// logical block `i` is stored in physical block `map[i]`. A physical block is free once all its elements are read.
+#[derive(Debug, Clone)]
+pub struct InPlaceBlocks {
+    map: Vec<usize>,
+    unread: Vec<usize>,
+    // lowest first, so the reserve behind the input is only used when needed
+    free: BinaryHeap<Reverse<usize>>,
+}
+
+impl InPlaceBlocks {
+    /// Blocks of the sorted `runs`, given as (first element, length) from lba 0, and `reserve` free blocks behind them
+    pub fn new(runs: &[(usize, usize)], reserve: usize) -> Self {
+        let span = runs.last().map_or(0, |&(start, n)| start + n);
+        let input_blocks = span.div_ceil(BLOCK_ELEMENTS);
+        let mut unread = vec![0; input_blocks + reserve];
+        for &(start, n) in runs {
+            let mut position = start;
+            while position < start + n {
+                let block_end = min(start + n, (position / BLOCK_ELEMENTS + 1) * BLOCK_ELEMENTS);
+                unread[position / BLOCK_ELEMENTS] += block_end - position;
+                position = block_end;
+            }
+        }
+        let free = (0..input_blocks + reserve).filter(|&block| unread[block] == 0).map(Reverse).collect();
+        Self { map: (0..input_blocks).collect(), unread, free }
+    }
+
+    /// Blocks of the input and the reserve
+    pub fn len(&self) -> usize {
+        self.unread.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unread.is_empty()
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.free.len()
+    }
+
+    fn lba(&self, position: usize) -> usize {
+        self.map[position / BLOCK_ELEMENTS] * LBA_PER_BLOCK + position % BLOCK_ELEMENTS / (LBA_SIZE / 8)
+    }
+
+    /// Marks `n` elements from `position` of the current pass as read
+    pub fn consumed(&mut self, position: usize, n: usize) {
+        let block = self.map[position / BLOCK_ELEMENTS];
+        self.unread[block] -= n;
+        if self.unread[block] == 0 {
+            self.free.push(Reverse(block));
+        }
+    }
+
+    /// Lowest free block for the output, None if all blocks hold unread elements
+    pub fn allocate(&mut self) -> Option<usize> {
+        self.free.pop().map(|Reverse(block)| block)
+    }
+
+    /// Marks `block` as holding `n` elements of the output of the pass
+    pub fn written(&mut self, block: usize, n: usize) {
+        self.unread[block] = n;
+    }
+}
+
+// output of a pass, written block by block to free blocks
+struct Output<'a> {
+    map: Vec<usize>,
+    idx: usize,
+    buffer: &'a mut Dma<u8>,
+}
+
+/// Merges the sorted `runs`, given as (first element, length) from lba 0, in place like `merge_sequential`.
+/// The output of a pass is written block by block to the blocks whose elements were all read already,
+/// so besides the input only `plan.fan_in + 2` blocks of `IN_PLACE_BLOCK_SIZE` bytes are used.
+/// Afterwards the blocks are moved into order, the result starts at lba 0. Returns the time spent reading and writing.
+/// A pass overwrites its input, if it is cancelled or fails the elements are left permuted across the blocks
+/// and cannot be recovered.
+#[instrument(level = "debug", skip_all, fields(runs = runs.len()))]
+pub(crate) fn merge_in_place(qpair: &mut NvmeQueuePair, runs: &[(usize, usize)], plan: &SortPlan, buffers: &mut [Dma<u8>], output_buffer: &mut Dma<u8>, options: &SortOptions, progress: &ProgressTracker) -> Result<Duration, Box<dyn Error>> {
+    let passes = merge_passes(runs.len(), plan.fan_in);
+    if passes == 0 {
+        return Ok(Duration::ZERO);
+    }
+    assert!(buffers.len() >= plan.fan_in, "One read buffer per merged run required");
+    assert!(plan.buffer_size >= IN_PLACE_BLOCK_SIZE && output_buffer.size >= IN_PLACE_BLOCK_SIZE, "Buffers smaller than a block");
+
+    // every active run and the boundary to the next group hold at most one partially read block
+    let reserve = plan.fan_in + 2;
+    let mut blocks = InPlaceBlocks::new(runs, reserve);
+    info!("In-place merge of {} runs in {} passes, {} blocks, {} reserved", runs.len(), passes, blocks.len() - reserve, reserve);
+
+    let mut runs = runs.to_vec();
+    let mut time_for_io = Duration::ZERO;
+    for pass in 0..passes {
+        let _round = debug_span!("merge_round", round = pass).entered();
+        progress.start_merge_level(pass);
+        let mut output = Output { map: Vec::with_capacity(blocks.map.len()), idx: 0, buffer: output_buffer };
+        let mut merged = Vec::with_capacity(runs.len().div_ceil(plan.fan_in));
+        // the output of a pass is one stream, the groups continue each other in the logical blocks
+        let mut out_start = 0;
+        for group in runs.chunks(plan.fan_in) {
+            options.check_cancelled()?;
+            let group_len = group.iter().map(|&(_, n)| n).sum();
+            debug!("Pass {pass}: merging {} runs of {group_len} elements to {out_start}", group.len());
+            let mut cursors: Vec<Cursor> = group.iter()
+                .map(|&(start, n)| Cursor { next: start, end: start + n, idx: 0, filled: 0 })
+                .collect();
+            time_for_io += merge_group(qpair, &mut cursors, &mut blocks, &mut output, buffers, options, progress)?;
+            merged.push((out_start, group_len));
+            out_start += group_len;
+        }
+        if output.idx > 0 {
+            time_for_io += write_block(qpair, &mut blocks, &mut output, progress)?;
+        }
+        blocks.map = output.map;
+        runs = merged;
+    }
+
+    info!("Moving {} blocks into order", blocks.map.len());
+    let (carry, _) = buffers.split_at_mut(1);
+    time_for_io += arrange(qpair, &blocks.map, &mut carry[0], output_buffer, progress);
+
+    if options.trim_scratch {
+        info!("Deallocating reserved blocks");
+        let used = blocks.map.len();
+        // the result is complete, a failed TRIM only leaves the reserve allocated
+        if let Err(e) = deallocate_lbas(qpair, output_buffer, used * LBA_PER_BLOCK, (blocks.len() - used) * LBA_PER_BLOCK) {
+            warn!("{}", e);
+        }
+    }
+    info!("Time for IO: {:?}", time_for_io);
+    Ok(time_for_io)
+}
+
+// merges the runs of `cursors` into the output of the pass
+fn merge_group(qpair: &mut NvmeQueuePair, cursors: &mut [Cursor], blocks: &mut InPlaceBlocks, output: &mut Output, buffers: &mut [Dma<u8>], options: &SortOptions, progress: &ProgressTracker) -> Result<Duration, Box<dyn Error>> {
+    let mut time_for_io = Duration::ZERO;
+    let mut min_heap = BinaryHeap::with_capacity(cursors.len());
+    for (k, cursor) in cursors.iter_mut().enumerate() {
+        let value = refill(qpair, cursor, blocks, &mut buffers[k], &mut time_for_io);
+        min_heap.push(Reverse((value, k)));
+    }
+
+    while let Some(Reverse((value, k))) = min_heap.pop() {
+        u8_to_u64_slice(&mut output.buffer[output.idx * 8..output.idx * 8 + 8])[0] = value;
+        output.idx += 1;
+        if output.idx == BLOCK_ELEMENTS {
+            time_for_io += write_block(qpair, blocks, output, progress)?;
+            options.check_cancelled()?;
+        }
+
+        let cursor = &mut cursors[k];
+        cursor.idx += 1;
+        if cursor.idx < cursor.filled {
+            let idx = cursor.idx;
+            min_heap.push(Reverse((u8_to_u64(&buffers[k][idx * 8..idx * 8 + 8]), k)));
+        } else if cursor.next < cursor.end {
+            let value = refill(qpair, cursor, blocks, &mut buffers[k], &mut time_for_io);
+            min_heap.push(Reverse((value, k)));
+        }
+    }
+    Ok(time_for_io)
+}
+
+// reads the rest of the current block of `cursor`, which frees the block once the elements of all runs in it are read
+fn refill(qpair: &mut NvmeQueuePair, cursor: &mut Cursor, blocks: &mut InPlaceBlocks, buffer: &mut Dma<u8>, time_for_io: &mut Duration) -> u64 {
+    let n = min(cursor.end, (cursor.next / BLOCK_ELEMENTS + 1) * BLOCK_ELEMENTS) - cursor.next;
+    let lead = cursor.next % (LBA_SIZE / 8);
+    let start = Instant::now();
+    read_write_elements(qpair, buffer, blocks.lba(cursor.next), lead, n, false);
+    *time_for_io += start.elapsed();
+    blocks.consumed(cursor.next, n);
+    cursor.next += n;
+    cursor.idx = lead;
+    cursor.filled = lead + n;
+    u8_to_u64(&buffer[lead * 8..lead * 8 + 8])
+}
+
+fn write_block(qpair: &mut NvmeQueuePair, blocks: &mut InPlaceBlocks, output: &mut Output, progress: &ProgressTracker) -> Result<Duration, Box<dyn Error>> {
+    let block = blocks.allocate().ok_or("No free block left for the output of the in-place merge")?;
+    let start = Instant::now();
+    read_write_elements(qpair, output.buffer, block * LBA_PER_BLOCK, 0, output.idx, true);
+    progress.written((output.idx * 8) as u64);
+    blocks.written(block, output.idx);
+    output.map.push(block);
+    output.idx = 0;
+    Ok(start.elapsed())
+}
+
+/// Moves of the blocks that bring logical block `j` of `map` to physical block `j`. In each chain the block at
+/// `chain[i]` moves to `chain[i + 1]`, the last block of a chain is free or the first one, which the chain freed.
+/// Every misplaced block is moved once.
+pub fn arrange_chains(map: &[usize]) -> Vec<Vec<usize>> {
+    let num_blocks = map.iter().copied().max().map_or(0, |block| block + 1).max(map.len());
+    let mut owner: Vec<Option<usize>> = vec![None; num_blocks];
+    for (logical, &block) in map.iter().enumerate() {
+        owner[block] = Some(logical);
+    }
+    let mut location = map.to_vec();
+    let mut chains = Vec::new();
+
+    for logical in 0..map.len() {
+        if location[logical] == logical {
+            continue;
+        }
+        let mut chain = vec![location[logical], logical];
+        // the block displaced from `logical` is carried to its own place, which displaces the next one
+        let mut displaced = owner[logical];
+        owner[location[logical]] = None;
+        owner[logical] = Some(logical);
+        location[logical] = logical;
+        while let Some(home) = displaced {
+            displaced = owner[home];
+            chain.push(home);
+            owner[home] = Some(home);
+            location[home] = home;
+        }
+        chains.push(chain);
+    }
+    chains
+}
+
+// moves the blocks of `map` into order along `arrange_chains`, every block is read and written once
+fn arrange(qpair: &mut NvmeQueuePair, map: &[usize], carry: &mut Dma<u8>, spare: &mut Dma<u8>, progress: &ProgressTracker) -> Duration {
+    let start = Instant::now();
+    let mut moved = 0;
+    for chain in arrange_chains(map) {
+        read_write_elements(qpair, spare, chain[0] * LBA_PER_BLOCK, 0, BLOCK_ELEMENTS, false);
+        for (i, &block) in chain.iter().enumerate().skip(1) {
+            // the block in the way is read before it is overwritten, the last one is free
+            if i + 1 < chain.len() {
+                read_write_elements(qpair, carry, block * LBA_PER_BLOCK, 0, BLOCK_ELEMENTS, false);
+            }
+            read_write_elements(qpair, spare, block * LBA_PER_BLOCK, 0, BLOCK_ELEMENTS, true);
+            mem::swap(carry, spare);
+            moved += 1;
+        }
+    }
+    progress.written((moved * IN_PLACE_BLOCK_SIZE) as u64);
+    debug!("Moved {} of {} blocks", moved, map.len());
+    start.elapsed()
+}
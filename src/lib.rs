@@ -15,9 +15,42 @@ mod setup;
 mod parallel_sort_merge;
 mod rolling_sort;
 mod sequential_sort_merge;
+mod file_sort;
+mod verify;
+mod workloads;
+mod report;
+mod metrics;
+mod progress;
+mod cancel;
+mod context;
+mod block_cache;
+mod distribution_sort;
+mod planner;
+mod run_generation;
+mod in_place_merge;
+mod output;
+#[cfg(feature = "chrome-trace")]
+mod trace;
 
 pub use sort::*;
 pub use base_case::insertion_sort;
-pub use setup::{clear_chunks, setup_array};
+pub use setup::{clear_chunks, clear_lbas, setup_array, ClearStrategy};
 pub use config::*;
-pub use conversion::*;
\ No newline at end of file
+pub use conversion::*;
+pub use verify::{verify_sort_merge, verify_sorted_ext, Checksum, SortedVerifier};
+pub use workloads::{Distribution, Workload};
+pub use report::{BenchReport, BenchResult, Regression, ReportFormat};
+pub use metrics::SortMetrics;
+pub use progress::{Phase, Progress, ProgressSink};
+pub use cancel::{CancellationToken, Cancelled};
+pub use context::SortContext;
+pub use planner::{Hugepages, SortPlan};
+pub use run_generation::{adaptive_runs, heapify, run_order, sift_down, RunGeneration, RunOrder};
+pub use output::{OutputReader, SortedOutput};
+pub use in_place_merge::{arrange_chains, InPlaceBlocks};
+pub use sampling::ExtStats;
+pub use block_cache::ClockPolicy;
+pub use vroom::QueuePairStats;
+#[cfg(feature = "chrome-trace")]
+pub use trace::chrome_trace;
+pub use file_sort::{read_elements, sort_file, sort_file_with, stage_file, unstage_file, write_elements};
\ No newline at end of file
@@ -1,56 +1,358 @@
-mod sampling;
-mod base_case;
-mod classification;
-mod config;
-mod permutation;
-mod cleanup;
-mod sort;
-mod sorter;
-mod sequential;
-mod parallel;
-mod conversion;
-mod setup;
-mod parallel_sort_merge;
-mod rolling_sort;
-mod sequential_sort_merge;
-use vroom::memory::{DmaSlice};
+use bachelorthesis::*;
+use log::LevelFilter;
+use std::collections::HashMap;
 use std::error::Error;
-use rand::prelude::*;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+use std::{env, fs, process};
+use vroom::{Backend, QUEUE_LENGTH};
 
+const USAGE: &str = "Usage: bachelorthesis <command> [options]
 
-fn verify_sorted(arr: &[u64]) {
-    for i in 1..arr.len() {
-        assert!(arr[i - 1] <= arr[i], "Difference at i={i}. {} > {}", arr[i - 1], arr[i]);
-    }
+Commands:
+  sort <input> <output>     Sort a file of u64 elements
+      --algorithm <a>       auto | memory | file | sort-merge | rolling | distribution (default: auto),
+                            file sorts runs in memory and merges them through a scratch file,
+                            the other external sorts need --device
+      --parallel            Use the parallel variants
+      --memory <bytes>      Memory available for in-memory sorting and runs (default: 4GiB,
+                            all free hugepages with --device)
+      --device <pci addr>   Sort on an NVMe device through vroom, the device sorts do not run on a
+                            file-backed device, use --algorithm file for data larger than memory
+                            without a device
+      --runs <r>            hugepage | hugepages:<n> | replacement-selection | adaptive, run generation
+                            of the sort-merge on a device (default: hugepage), hugepages:<n> with
+                            more than one hugepage needs the vfio backend, adaptive and
+                            replacement-selection are not supported with --parallel
+      --in-place            Merge the runs on the device in place instead of through a scratch region,
+                            the input is lost if the merge is interrupted
+      --trim                Deallocate (TRIM) the scratch region on the device once the result is complete
+      --backend <b>         auto | sysfs | vfio, driver backend for --device (default: auto)
+      --metrics             Print the time spent per phase and the I/O issued
+      --progress            Print the phase, runs sorted, merge level and ETA to stderr
+  verify <file>             Check that a file of u64 elements is sorted
+  generate <output> <len>   Write <len> u64 elements
+      --distribution <d>    sorted | reverse-sorted | almost-sorted | uniform | exponential | root-dup |
+                            two-dup | eight-dup | range[:n] | zipf[:s] | staggered | organ-pipe |
+                            block-almost-sorted (default: uniform)
+      --seed <seed>         Seed of the random number generator (default: 12345)
+  bench <len>               Sort <len> generated elements in memory and print the throughput
+      --parallel            Use sort_parallel
+      --distribution <d>    Input distribution, see generate (default: uniform)
+      --seed <seed>         Seed of the random number generator (default: 12345)
+  inspect <pci addr>        Print namespaces and SMART information of an NVMe device
+      --backend <b>         auto | sysfs | vfio (default: auto)
+
+Options:
+  -v, --verbose             Log progress
+      --trace <file>        Write a Chrome trace of the spans (needs the chrome-trace feature)
+      --trace-level <l>     debug | trace, trace includes every in-memory task (default: debug)";
+
+const DEFAULT_MEMORY: usize = 4 * 1024 * 1024 * 1024;
+const DEFAULT_SEED: u64 = 12345;
+
+// options followed by a value
+const VALUE_OPTIONS: [&str; 9] = ["--algorithm", "--memory", "--device", "--runs", "--backend", "--distribution", "--seed", "--trace", "--trace-level"];
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: Vec<String>,
 }
 
-use rand::prelude::*;
-use rayon::prelude::ParallelSliceMut;
-use crate::sort::{sort};
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Args, Box<dyn Error>> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut flags = Vec::new();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if let Some((key, value)) = arg.split_once('=').filter(|_| arg.starts_with("--")) {
+                options.insert(key.to_string(), value.to_string());
+            } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+                let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+                options.insert(arg, value);
+            } else if arg.starts_with('-') {
+                flags.push(arg);
+            } else {
+                positional.push(arg);
+            }
+        }
+        Ok(Args { positional, options, flags })
+    }
+
+    fn positional(&self, i: usize, name: &str) -> Result<&str, Box<dyn Error>> {
+        self.positional.get(i).map(|s| s.as_str()).ok_or_else(|| format!("Missing argument <{}>", name).into())
+    }
 
+    fn flag(&self, names: &[&str]) -> bool {
+        self.flags.iter().any(|f| names.contains(&f.as_str()))
+    }
 
+    fn option<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, Box<dyn Error>> {
+        match self.options.get(name) {
+            Some(value) => value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value).into()),
+            None => Ok(default),
+        }
+    }
+
+    fn workload(&self, len: usize) -> Result<Workload, Box<dyn Error>> {
+        let distribution = match self.options.get("--distribution") {
+            Some(name) => name.parse::<Distribution>()?,
+            None => Distribution::Uniform,
+        };
+        Ok(Workload::new(distribution, len, self.option("--seed", DEFAULT_SEED)?))
+    }
+
+    fn backend(&self) -> Result<Backend, Box<dyn Error>> {
+        match self.options.get("--backend").map(|s| s.as_str()) {
+            None | Some("auto") => Ok(Backend::Auto),
+            Some("sysfs") => Ok(Backend::Sysfs),
+            Some("vfio") => Ok(Backend::Vfio),
+            Some(other) => Err(format!("Unknown backend: {}", other).into()),
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next();
+    let command = args.next().unwrap_or_default();
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(e) => exit_with_usage(&e.to_string()),
+    };
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::builder()
-        .filter_level(log::LevelFilter::Error)
+        .filter_level(if args.flag(&["-v", "--verbose"]) { LevelFilter::Info } else { LevelFilter::Error })
         .init();
 
-    let mut rng = StdRng::seed_from_u64(54321);
-    let mut data = generate_uniform(&mut rng, 1_000_000);
+    #[cfg(feature = "chrome-trace")]
+    let _trace = match args.options.get("--trace").map(|path| chrome_trace(Path::new(path), args.option("--trace-level", tracing::Level::DEBUG)?)).transpose() {
+        Ok(guard) => guard,
+        Err(e) => exit_with_usage(&format!("Cannot write trace: {}", e)),
+    };
+    #[cfg(not(feature = "chrome-trace"))]
+    if args.options.contains_key("--trace") {
+        exit_with_usage("--trace needs the chrome-trace feature");
+    }
 
-    sort(&mut data);
-    verify_sorted(&data);
+    let result = match command.as_str() {
+        "sort" => cmd_sort(&args),
+        "verify" => cmd_verify(&args),
+        "generate" => cmd_generate(&args),
+        "bench" => cmd_bench(&args),
+        "inspect" => cmd_inspect(&args),
+        "" | "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => exit_with_usage(&format!("Unknown command: {}", other)),
+    };
 
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn cmd_sort(args: &Args) -> Result<(), Box<dyn Error>> {
+    let input = Path::new(args.positional(0, "input")?);
+    let output = Path::new(args.positional(1, "output")?);
+    let parallel = args.flag(&["--parallel"]);
+    let memory: usize = args.option("--memory", DEFAULT_MEMORY)?;
+    let size = fs::metadata(input)?.len() as usize;
+    if size % 8 != 0 {
+        return Err(format!("Input of {} bytes is not a multiple of 8 bytes", size).into());
+    }
+
+    let algorithm = match args.options.get("--algorithm").map(|s| s.as_str()) {
+        None | Some("auto") => {
+            if size <= memory {
+                "memory"
+            } else if args.options.contains_key("--device") {
+                "sort-merge"
+            } else {
+                "file"
+            }
+        }
+        Some(algorithm @ ("memory" | "file" | "sort-merge" | "rolling" | "distribution")) => algorithm,
+        Some(other) => return Err(format!("Unknown algorithm: {}", other).into()),
+    };
+
+    let mut options = SortOptions::new();
+    if args.options.contains_key("--memory") {
+        // the device sorts plan their runs and merge within the same budget
+        options = options.with_memory(memory);
+    }
+    if let Some(runs) = args.options.get("--runs") {
+        options = options.with_run_generation(runs.parse()?);
+    }
+    if args.flag(&["--in-place"]) {
+        options = options.with_in_place(true);
+    }
+    if args.flag(&["--trim"]) {
+        options = options.with_trim_scratch(true);
+    }
+    if args.flag(&["--progress"]) {
+        options = options.with_progress(|progress: &Progress| eprintln!("{}", progress));
+    }
+
+    let start = Instant::now();
+    let (len, metrics) = match (algorithm, args.options.get("--device")) {
+        ("memory", _) => {
+            if size > memory {
+                return Err(format!("Input of {} bytes does not fit into {} bytes of memory", size, memory).into());
+            }
+            let mut data = vec![0u64; size / 8];
+            read_elements(&mut File::open(input)?, &mut data)?;
+            let metrics = if parallel {
+                sort_parallel(&mut data)
+            } else {
+                sort(&mut data)
+            };
+            let mut writer = BufWriter::new(File::create(output)?);
+            write_elements(&mut writer, &mut data)?;
+            writer.flush()?;
+            (data.len(), metrics)
+        }
+        ("file", None) => sort_file_with(input, output, memory / 8, parallel, &options)?,
+        ("file", Some(_)) => return Err("The file sort does not use a --device".into()),
+        (_, None) => return Err(format!("The {} sort needs an NVMe --device, use --algorithm file to sort without one", algorithm).into()),
+        (_, Some(pci_addr)) => {
+            let mut nvme = vroom::init_with_backend(pci_addr, args.backend()?)?;
+            let capacity = nvme.namespaces.get(&1).map_or(0, |ns| ns.blocks * ns.block_size) as usize;
+            // the distribution sort also needs a partially filled extent per bucket,
+            // the in-place merge a block per merged run and two more behind the input
+            let needed = if algorithm == "distribution" {
+                2 * size + K * DISTRIBUTION_EXTENT_SIZE
+            } else if options.in_place {
+                size + memory + 2 * IN_PLACE_BLOCK_SIZE
+            } else {
+                2 * size
+            };
+            if needed > capacity {
+                return Err(format!("Device capacity of {} bytes is too small, the sort needs {} bytes", capacity, needed).into());
+            }
+
+            let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+            let (len, _) = stage_file(&mut qpair, input)?;
+            nvme.delete_io_queue_pair(qpair)?;
+            let mut context = SortContext::with_device(nvme)?;
+            let (sorted, metrics) = match algorithm {
+                "rolling" => context.rolling_sort_with(len, &options)?,
+                "distribution" => context.distribution_sort_with(len, &options)?,
+                _ => context.sort_merge_with(len, parallel, &options)?,
+            };
+            let mut writer = BufWriter::new(File::create(output)?);
+            io::copy(&mut context.reader(&sorted)?, &mut writer)?;
+            writer.flush()?;
+            (len, metrics)
+        }
+    };
+
+    println!("Sorted {} elements ({}) in {:?}", len, algorithm, start.elapsed());
+    if args.flag(&["--metrics"]) {
+        println!("{}", metrics);
+    }
+    Ok(())
+}
+
+fn cmd_verify(args: &Args) -> Result<(), Box<dyn Error>> {
+    let path = args.positional(0, "file")?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = vec![0u64; HUGE_PAGE_SIZE_2M / 8];
+    let mut verifier = SortedVerifier::new();
+    loop {
+        let n = read_elements(&mut reader, &mut buffer)?;
+        verifier.push(&buffer[..n])?;
+        if n < buffer.len() {
+            break;
+        }
+    }
+    println!("{} elements sorted, checksum: {:?}", verifier.checksum.count, verifier.checksum);
+    Ok(())
+}
+
+fn cmd_generate(args: &Args) -> Result<(), Box<dyn Error>> {
+    let output = args.positional(0, "output")?;
+    let len: usize = args.positional(1, "len")?.parse()?;
+    let workload = args.workload(len)?;
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut buffer = vec![0u64; HUGE_PAGE_SIZE_2M / 8];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(buffer.len());
+        workload.fill(written, &mut buffer[..n]);
+        write_elements(&mut writer, &mut buffer[..n])?;
+        written += n;
+    }
+    writer.flush()?;
+    println!("Generated {} elements ({})", len, workload.distribution);
     Ok(())
 }
 
+fn cmd_bench(args: &Args) -> Result<(), Box<dyn Error>> {
+    let len: usize = args.positional(0, "len")?.parse()?;
+    let parallel = args.flag(&["--parallel"]);
+    let mut data = args.workload(len)?.generate();
+    let checksum = Checksum::from_slice(&data);
+
+    let mut context = if parallel { Some(SortContext::new()?) } else { None };
 
-fn generate_uniform(rng: &mut StdRng, length: usize) -> Vec<u64> {
-    (0..length)
-        .map(|_| rng.gen::<u64>())
-        .collect()
+    let start = Instant::now();
+    match context.as_mut() {
+        Some(context) => { context.sort_parallel(&mut data); }
+        None => { sort(&mut data); }
+    }
+    let duration = start.elapsed();
+
+    let mut verifier = SortedVerifier::new();
+    verifier.push(&data)?;
+    if verifier.checksum != checksum {
+        return Err("Output is not a permutation of the input".into());
+    }
+    println!(
+        "Sorted {} elements in {:?} ({:.2} M elements/s)",
+        len,
+        duration,
+        len as f64 / duration.as_secs_f64() / 1e6
+    );
+    Ok(())
 }
 
+fn cmd_inspect(args: &Args) -> Result<(), Box<dyn Error>> {
+    let pci_addr = args.positional(0, "pci addr")?;
+    let mut nvme = vroom::init_with_backend(pci_addr, args.backend()?)?;
+
+    let mut namespaces: Vec<_> = nvme.namespaces.values().copied().collect();
+    namespaces.sort_by_key(|ns| ns.id);
+    for ns in namespaces {
+        println!(
+            "Namespace {}: {} blocks of {} bytes ({} GiB)",
+            ns.id,
+            ns.blocks,
+            ns.block_size,
+            ns.blocks * ns.block_size / (1 << 30)
+        );
+    }
+    println!("Clear strategy: {:?}", ClearStrategy::detect(&nvme));
 
+    let smart = nvme.get_smart_log()?;
+    println!("Temperature: {} °C", smart.temperature_celsius());
+    println!("Percentage used: {}%", smart.percentage_used);
+    println!("Available spare: {}%", smart.available_spare);
+    println!("Media errors: {}", smart.media_errors);
+    if smart.has_critical_warning() {
+        println!("Critical warning: 0x{:x}", smart.critical_warning);
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use vroom::QueuePairStats;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Time spent in the phases of a sort and the I/O issued on its queue pairs.
+/// Phase durations are summed over all threads, `total` is the wall clock time of the sort.
+/// The external classification, permutation and cleanup include the I/O they issue.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortMetrics {
+    pub sampling: Duration,
+    pub classification: Duration,
+    pub permutation: Duration,
+    pub cleanup: Duration,
+    /// Reading the input and writing the sorted runs
+    pub run_io: Duration,
+    pub merge_compute: Duration,
+    pub merge_io: Duration,
+    pub total: Duration,
+    /// I/O of all queue pairs
+    pub io: QueuePairStats,
+    /// I/O per queue pair, the ones of the workers in thread order followed by the ones of the sort itself
+    pub queue_pairs: Vec<QueuePairStats>,
+}
+
+impl SortMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the phases and I/O of `other`, e.g. of another thread or phase. `total` is not changed.
+    /// The queue pairs are added index by index, so the same workers in another phase stay one queue pair each.
+    pub fn combine(&mut self, other: &SortMetrics) {
+        self.sampling += other.sampling;
+        self.classification += other.classification;
+        self.permutation += other.permutation;
+        self.cleanup += other.cleanup;
+        self.run_io += other.run_io;
+        self.merge_compute += other.merge_compute;
+        self.merge_io += other.merge_io;
+        self.io.add(&other.io);
+        if self.queue_pairs.len() < other.queue_pairs.len() {
+            self.queue_pairs.resize(other.queue_pairs.len(), QueuePairStats::default());
+        }
+        for (stats, other) in self.queue_pairs.iter_mut().zip(&other.queue_pairs) {
+            stats.add(other);
+        }
+    }
+
+    /// Adds the I/O of another queue pair
+    pub fn add_queue_pair(&mut self, stats: QueuePairStats) {
+        self.io.add(&stats);
+        self.queue_pairs.push(stats);
+    }
+
+    pub fn compute_time(&self) -> Duration {
+        self.sampling + self.classification + self.permutation + self.cleanup + self.merge_compute
+    }
+
+    pub fn io_time(&self) -> Duration {
+        self.run_io + self.merge_io
+    }
+
+    /// More time was spent waiting for the device than computing
+    pub fn is_device_bound(&self) -> bool {
+        self.io_time() > self.compute_time()
+    }
+}
+
+impl Display for SortMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Total:          {:?}", self.total)?;
+        writeln!(f, "Sampling:       {:?}", self.sampling)?;
+        writeln!(f, "Classification: {:?}", self.classification)?;
+        writeln!(f, "Permutation:    {:?}", self.permutation)?;
+        writeln!(f, "Cleanup:        {:?}", self.cleanup)?;
+        writeln!(f, "Run I/O:        {:?}", self.run_io)?;
+        writeln!(f, "Merge compute:  {:?}", self.merge_compute)?;
+        writeln!(f, "Merge I/O:      {:?}", self.merge_io)?;
+        write!(
+            f,
+            "I/O: {} reads ({} bytes), {} writes ({} bytes), {} other commands, {:?} waiting for completions -> {}",
+            self.io.read_commands,
+            self.io.bytes_read,
+            self.io.write_commands,
+            self.io.bytes_written,
+            self.io.other_commands,
+            self.io.completion_wait,
+            if self.is_device_bound() { "device bound" } else { "cpu bound" }
+        )?;
+        if self.queue_pairs.len() > 1 {
+            for (i, stats) in self.queue_pairs.iter().enumerate() {
+                write!(
+                    f,
+                    "\n  Queue pair {}: {} reads ({} bytes), {} writes ({} bytes), {} other commands, {:?} waiting",
+                    i, stats.read_commands, stats.bytes_read, stats.write_commands, stats.bytes_written, stats.other_commands, stats.completion_wait
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
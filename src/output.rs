@@ -0,0 +1,146 @@
+use crate::config::*;
+use crate::conversion::*;
+use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
+use vroom::memory::{Dma, DmaSlice};
+use std::cmp::min;
+use std::error::Error;
+use std::io;
+use log::{debug, warn};
+
+/// Where the sorted elements of a sort on the device are, `len` elements starting at `lba`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortedOutput {
+    pub lba: usize,
+    pub len: usize,
+}
+
+impl SortedOutput {
+    pub fn new(lba: usize, len: usize) -> Self {
+        Self { lba, len }
+    }
+
+    /// Streams the elements with a queue pair of `nvme`, see `OutputReader`
+    pub fn reader<'a>(&self, nvme: &'a mut NvmeDevice) -> Result<OutputReader<'a>, Box<dyn Error>> {
+        OutputReader::new(nvme, *self)
+    }
+}
+
+/// Reads a `SortedOutput` front to back through two buffers of `OUTPUT_READ_AHEAD` bytes,
+/// the next buffer is read while the elements of the current one are consumed.
+/// Iterates the elements or reads their bytes with `io::Read`, both can be mixed.
+pub struct OutputReader<'a> {
+    nvme: &'a mut NvmeDevice,
+    qpair: Option<NvmeQueuePair>,
+    output: SortedOutput,
+    // buffers[0] is consumed, buffers[1] is read ahead
+    buffers: [Dma<u8>; 2],
+    // elements submitted so far, the commands still in flight into buffers[1] and the elements they read
+    submitted: usize,
+    in_flight: usize,
+    ahead: usize,
+    // bytes of buffers[0] that were read and the next one to hand out
+    filled: usize,
+    pos: usize,
+    consumed: usize,
+}
+
+impl<'a> OutputReader<'a> {
+    pub fn new(nvme: &'a mut NvmeDevice, output: SortedOutput) -> Result<Self, Box<dyn Error>> {
+        let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+        qpair.set_completion_mode(COMPLETION_MODE);
+        let buffers = [Dma::allocate(OUTPUT_READ_AHEAD)?, Dma::allocate(OUTPUT_READ_AHEAD)?];
+        let mut reader = Self { nvme, qpair: Some(qpair), output, buffers, submitted: 0, in_flight: 0, ahead: 0, filled: 0, pos: 0, consumed: 0 };
+        reader.read_ahead();
+        Ok(reader)
+    }
+
+    /// Elements not consumed yet
+    pub fn remaining(&self) -> usize {
+        self.output.len - self.consumed / 8
+    }
+
+    // submits the read of the next buffer without waiting for it
+    fn read_ahead(&mut self) {
+        let n = min(self.output.len - self.submitted, OUTPUT_READ_AHEAD / 8);
+        if n == 0 {
+            return;
+        }
+        let num_lba = (n * 8).div_ceil(LBA_SIZE);
+        let lba = self.output.lba + self.submitted / (LBA_SIZE / 8);
+        let qpair = self.qpair.as_mut().unwrap();
+        self.in_flight = qpair.submit_io(&self.buffers[1].slice(0..num_lba * LBA_SIZE), lba as u64, false);
+        self.ahead = n;
+        self.submitted += n;
+    }
+
+    // waits for the buffer read ahead and continues with it, false at the end of the output
+    fn advance(&mut self) -> io::Result<bool> {
+        if self.in_flight == 0 {
+            return Ok(false);
+        }
+        let completed = self.qpair.as_mut().unwrap().complete_io(self.in_flight);
+        self.in_flight = 0;
+        if completed.is_none() {
+            return Err(io::Error::other(format!("Reading the sorted output before element {} failed", self.submitted)));
+        }
+        self.buffers.swap(0, 1);
+        self.filled = self.ahead * 8;
+        self.pos = 0;
+        self.read_ahead();
+        Ok(true)
+    }
+}
+
+impl Iterator for OutputReader<'_> {
+    type Item = u64;
+
+    /// Panics if reading from the device fails, use `io::Read` to handle the error
+    fn next(&mut self) -> Option<u64> {
+        if self.filled - self.pos >= 8 {
+            let value = u8_to_u64(&self.buffers[0][self.pos..self.pos + 8]);
+            self.pos += 8;
+            self.consumed += 8;
+            return Some(value);
+        }
+        // an element split by an earlier `read` or spanning both buffers
+        let mut bytes = [0u8; 8];
+        match io::Read::read_exact(self, &mut bytes) {
+            Ok(()) => Some(u64::from_ne_bytes(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining(), Some(self.remaining()))
+    }
+}
+
+impl ExactSizeIterator for OutputReader<'_> {}
+
+impl io::Read for OutputReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled && !self.advance()? {
+            return Ok(0);
+        }
+        let n = min(buf.len(), self.filled - self.pos);
+        buf[..n].copy_from_slice(&self.buffers[0][self.pos..self.pos + n]);
+        self.pos += n;
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+impl Drop for OutputReader<'_> {
+    // the buffers are freed after the reads into them completed
+    fn drop(&mut self) {
+        let mut qpair = self.qpair.take().unwrap();
+        if self.in_flight > 0 && qpair.complete_io(self.in_flight).is_none() {
+            warn!("Read ahead of the sorted output failed");
+        }
+        if let Err(e) = self.nvme.delete_io_queue_pair(qpair) {
+            warn!("Deleting the queue pair of the output reader failed: {}", e);
+        }
+        debug!("Output reader consumed {} of {} elements", self.consumed / 8, self.output.len);
+    }
+}
@@ -1,29 +1,29 @@
 use crate::base_case::insertion_sort;
-use crate::sorter::{IPS2RaSorter, Task};
-use std::cell::RefCell;
+use crate::context::Workers;
+use crate::sorter::Task;
 use rayon::scope;
-//use tracing::{instrument, span, Level};
+use tracing::trace_span;
 
-thread_local! {
-    static SORTER: RefCell<IPS2RaSorter> = RefCell::new(*IPS2RaSorter::new_parallel());
-}
-
-pub fn parallel_rec(task: &mut Task) {
+pub(crate) fn parallel_rec(sorters: &Workers, task: &mut Task) {
     //println!("Starting parallel rec");
     //println!("Thread {}, len: {} processing task", rayon::current_thread_index().unwrap(), task.arr.len());
+    let _span = trace_span!("task", level = task.level, len = task.arr.len()).entered();
     if task.is_base_case() {
         insertion_sort(task.arr);
     } else {
-        let element_counts = SORTER.with(
+        let element_counts = sorters.with(
             |sorter| unsafe {
-                let mut sorter = sorter.borrow_mut();
+                if sorter.is_cancelled() {
+                    return None;
+                }
                 sorter.clear();
-                sorter.classify(task);
-                sorter.permutate_blocks(task);
-                sorter.cleanup(task);
-                sorter.element_counts
+                sorter.partition(task);
+                Some(sorter.element_counts)
             }
         );
+        let Some(element_counts) = element_counts else {
+            return;
+        };
 
         if task.level + 1 == task.level_end {
             return;
@@ -32,16 +32,19 @@ pub fn parallel_rec(task: &mut Task) {
         //println!("Thread {}, len: {} spawning subtasks", rayon::current_thread_index().unwrap(), task.arr.len());
 
         scope(|s| {
-            for mut new_task in task.generate_subtasks(&element_counts){
+            for (i, mut new_task) in task.generate_subtasks(&element_counts).into_iter().enumerate() {
                 //println!("Thread {} spawning subtasks", rayon::current_thread_index().unwrap());
+                // created here so it is a child of this task, entered on the thread that runs the subtask
+                let bucket = trace_span!("bucket", subtask = i);
                 s.spawn(move |_| {
+                    let _bucket = bucket.entered();
                     //println!("Spawning subtasks of length: {}", task.arr.len());
                     //println!("Thread {} spawned", rayon::current_thread_index().unwrap());
-                    parallel_rec(&mut new_task);
+                    parallel_rec(sorters, &mut new_task);
                 });
             }
         });
 
         //println!("Thread {}, len: {} done", rayon::current_thread_index().unwrap(), task.arr.len());
     }
-}
\ No newline at end of file
+}
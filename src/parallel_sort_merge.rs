@@ -1,35 +1,44 @@
 use crate::config::*;
 use crate::conversion::*;
-use crate::sort::{read_write_elements, read_write_hugepage_1G, read_write_hugepage_2M};
+use crate::sort::{deallocate_lbas, read_write_elements, read_write_hugepage_1G, read_write_hugepage_2M, release_resources};
 use crate::sorter::{IPS2RaSorter, Task};
+use crate::context::Workers;
+use crate::verify::{verify_sort_merge, Checksum};
+use crate::workloads::{Distribution, Workload};
+use crate::metrics::SortMetrics;
+use crate::progress::{Phase, ProgressTracker};
+use crate::cancel::Cancelled;
+use crate::sort::SortOptions;
+use crate::planner::SortPlan;
+use crate::run_generation::sort_run_parallel;
+use crate::in_place_merge::merge_in_place;
 use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
 use vroom::memory::Dma;
 use std::error::Error;
-use std::cmp::{min};
-use std::cell::RefCell;
+use std::cmp::{max, min};
 use std::cmp::Ordering::{Equal, Greater, Less};
 use std::collections::{BinaryHeap};
 use std::{io, mem};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 use rayon::{ThreadPoolBuilder};
-use log::{debug, info, LevelFilter};
-use rand::prelude::StdRng;
-use rand::{Rng, SeedableRng};
-
-thread_local! {
-    static SORTER: RefCell<IPS2RaSorter> = RefCell::new(*IPS2RaSorter::new_parallel());
-}
-
-//#[instrument]
-pub fn parallel_sort_merge(mut nvme: NvmeDevice, len: usize) -> Result<NvmeDevice, Box<dyn Error>> {
-    let num_hugepages = (len + HUGE_PAGE_SIZE_1G / 8 - 1) / (HUGE_PAGE_SIZE_1G / 8);
-
-    let max = (num_hugepages as f64).log((NUM_THREADS) as f64).ceil() as usize;
+use log::{debug, info, warn, LevelFilter};
+use tracing::{debug_span, instrument, Span};
+
+/// Sorts the runs with `run_sorters` when they span several hugepages, else each hugepage on one thread of `sorters`,
+/// and merges them with `sorters`
+#[instrument(level = "debug", skip(nvme, run_sorters, sorters, options))]
+pub(crate) fn parallel_sort_merge(nvme: &mut NvmeDevice, run_sorters: &Workers, sorters: &Workers, len: usize, plan: &SortPlan, options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    let total_start = Instant::now();
+    let num_hugepages = max(1, len.div_ceil(HUGE_PAGE_SIZE_1G / 8));
+    let run_pages = plan.run_len / (HUGE_PAGE_SIZE_1G / 8);
+
+    let max = plan.passes;
+    // in place the runs are written back where they were read
     let sort_offset =
-        if max % 2 == 0 {
+        if max % 2 == 0 || options.in_place {
             0
         } else {
             num_hugepages * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G
@@ -40,64 +49,93 @@ pub fn parallel_sort_merge(mut nvme: NvmeDevice, len: usize) -> Result<NvmeDevic
         } else {
             0
         };
+    // every level rewrites all elements, the offsets leave the result of the last level at lba 0,
+    // in place the blocks are moved into order once after the last level instead
+    let reorder = if options.in_place { min(max, 1) } else { 0 };
+    let progress = ProgressTracker::new(options.progress.clone(), plan.num_runs, max, (len * 8 * (1 + max + reorder)) as u64);
+    progress.set_phase(Phase::RunGeneration);
 
     let mut cleanup_qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+    cleanup_qpair.set_completion_mode(COMPLETION_MODE);
     let mut cleanup_buffer = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
 
-    println!("Starting parallel sorting. Len: {}, Max: {}, output_offset: {}", len, max, sort_offset);
-    let initial_separators = sort_parallel_threadlocal(len, num_hugepages, sort_offset);
+    // discard the statistics of earlier runs on the queue pairs of the sorters
+    sorters.take_metrics();
+    sorters.set_cancellation(options.cancel.clone());
+
+    info!("Starting parallel sorting. Len: {}, Run pages: {}, Fan-in: {}, Max: {}, output_offset: {}", len, run_pages, plan.fan_in, max, sort_offset);
+    let (initial_separators, checksum, mut metrics) = if run_pages > 1 {
+        match sort_runs_parallel(&mut cleanup_qpair, run_sorters, len, plan, sort_offset, sorters.num_threads(), options, &progress) {
+            Ok((separators, checksum, run_io)) => {
+                let mut metrics = run_sorters.take_metrics();
+                metrics.run_io += run_io;
+                (separators, checksum, metrics)
+            }
+            Err(e) => {
+                sorters.set_cancellation(None);
+                release_resources(nvme, cleanup_qpair, vec![cleanup_buffer])?;
+                return Err(e);
+            }
+        }
+    } else {
+        let (separators, checksum) = sort_parallel_threadlocal(sorters, len, num_hugepages, sort_offset, &progress);
+        (separators, checksum, sorters.take_metrics())
+    };
     info!("Done");
 
-    println!("Starting parallel merging");
-    merge_parallel(&mut cleanup_qpair, &mut cleanup_buffer, initial_separators, len, num_hugepages, max, sort_offset, merge_offset);
+    let layout = MergeLayout { len, num_hugepages, run_pages, fan_in: plan.fan_in, rounds: max, start_lba: sort_offset, output_lba: merge_offset };
+    let merged = options.check_cancelled().map_err(Into::into).and_then(|_| if options.in_place {
+        info!("Starting in-place merging");
+        merge_parallel_in_place(sorters, len, plan, options, &progress)
+    } else {
+        info!("Starting parallel merging");
+        Ok(merge_parallel(&mut cleanup_qpair, &mut cleanup_buffer, sorters, initial_separators, layout, options, &progress)?)
+    });
+    sorters.set_cancellation(None);
+    if let Err(e) = merged {
+        info!("Merge stopped ({}), releasing queue pairs and buffers", e);
+        release_resources(nvme, cleanup_qpair, vec![cleanup_buffer])?;
+        return Err(e);
+    }
+    metrics.combine(&sorters.take_metrics());
+    metrics.merge_io += cleanup_qpair.stats.completion_wait;
     info!("Done");
 
-    Ok(nvme)
-}
-
-pub fn initialize_thread_local(nvme: NvmeDevice, num_buffer: usize) -> NvmeDevice {
-    assert!(NUM_THREADS * min(NUM_THREADS, num_buffer) <= HUGE_PAGES_2M, "Not enough 2MiB hugepages available for buffers");
-    assert!(HUGE_PAGES_1G >= NUM_THREADS, "Not enough 1GiB hugepages available for buffers");
-    println!("Initializing thread local sorters");
-    let nvme_arc = Arc::new(Mutex::new(nvme));
-
-    (0..NUM_THREADS).into_par_iter().for_each(|thread_id| {
-        let nvme_clone = Arc::clone(&nvme_arc);
-
-        let mut nvme = nvme_clone.lock().unwrap();
-        let qpair = nvme.create_io_queue_pair(QUEUE_LENGTH).unwrap();
-
-        // Allocate buffers
-        let buffers: Vec<Dma<u8>> = (0..min(NUM_THREADS, num_buffer))
-            .map(|_| Dma::allocate(HUGE_PAGE_SIZE_2M).unwrap())
-            .collect();
-        let sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G).unwrap();
-
-        // Initialize the SORTER for this thread
-        SORTER.with(|sorter| {
-            let mut sorter_ref = sorter.borrow_mut();
-            *sorter_ref = *IPS2RaSorter::new_ext_sequential(qpair, buffers, sort_buffer);
-        });
-
-        info!("Thread {} initialized sorter", thread_id);
-    });
+    if VERIFY_SORT_MERGE {
+        progress.set_phase(Phase::Verifying);
+        verify_sort_merge(&mut cleanup_qpair, &mut cleanup_buffer, 0, len, &checksum)?;
+        info!("Output verified");
+    }
 
-    // Return the modified NVMe device
-    match Arc::try_unwrap(nvme_arc) {
-        Ok(mutex) => mutex.into_inner().unwrap(),
-        Err(_) => panic!("There are still references to the Arc, unable to unwrap."),
+    if options.trim_scratch && max > 0 && !options.in_place {
+        // result always ends up at lba 0, the second region was only used for the intermediate runs
+        info!("Deallocating scratch region");
+        deallocate_lbas(&mut cleanup_qpair, &mut cleanup_buffer, num_hugepages * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, num_hugepages * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G)?;
     }
+
+    progress.set_phase(Phase::Done);
+    metrics.add_queue_pair(cleanup_qpair.stats);
+    metrics.total = total_start.elapsed();
+    Ok(metrics)
 }
 
-//#[instrument]
-pub fn sort_parallel_threadlocal(len: usize, num_hugepages: usize, write_offset: usize) -> Vec<Vec<u64>> {
+/// Sorts each hugepage and writes it to `write_offset`. Returns the local separators and the checksum of the input.
+#[instrument(level = "debug", skip(sorters, progress))]
+pub(crate) fn sort_parallel_threadlocal(sorters: &Workers, len: usize, num_hugepages: usize, write_offset: usize, progress: &ProgressTracker) -> (Vec<Vec<u64>>, Checksum) {
     let local_separators: Arc<Mutex<Vec<Vec<u64>>>> = Arc::new(Mutex::new(vec![Vec::new(); num_hugepages]));
+    let checksum = Arc::new(Mutex::new(Checksum::new()));
+    let span = Span::current();
 
-    (0..num_hugepages).into_par_iter().for_each(|i| {
-        SORTER.with(|sorter| {
-            let mut sorter = sorter.borrow_mut();
+    sorters.install(|| (0..num_hugepages).into_par_iter().for_each(|i| {
+        let _run = debug_span!(parent: &span, "run", hugepage = i).entered();
+        sorters.with(|sorter| {
+            if sorter.is_cancelled() {
+                return;
+            }
             info!("Thread {} starting sort of hugepage {}.", rayon::current_thread_index().unwrap(), i);
+            let start = Instant::now();
             sorter.read_write_sort_buffer_1G(i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, false);
+            sorter.metrics.run_io += start.elapsed();
 
             let mut buffer = sorter.sort_buffer.take().unwrap();
             let u64slice = u8_to_u64_slice(&mut buffer[0..{
@@ -108,58 +146,141 @@ pub fn sort_parallel_threadlocal(len: usize, num_hugepages: usize, write_offset:
                     (len - i * HUGE_PAGE_SIZE_1G / 8)*8
                 }
             }]);
+            if VERIFY_SORT_MERGE {
+                checksum.lock().unwrap().combine(&Checksum::from_slice(u64slice));
+            }
+            let run_bytes = (u64slice.len() * 8) as u64;
 
             let mut task = Task::new(u64slice, 0,  8);
-            if !task.sample() {
-                return;
+            let start = Instant::now();
+            // sorted, descending and equal hugepages are not sampled, the sample reversed the descending ones already
+            let sampled = task.sample();
+            sorter.metrics.sampling += start.elapsed();
+            if sampled {
+                sorter.sequential_rec(&mut task);
             }
-            sorter.sequential_rec(&mut task);
 
-            let local_separator = compute_local_separators(u64slice, NUM_THREADS - 1);
+            let local_separator = compute_local_separators(u64slice, sorters.num_threads() - 1);
             sorter.sort_buffer = Some(buffer);
+            let start = Instant::now();
             sorter.read_write_sort_buffer_1G(i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G + write_offset, true);
-            println!("Thread {} finished sorting hugepage {}. Writing to lba {}. Local separators: {:?}. First elements: {:?}", rayon::current_thread_index().unwrap(), i, i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G + write_offset, local_separator, u8_to_u64_slice(&mut sorter.sort_buffer.as_mut().unwrap()[0..128]));
+            sorter.metrics.run_io += start.elapsed();
+            progress.run_sorted(run_bytes);
+            debug!("Thread {} finished sorting hugepage {}. Writing to lba {}. Local separators: {:?}. First elements: {:?}", rayon::current_thread_index().unwrap(), i, i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G + write_offset, local_separator, u8_to_u64_slice(&mut sorter.sort_buffer.as_mut().unwrap()[0..128]));
 
             // push to local separators at idx i.
             let mut local_separators_locked = local_separators.lock().unwrap();
             local_separators_locked[i] = local_separator;
             sorter.clear();
         });
-    });
+    }));
 
     let mut separators_guard = local_separators.lock().unwrap();
-    mem::take(&mut *separators_guard)
+    let checksum = *checksum.lock().unwrap();
+    (mem::take(&mut *separators_guard), checksum)
+}
+
+/// Sorts the runs of `plan` one after another with all sorters of the pool and writes them to `write_offset`.
+/// Returns the separators of each run, the checksum of the input and the time spent reading and writing.
+#[instrument(level = "debug", skip_all, fields(runs = plan.num_runs))]
+fn sort_runs_parallel(qpair: &mut NvmeQueuePair, sorters: &Workers, len: usize, plan: &SortPlan, write_offset: usize, num_threads: usize, options: &SortOptions, progress: &ProgressTracker) -> Result<(Vec<Vec<u64>>, Checksum, Duration), Box<dyn Error>> {
+    let mut run_buffer = Dma::allocate(plan.run_len * 8)?;
+    let mut separators = Vec::with_capacity(plan.num_runs);
+    let mut checksum = Checksum::new();
+    let mut run_io = Duration::ZERO;
+    sorters.set_cancellation(options.cancel.clone());
+    for i in 0..plan.num_runs {
+        if options.is_cancelled() {
+            break;
+        }
+        let _run = debug_span!("run", run = i).entered();
+        let lba = i * plan.run_len / (LBA_SIZE / 8);
+        let run_len = min(plan.run_len, len - i * plan.run_len);
+        let start = Instant::now();
+        read_write_elements(qpair, &mut run_buffer, lba, 0, run_len, false);
+        run_io += start.elapsed();
+
+        let u64slice = u8_to_u64_slice(&mut run_buffer[0..run_len * 8]);
+        if VERIFY_SORT_MERGE {
+            checksum.add_slice(u64slice);
+        }
+        sort_run_parallel(sorters, u64slice);
+        separators.push(compute_local_separators(u64slice, num_threads - 1));
+
+        let start = Instant::now();
+        read_write_elements(qpair, &mut run_buffer, lba + write_offset, 0, run_len, true);
+        run_io += start.elapsed();
+        progress.run_sorted((run_len * 8) as u64);
+        info!("Sorted run {} of {} elements", i, run_len);
+    }
+    sorters.set_cancellation(None);
+    run_buffer.free()?;
+    Ok((separators, checksum, run_io))
 }
 
-//#[instrument]
-pub fn merge_parallel(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, initial_separators: Vec<Vec<u64>>, len: usize, mut num_hugepages: usize, max: usize, mut start_lba: usize, mut output_lba: usize) {
+/// Merges the sorted runs in place with the buffers of the first sorter, the merge does not need a scratch region.
+/// It runs on sorter 0 alone, the other sorters are idle, so it is no faster than the sequential in-place merge.
+fn merge_parallel_in_place(sorters: &Workers, len: usize, plan: &SortPlan, options: &SortOptions, progress: &ProgressTracker) -> Result<(), Box<dyn Error>> {
+    let runs: Vec<(usize, usize)> = (0..plan.num_runs)
+        .map(|i| (i * plan.run_len, min(plan.run_len, len - i * plan.run_len)))
+        .collect();
+    sorters.with_first(|sorter| sorter.timed_merge(|sorter| {
+        let qpair = sorter.qpair.as_mut().expect("Sorter has no queue pair");
+        let buffers = sorter.buffers.as_mut().expect("Sorter has no buffers");
+        let sort_buffer = sorter.sort_buffer.as_mut().expect("Sorter has no sort buffer");
+        merge_in_place(qpair, &runs, plan, buffers, sort_buffer, options, progress).map(|_| ())
+    }))
+}
+
+/// Runs and rounds of a parallel merge
+pub(crate) struct MergeLayout {
+    pub len: usize,
+    pub num_hugepages: usize,
+    // 1GiB hugepages per initial run
+    pub run_pages: usize,
+    pub fan_in: usize,
+    pub rounds: usize,
+    // the runs of the first round start at `start_lba` and are merged to `output_lba`, the two swap after each round
+    pub start_lba: usize,
+    pub output_lba: usize,
+}
+
+/// Merges the sorted runs of `layout.run_pages` hugepages each in `layout.rounds` rounds of `layout.fan_in` runs
+#[instrument(level = "debug", skip_all, fields(len = layout.len, rounds = layout.rounds))]
+pub(crate) fn merge_parallel(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, sorters: &Workers, initial_separators: Vec<Vec<u64>>, layout: MergeLayout, options: &SortOptions, progress: &ProgressTracker) -> Result<(), Cancelled> {
+    let MergeLayout { len, num_hugepages, run_pages, fan_in, rounds: max, mut start_lba, mut output_lba } = layout;
     debug!("Total number of hugepages: {num_hugepages}, start_lba: {start_lba}, output_lba: {output_lba}");
 
-    assert_eq!(initial_separators.len(), num_hugepages);
+    assert_eq!(initial_separators.len(), num_hugepages.div_ceil(run_pages));
 
+    // each merge splits its runs into one partition per thread, independent of the fan-in
+    let num_threads = sorters.num_threads();
     let mut separators = initial_separators;
     info!("Initial separators: {:?}", separators);
 
     for i in 0..max {
+        let _round = debug_span!("merge_round", round = i).entered();
+        progress.start_merge_level(i);
         info!("\n\ni: {i}, start_lba: {start_lba}, output_lba: {output_lba}, separators: {:?}", separators);
 
-        let input_length = NUM_THREADS.pow(i as u32);
-        let result_length = input_length * NUM_THREADS;
+        let input_length = run_pages * fan_in.pow(i as u32);
+        let result_length = input_length * fan_in;
 
         let mut remaining_hugepages = (num_hugepages + input_length - 1) / input_length;
         let mut next_separators: Vec<Vec<u64>> = vec![];
-        let mut flattened_separators: Vec<u64> = Vec::with_capacity((NUM_THREADS - 1) * min(NUM_THREADS, remaining_hugepages)); // TODO: double check
+        let mut flattened_separators: Vec<u64> = Vec::with_capacity((num_threads - 1) * min(fan_in, remaining_hugepages)); // TODO: double check
 
         for j in 0..(num_hugepages + result_length - 1) / result_length {
+            options.check_cancelled()?;
             info!("\nj: {j}, input_length: {input_length}, result_length: {result_length}, remaining_hugepages: {remaining_hugepages}");
             // read line from stdin
             //let mut input = String::new();
             //io::stdin().read_line(&mut input).unwrap();
             let mut last_length = 0;
             let cur_num_hugepages =
-                if remaining_hugepages > NUM_THREADS {
+                if remaining_hugepages > fan_in {
                     last_length = input_length * HUGE_PAGE_SIZE_1G / 8;
-                    NUM_THREADS
+                    fan_in
                 } else {
                     last_length = len - ((j*result_length*HUGE_PAGE_SIZE_1G/8) + ((remaining_hugepages-1) * input_length * HUGE_PAGE_SIZE_1G / 8));
                     remaining_hugepages
@@ -170,22 +291,24 @@ pub fn merge_parallel(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, initial_s
             if cur_num_hugepages <= 1 {
                 info!("Only one hugepage remaining. Copying {last_length} elements from lba {} to output lba {}", start_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, output_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK);
                 copy_elements_ext(qpair, buffer, start_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, output_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, last_length);
-                next_separators.push(separators[j * NUM_THREADS].clone());
+                next_separators.push(separators[j * fan_in].clone());
+                progress.written((last_length * 8) as u64);
                 break;
             }
 
             flattened_separators.clear();
-            for vec in separators[j * NUM_THREADS..j * NUM_THREADS + cur_num_hugepages].iter() {
+            for vec in separators[j * fan_in..j * fan_in + cur_num_hugepages].iter() {
                 flattened_separators.extend(vec);
             }
             flattened_separators.sort_unstable();
             info!("Flattened separators: {:?}", flattened_separators);
 
-            let global_separators = compute_local_separators(&flattened_separators, NUM_THREADS - 1);
+            let global_separators = compute_local_separators(&flattened_separators, num_threads - 1);
             info!("Global separators: {:?}", global_separators);
             // TODO: double check start_lba and output_lba
-            prepare_thread_merge(qpair, buffer, &global_separators, start_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, output_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, input_length, cur_num_hugepages, last_length);
+            prepare_thread_merge(qpair, buffer, sorters, &global_separators, start_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, output_lba + j * result_length * CHUNKS_PER_HUGE_PAGE_1G * LBA_PER_CHUNK, input_length, cur_num_hugepages, last_length, options)?;
             next_separators.push(global_separators);
+            progress.written((((cur_num_hugepages - 1) * input_length * HUGE_PAGE_SIZE_1G / 8 + last_length) * 8) as u64);
             info!("Next separators: {:?}", next_separators);
             remaining_hugepages -= cur_num_hugepages;
         }
@@ -194,46 +317,49 @@ pub fn merge_parallel(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, initial_s
         start_lba = output_lba;
         output_lba = tmp;
     }
+    Ok(())
 }
 
 
-//#[instrument]
-fn prepare_thread_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, global_separators: &Vec<u64>, start_lba: usize, write_lba: usize, input_length: usize, remaining_hugepages: usize, last_length: usize) {
+#[instrument(level = "debug", skip_all, fields(runs = remaining_hugepages))]
+fn prepare_thread_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, sorters: &Workers, global_separators: &Vec<u64>, start_lba: usize, write_lba: usize, input_length: usize, remaining_hugepages: usize, last_length: usize, options: &SortOptions) -> Result<(), Cancelled> {
     info!("Preparing thread merge with global separators: {:?}, start_lba: {}, write_lba: {}, input_length: {}, remaining_hugepages: {}", global_separators, start_lba, write_lba, input_length, remaining_hugepages);
-    let remainders: Arc<Mutex<Vec<Vec<u64>>>> = Arc::new(Mutex::new(vec![Vec::new(); NUM_THREADS]));
+    let num_threads = sorters.num_threads();
+    let remainders: Arc<Mutex<Vec<Vec<u64>>>> = Arc::new(Mutex::new(vec![Vec::new(); num_threads]));
+    let span = Span::current();
 
-    let local_indices: Vec<Vec<usize>> = (0..remaining_hugepages).into_par_iter().map(|x| {
-        SORTER.with(
+    let local_indices: Vec<Vec<usize>> = sorters.install(|| (0..remaining_hugepages).into_par_iter().map(|x| {
+        let _search = debug_span!(parent: &span, "binary_search", run = x).entered();
+        sorters.with(
             |sorter| unsafe {
-                let mut sorter = sorter.borrow_mut();
-                sorter.binary_search_indices(global_separators,
+                sorter.timed_merge(|sorter| sorter.binary_search_indices(global_separators,
                                              start_lba + x * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G * input_length,
                                              if x == remaining_hugepages - 1 {
                                                  last_length
                                              } else {
                                                  input_length * HUGE_PAGE_SIZE_1G / 8
-                                             })
+                                             }))
             }
         )
-    }).collect();
+    }).collect());
     info!("Local indices: {:?}", local_indices);
 
-    let ranges = transform_indices_to_ranges(&local_indices, input_length * HUGE_PAGE_SIZE_1G / 8, NUM_THREADS, last_length);
+    let ranges = transform_indices_to_ranges(&local_indices, input_length * HUGE_PAGE_SIZE_1G / 8, num_threads, last_length);
     info!("Ranges: {:?}", ranges);
 
     //pre-compute total ranges
-    let mut total_ranges: Vec<(usize, usize)> = Vec::with_capacity(NUM_THREADS);
+    let mut total_ranges: Vec<(usize, usize)> = Vec::with_capacity(num_threads);
     let mut sum: usize = 0;
-    for i in 0..NUM_THREADS {
+    for i in 0..num_threads {
         let start = sum;
         sum += ranges[i].iter().map(|(start, end)| end - start).sum::<usize>();
         total_ranges.push((start, sum));
     }
     info!("Total ranges: {:?}", total_ranges);
 
-    (0..NUM_THREADS).into_par_iter().for_each(|thread_id| {
-        let merge_result = SORTER.with(|sorter| {
-            let mut sorter = sorter.borrow_mut();
+    sorters.install(|| (0..num_threads).into_par_iter().for_each(|thread_id| {
+        let _merge = debug_span!(parent: &span, "thread_merge", thread = thread_id).entered();
+        let merge_result = sorters.with(|sorter| {
             let mut output_lba_offset = if thread_id == 0 { 0 } else { total_ranges[thread_id - 1].1 * 8 / LBA_SIZE };
             info!("output_lba_offset: {output_lba_offset} (total_ranges[thread_id - 1].1: {} * 8 / LBA_SIZE: {LBA_SIZE})", if thread_id == 0 {0} else {total_ranges[thread_id - 1].1});
 
@@ -241,23 +367,26 @@ fn prepare_thread_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, global_
             //let mut input = String::new();
             //std::io::stdin().read_line(&mut input).unwrap();
 
-            sorter.thread_merge(
+            sorter.timed_merge(|sorter| sorter.thread_merge(
                 &ranges[thread_id],
                 start_lba,
                 write_lba + output_lba_offset,
                 total_ranges[thread_id].0 % (LBA_SIZE / 8),
                 total_ranges[thread_id].1 - total_ranges[thread_id].0,
-                input_length * HUGE_PAGE_SIZE_1G)
+                input_length * HUGE_PAGE_SIZE_1G))
         });
 
         // Store the result in the appropriate part of remainders
         let mut remainders_locked = remainders.lock().unwrap();
         remainders_locked[thread_id] = merge_result;
-    });
+    }));
 
     //let span = span!(Level::INFO, "cleanup");
     //let _enter = span.enter();
 
+    // the merges stop early when cancelled, their remainders are incomplete
+    options.check_cancelled()?;
+
     // Cleanup:
     info!("Starting cleanup");
     let mut remainders_locked = remainders.lock().unwrap();
@@ -265,7 +394,7 @@ fn prepare_thread_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, global_
     // read line from stdin
     //let mut input = String::new();
     //std::io::stdin().read_line(&mut input).unwrap();
-    for i in 0..NUM_THREADS {
+    for i in 0..num_threads {
         sum += total_ranges[i].1 - total_ranges[i].0;
         let tailsize = sum % (LBA_SIZE / 8);
         if tailsize > 0 {
@@ -276,6 +405,7 @@ fn prepare_thread_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, global_
             read_write_elements(qpair, buffer, lba, 0, LBA_SIZE / 8, true);
         }
     }
+    Ok(())
 }
 
 struct HeapEntry {
@@ -304,6 +434,17 @@ impl PartialOrd for HeapEntry {
 }
 
 impl IPS2RaSorter {
+    // time waiting for completions on the sorter's queue pair counts as merge I/O, the rest as merge compute
+    fn timed_merge<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let wait = self.qpair.as_ref().map_or(Duration::ZERO, |qpair| qpair.stats.completion_wait);
+        let start = Instant::now();
+        let result = f(self);
+        let io = self.qpair.as_ref().map_or(Duration::ZERO, |qpair| qpair.stats.completion_wait) - wait;
+        self.metrics.merge_io += io;
+        self.metrics.merge_compute += start.elapsed().saturating_sub(io);
+        result
+    }
+
     pub fn thread_merge(&mut self, indices: &Vec<(usize, usize)>, start_lba: usize, output_lba: usize, output_offset: usize, total_length: usize, input_length_byte: usize) -> Vec<u64> {
         /*if indices[0].0 != 0 {
             debug!("Thread {} waiting for other threads to finish", rayon::current_thread_index().unwrap());
@@ -323,20 +464,19 @@ impl IPS2RaSorter {
         assert!(self.buffers.is_some());
         assert!(self.sort_buffer.is_some());
 
+        let cancel = self.cancel.clone().unwrap_or_default();
         let qpair = self.qpair.as_mut().unwrap();
         let buffers = self.buffers.as_mut().unwrap();
         let mut output_buffer = self.sort_buffer.as_mut().unwrap();
-        assert!(buffers.len() >= NUM_THREADS, "At least NUM_THREADS 2MiB buffers required for each parallel merge thread");
+        assert!(buffers.len() >= indices.len(), "One 2MiB buffer required for each merged run");
 
         let mut minHeap = BinaryHeap::new();
-        let mut write_elements: Vec<usize> = vec![0; NUM_THREADS];
-        let mut output_write_hugepages: Vec<usize> = vec![0; NUM_THREADS];
+        let mut write_elements: Vec<usize> = vec![0; indices.len()];
 
         let tailsize = (total_length + output_offset) % (LBA_SIZE / 8);
         info!("Thread {} starting thread merge with indices: {:?}, start_lba: {}, output_lba: {}, output_offset: {}, total_length: {}, input_length: {}, tailsize: {}", rayon::current_thread_index().unwrap(), indices, start_lba, output_lba, output_offset, total_length, input_length_byte, tailsize);
 
         // read first hugepages (2M) of each chunk
-        // TODO: check if not NUM_THREADS
         for i in 0..indices.len() {
             if indices[i].0 >= indices[i].1 {
                 continue;
@@ -380,6 +520,9 @@ impl IPS2RaSorter {
                             //assert_eq!((tailsize + output_offset) % (LBA_SIZE / 8), 0);
                         }
                         write_idx = 0;
+                        if cancel.is_cancelled() {
+                            return Vec::new();
+                        }
                     }
 
                     let global_idx = write_elements[array] + indices[array].0;
@@ -551,38 +694,31 @@ fn calculate_lba(idx: usize, start_lba: usize, i: usize, input_length: usize) ->
     (i * input_length / LBA_SIZE + start_lba + idx * 8 / LBA_SIZE, 0)
 }
 
-pub fn prepare_benchmark_parallel(num_hugepages: usize, seed: usize) { // Use for benchmarking only!!
-    // assume thread-local sorters are initialized
-    println!("Preparing benchmark with {} hugepages", num_hugepages);
-    fn generate_uniform(rng: &mut StdRng, size: usize) -> Vec<u64> {
-        (0..size).map(|_| rng.gen::<u64>()).collect()
-    }
+pub(crate) fn prepare_benchmark_parallel(sorters: &Workers, num_hugepages: usize, seed: usize) { // Use for benchmarking only!!
+    info!("Preparing benchmark with {} hugepages", num_hugepages);
+    let workload = Workload::new(Distribution::Uniform, num_hugepages * HUGE_PAGE_SIZE_1G / 8, seed as u64);
 
-    (0..num_hugepages).into_par_iter().for_each(|i| {
-        println!("Thread {} preparing hugepage {}", rayon::current_thread_index().unwrap(), i);
-        let mut rng = StdRng::seed_from_u64((i + seed) as u64 * seed as u64);
-        //let mut data = generate_uniform(&mut rng, HUGE_PAGE_SIZE_1G / 8);
-        let mut data: Vec<u64> = (0..HUGE_PAGE_SIZE_1G as u64 / 8).collect();
-        SORTER.with(|sorter| {
-            let mut sorter = sorter.borrow_mut();
+    sorters.install(|| (0..num_hugepages).into_par_iter().for_each(|i| {
+        debug!("Thread {} preparing hugepage {}", rayon::current_thread_index().unwrap(), i);
+        sorters.with(|sorter| {
             let mut buffer = sorter.sort_buffer.take().unwrap();
             let mut qpair = sorter.qpair.take().unwrap();
-            &buffer[0..HUGE_PAGE_SIZE_1G].copy_from_slice(&u64_to_u8_slice(&mut data));
+            workload.fill(i * HUGE_PAGE_SIZE_1G / 8, u8_to_u64_slice(&mut buffer[0..HUGE_PAGE_SIZE_1G]));
             read_write_hugepage_1G(&mut qpair, i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, &mut buffer, true);
 
             sorter.sort_buffer = Some(buffer);
             sorter.qpair = Some(qpair);
         });
-    });
+    }));
 }
 
 // like parallel_sort_merge, only with time measurements
 // Mode 0: only sort
 // Mode 1: merge (sort required)
-pub fn bench_parallel_sort_merge(mut nvme: NvmeDevice, len: usize, mode: usize) -> Result<(NvmeDevice, Duration), Box<dyn Error>> {
-    let num_hugepages = (len + HUGE_PAGE_SIZE_1G / 8 - 1) / (HUGE_PAGE_SIZE_1G / 8);
+pub(crate) fn bench_parallel_sort_merge(nvme: &mut NvmeDevice, sorters: &Workers, len: usize, plan: &SortPlan, mode: usize) -> Result<Duration, Box<dyn Error>> {
+    let num_hugepages = plan.num_runs;
 
-    let max = (num_hugepages as f64).log((NUM_THREADS) as f64).ceil() as usize;
+    let max = plan.passes;
     let sort_offset =
         if max % 2 == 0 {
             0
@@ -597,22 +733,24 @@ pub fn bench_parallel_sort_merge(mut nvme: NvmeDevice, len: usize, mode: usize)
         };
 
     let mut cleanup_qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
+    cleanup_qpair.set_completion_mode(COMPLETION_MODE);
     let mut cleanup_buffer = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
 
     if mode == 0 {
         let mut start = std::time::Instant::now();
-        sort_parallel_threadlocal(len, num_hugepages, sort_offset);
+        sort_parallel_threadlocal(sorters, len, num_hugepages, sort_offset, &ProgressTracker::disabled());
         let duration = start.elapsed();
-        return Ok((nvme, duration));
+        return Ok(duration);
     }
 
-    let initial_separators = sort_parallel_threadlocal(len, num_hugepages, sort_offset);
-    println!("Starting parallel merging");
+    let (initial_separators, _) = sort_parallel_threadlocal(sorters, len, num_hugepages, sort_offset, &ProgressTracker::disabled());
+    info!("Starting parallel merging");
     let mut start = std::time::Instant::now();
-    merge_parallel(&mut cleanup_qpair, &mut cleanup_buffer, initial_separators, len, num_hugepages, max, sort_offset, merge_offset);
+    let layout = MergeLayout { len, num_hugepages, run_pages: 1, fan_in: plan.fan_in, rounds: max, start_lba: sort_offset, output_lba: merge_offset };
+    merge_parallel(&mut cleanup_qpair, &mut cleanup_buffer, sorters, initial_separators, layout, &SortOptions::new(), &ProgressTracker::disabled())?;
     let duration = start.elapsed();
 
-    Ok((nvme, duration))
+    Ok(duration)
 }
 
 
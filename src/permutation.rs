@@ -1,10 +1,9 @@
 use crate::config::*;
-use crate::conversion::*;
-use crate::sort::{find_bucket_ips2ra, read_write_elements, read_write_hugepage_1G};
+use crate::sort::find_bucket_ips2ra;
 use crate::sorter::{ExtTask, IPS2RaSorter, Task};
-use vroom::memory::{Dma, DmaSlice};
-use vroom::{NvmeQueuePair};
+use crate::block_cache::BlockCache;
 use std::cmp::max;
+use std::error::Error;
 use std::ptr::write;
 use log::{debug, info};
 
@@ -121,18 +120,11 @@ impl IPS2RaSorter {
         (tmp, self.pointers[bucket].1)
     }
 
-    pub fn permutate_blocks_ext(&mut self, task: &mut ExtTask){
+    pub(crate) fn permutate_blocks_ext(&mut self, task: &mut ExtTask, cache: &mut BlockCache) -> Result<(), Box<dyn Error>> {
         self.calculate_pointers();
 
         debug!("External Sorter before permutation: {:?}", self);
 
-        assert!(self.qpair.is_some(), "Cannot classify_in_out without qpair");
-        assert!(self.buffers.is_some(), "Cannot classify_in_out without buffers");
-
-        let buffer = self.buffers.as_mut().unwrap();
-
-        assert!(buffer.len() > 1, "Need at least two buffers for external permutation");
-
         let mut read_bucket = 0;
         let max_off = Self::align_to_next_block(task.size+1) - BLOCKSIZE;
 
@@ -142,12 +134,12 @@ impl IPS2RaSorter {
             let mut dest_bucket: i64;
 
             while {
-                dest_bucket = self.classify_and_read_block_ext(read_bucket, task);
+                dest_bucket = self.classify_and_read_block_ext(read_bucket, task, cache)?;
                 dest_bucket != -1
             } {
                 let mut current_swap: bool = false;
                 while {
-                    dest_bucket = self.swap_block_ext(max_off, dest_bucket, current_swap, task);
+                    dest_bucket = self.swap_block_ext(max_off, dest_bucket, current_swap, task, cache)?;
                     dest_bucket != -1
                 } {
                     current_swap = !current_swap;
@@ -155,33 +147,33 @@ impl IPS2RaSorter {
             }
             read_bucket = (read_bucket + 1) % K;
         }
+        Ok(())
     }
 
-    fn classify_and_read_block_ext(&mut self, bucket: usize, task: &mut ExtTask) -> i64 {
+    fn classify_and_read_block_ext(&mut self, bucket: usize, task: &mut ExtTask, cache: &mut BlockCache) -> Result<i64, Box<dyn Error>> {
         let (write_ptr, read_ptr) = self.fetch_sub_most_significant(bucket);
-        let qpair = self.qpair.as_mut().unwrap();
-        let buffer = self.buffers.as_mut().unwrap();
         debug!("Classify block {bucket}: write_ptr={write_ptr}, read_ptr={read_ptr}");
 
         if read_ptr<write_ptr {
-            return -1;
+            return Ok(-1);
         }
 
-        // read from ssd
-        let (cur_lba, cur_offset) = calculate_lba_offset(read_ptr as usize, task.start_lba, task.offset);
-        read_write_elements(qpair, &mut buffer[0], cur_lba, cur_offset, BLOCKSIZE, false);
-        debug!("Copying {:?} (lba: {cur_lba}) to swap buffer 0", &u8_to_u64_slice(&mut buffer[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
-        self.swap_buffer[0].copy_from_slice(u8_to_u64_slice(&mut buffer[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
+        cache.read(task.position(read_ptr as usize), &mut self.swap_buffer[0])?;
+        debug!("Copied {:?} (start_index: {read_ptr}) to swap buffer 0", &self.swap_buffer[0]);
 
-        find_bucket_ips2ra(self.swap_buffer[0][0], task.level) as i64
+        // readahead of the next block at the read pointer of the bucket
+        let (next_write, next_read) = self.pointers[bucket];
+        if next_read >= next_write {
+            cache.prefetch(task.position(next_read as usize), BLOCKSIZE)?;
+        }
+
+        Ok(task.bucket(self.swap_buffer[0][0]) as i64)
     }
 
-    fn swap_block_ext(&mut self, max_off: usize, dest_bucket: i64, current_swap: bool, task: &mut ExtTask) -> i64 {
+    fn swap_block_ext(&mut self, max_off: usize, dest_bucket: i64, current_swap: bool, task: &mut ExtTask, cache: &mut BlockCache) -> Result<i64, Box<dyn Error>> {
         let mut new_dest_bucket: i64;
         let mut write_ptr: i64 = -1;
         let mut read_ptr: i64 = -1;
-        let mut cur_lba: usize;
-        let mut cur_offset: usize;
 
         debug!("Swap block: dest_bucket={dest_bucket}, current_swap={current_swap}");
         loop {
@@ -192,40 +184,34 @@ impl IPS2RaSorter {
                     // case overflow
                     self.overflow_buffer.copy_from_slice(&self.swap_buffer[current_swap as usize]);
                     self.overflow = true;
-                    return -1;
+                    return Ok(-1);
                 }
                 debug!("write ptr ({}) > read ptr ({}) && write_ptr > max_off ({})", write_ptr, read_ptr, max_off);
 
-                (cur_lba, cur_offset) = calculate_lba_offset(write_ptr as usize, task.start_lba, task.offset);
-                read_write_elements(self.qpair.as_mut().unwrap(), &mut self.buffers.as_mut().unwrap()[0], cur_lba, cur_offset, BLOCKSIZE, false);
-                debug!("1: Writing swap buffer {current_swap} to {:?} (lba: {cur_lba})", u8_to_u64_slice(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
-                self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8].copy_from_slice(u64_to_u8_slice(&mut self.swap_buffer[current_swap as usize]));
-
-                // write back to ssd
-                read_write_elements(self.qpair.as_mut().unwrap(), &mut self.buffers.as_mut().unwrap()[0], cur_lba, cur_offset, BLOCKSIZE, true);
-
-                return -1;
+                debug!("1: Writing swap buffer {current_swap} to start_index {write_ptr}");
+                cache.write(task.position(write_ptr as usize), &self.swap_buffer[current_swap as usize])?;
+                return Ok(-1);
             }
-            // read next block
-            (cur_lba, cur_offset) = calculate_lba_offset(write_ptr as usize, task.start_lba, task.offset);
-            read_write_elements(self.qpair.as_mut().unwrap(), &mut self.buffers.as_mut().unwrap()[0], cur_lba, cur_offset, BLOCKSIZE, false);
-            debug!("Reading new block: {:?} (lba: {cur_lba})", u8_to_u64_slice(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
-            new_dest_bucket = find_bucket_ips2ra(u8_to_u64(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+1)*8]), task.level) as i64;
+            // classify next block by its first element
+            new_dest_bucket = task.bucket(cache.get(task.position(write_ptr as usize))?) as i64;
 
             if new_dest_bucket != dest_bucket {
                 break;
             }
         }
         // copy to swap buffer
-        debug!("Copying {:?} (lba: {cur_lba}) to swap buffer {}", &u8_to_u64_slice(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]), 1-current_swap as usize);
-        self.swap_buffer[1-current_swap as usize].copy_from_slice(u8_to_u64_slice(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
-        debug!("Writing swap buffer {current_swap} to {:?} (lba: {cur_lba})", u8_to_u64_slice(&mut self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8]));
-        self.buffers.as_mut().unwrap()[0][cur_offset*8..(cur_offset+BLOCKSIZE)*8].copy_from_slice(u64_to_u8_slice(&mut self.swap_buffer[current_swap as usize]));
-
-        // write back to ssd
-        read_write_elements(self.qpair.as_mut().unwrap(), &mut self.buffers.as_mut().unwrap()[0], cur_lba, cur_offset, BLOCKSIZE, true);
+        cache.read(task.position(write_ptr as usize), &mut self.swap_buffer[1-current_swap as usize])?;
+        debug!("Copied {:?} (start_index: {write_ptr}) to swap buffer {}", &self.swap_buffer[1-current_swap as usize], 1-current_swap as usize);
+        debug!("Writing swap buffer {current_swap} to start_index {write_ptr}");
+        cache.write(task.position(write_ptr as usize), &self.swap_buffer[current_swap as usize])?;
+
+        // readahead of the next block at the write pointer of the bucket
+        let (next_write, next_read) = self.pointers[dest_bucket as usize];
+        if next_write <= next_read {
+            cache.prefetch(task.position(next_write as usize), BLOCKSIZE)?;
+        }
 
-        new_dest_bucket
+        Ok(new_dest_bucket)
     }
 
     pub fn align_to_next_block(index: usize) -> usize {
@@ -233,16 +219,6 @@ impl IPS2RaSorter {
     }
 }
 
-// TODO: include offset from task
-pub fn calculate_lba_offset(index: usize, start_lba: usize, task_offset: usize) -> (usize, usize){
-    let lba = index*8/LBA_SIZE + start_lba;
-    let offset = index % BLOCKSIZE + task_offset;
-
-    debug!("Index: {}, LBA: {}, Offset: {}", index, lba, offset);
-
-    (lba, offset)
-}
-
 /*
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,174 @@
+use crate::config::*;
+use crate::sort::SortOptions;
+use crate::run_generation::RunGeneration;
+use std::cmp::{max, min};
+use std::error::Error;
+use std::fs;
+use log::{info, warn};
+
+const SYSFS_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
+
+/// Free hugepages of both sizes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hugepages {
+    pub free_2m: usize,
+    pub free_1g: usize,
+}
+
+impl Hugepages {
+    /// Reads the free hugepages from sysfs
+    pub fn from_sysfs() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            free_2m: read_free_hugepages("hugepages-2048kB")?,
+            free_1g: read_free_hugepages("hugepages-1048576kB")?,
+        })
+    }
+
+    /// The hugepages allocated by the setup script, `HUGE_PAGES_2M` and `HUGE_PAGES_1G`
+    pub fn from_config() -> Self {
+        Self { free_2m: HUGE_PAGES_2M, free_1g: HUGE_PAGES_1G }
+    }
+
+    /// Free hugepages from sysfs, the configured ones if sysfs cannot be read
+    pub fn detect() -> Self {
+        Self::from_sysfs().unwrap_or_else(|e| {
+            warn!("Reading free hugepages from {} failed ({}), using the configured ones", SYSFS_HUGEPAGES, e);
+            Self::from_config()
+        })
+    }
+
+    /// Counts `held` hugepages of the caller as free, e.g. buffers it would release for a new plan
+    pub fn with_held(self, held: Hugepages) -> Self {
+        Self { free_2m: self.free_2m + held.free_2m, free_1g: self.free_1g + held.free_1g }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.free_2m * HUGE_PAGE_SIZE_2M + self.free_1g * HUGE_PAGE_SIZE_1G
+    }
+}
+
+fn read_free_hugepages(size: &str) -> Result<usize, Box<dyn Error>> {
+    Ok(fs::read_to_string(format!("{}/{}/free_hugepages", SYSFS_HUGEPAGES, size))?.trim().parse()?)
+}
+
+/// Run size, merge fan-in and buffers of an external sort within a memory budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortPlan {
+    /// Elements per sorted run, the run buffer is the output buffer of the merge afterwards
+    pub run_len: usize,
+    pub num_runs: usize,
+    /// Runs merged at once
+    pub fan_in: usize,
+    /// Bytes of the read buffer of each merged run
+    pub buffer_size: usize,
+    /// Merge passes over the device
+    pub passes: usize,
+    /// 2MiB buffers of the external classification and block cache of the rolling sort
+    pub ext_buffers: usize,
+}
+
+impl SortPlan {
+    /// Plan of a sort-merge of `len` elements within `memory` bytes of the free `pages`.
+    /// Of the buffer sizes the one needing fewer passes is chosen, larger buffers break ties.
+    pub fn sort_merge(len: usize, memory: usize, pages: &Hugepages) -> Result<Self, Box<dyn Error>> {
+        Self::sort_merge_with(len, memory, pages, RunGeneration::Hugepage)
+    }
+
+    /// Plan of a sort-merge with the run buffer of `runs`. The number of runs of a replacement selection
+    /// is only known afterwards, it is planned with its upper bound of one run per heap.
+    pub fn sort_merge_with(len: usize, memory: usize, pages: &Hugepages, runs: RunGeneration) -> Result<Self, Box<dyn Error>> {
+        let run_pages = runs.run_pages();
+        let (run_bytes, free_1g, free_2m) = if pages.free_1g >= run_pages && memory >= run_pages * HUGE_PAGE_SIZE_1G {
+            (run_pages * HUGE_PAGE_SIZE_1G, pages.free_1g - run_pages, pages.free_2m)
+        } else if matches!(runs, RunGeneration::Hugepages(_) | RunGeneration::ReplacementSelection) {
+            return Err(format!("{} runs need {} free 1GiB hugepages within the memory budget of {} bytes, {} are free", runs, run_pages, memory, pages.free_1g).into());
+        } else if pages.free_2m > 0 && memory >= HUGE_PAGE_SIZE_2M {
+            (HUGE_PAGE_SIZE_2M, pages.free_1g, pages.free_2m - 1)
+        } else {
+            return Err(format!("Memory budget of {} bytes is too small for a run buffer", memory).into());
+        };
+        let run_len = run_bytes / 8;
+        let num_runs = max(1, len.div_ceil(run_len));
+        let mut plan = Self { run_len, num_runs, fan_in: 1, buffer_size: 0, passes: 0, ext_buffers: 0 };
+        if num_runs == 1 {
+            return Ok(plan);
+        }
+
+        let memory = memory - run_bytes;
+        let candidates = [
+            (min(free_1g, memory / HUGE_PAGE_SIZE_1G), HUGE_PAGE_SIZE_1G),
+            (min(free_2m, memory / HUGE_PAGE_SIZE_2M), HUGE_PAGE_SIZE_2M),
+        ];
+        let (fan_in, buffer_size) = candidates.into_iter()
+            .filter(|&(fan_in, _)| fan_in >= 2)
+            .map(|(fan_in, buffer_size)| (min(fan_in, num_runs), buffer_size))
+            .min_by_key(|&(fan_in, buffer_size)| (merge_passes(num_runs, fan_in), usize::MAX - buffer_size))
+            .ok_or_else(|| format!("Memory budget of {} bytes is too small to merge {} runs", memory + run_bytes, num_runs))?;
+        plan.fan_in = fan_in;
+        plan.buffer_size = buffer_size;
+        plan.passes = merge_passes(num_runs, fan_in);
+        Ok(plan)
+    }
+
+    /// Plan of a parallel sort-merge on `num_threads` threads. Each thread sorts runs of one 1GiB hugepage
+    /// and merges through one 2MiB read buffer per run, so all runs are merged in one pass if memory allows.
+    pub fn parallel_sort_merge(len: usize, memory: usize, pages: &Hugepages, num_threads: usize) -> Result<Self, Box<dyn Error>> {
+        Self::parallel_sort_merge_with(len, memory, pages, num_threads, RunGeneration::Hugepage)
+    }
+
+    /// Plan of a parallel sort-merge with the runs of `runs`, runs of several hugepages are sorted one after
+    /// another with all threads in a separate run buffer
+    pub fn parallel_sort_merge_with(len: usize, memory: usize, pages: &Hugepages, num_threads: usize, runs: RunGeneration) -> Result<Self, Box<dyn Error>> {
+        if matches!(runs, RunGeneration::ReplacementSelection | RunGeneration::Adaptive) {
+            return Err(format!("{} runs are only supported by the sequential sort-merge", runs).into());
+        }
+        let run_pages = runs.run_pages();
+        let run_buffer = if run_pages > 1 { run_pages } else { 0 };
+        if pages.free_1g < num_threads + run_buffer || memory < (num_threads + run_buffer) * HUGE_PAGE_SIZE_1G {
+            return Err(format!("{} threads and {} runs need {} free 1GiB hugepages within the memory budget of {} bytes, {} are free", num_threads, runs, num_threads + run_buffer, memory, pages.free_1g).into());
+        }
+        let run_len = run_pages * HUGE_PAGE_SIZE_1G / 8;
+        let num_runs = max(1, len.div_ceil(run_len));
+        let mut plan = Self { run_len, num_runs, fan_in: 1, buffer_size: HUGE_PAGE_SIZE_2M, passes: 0, ext_buffers: 0 };
+        if num_runs == 1 {
+            return Ok(plan);
+        }
+        let per_thread = min(pages.free_2m, (memory - (num_threads + run_buffer) * HUGE_PAGE_SIZE_1G) / HUGE_PAGE_SIZE_2M) / num_threads;
+        if per_thread < 2 {
+            return Err(format!("Memory budget of {} bytes is too small to merge {} runs on {} threads", memory, num_runs, num_threads).into());
+        }
+        plan.fan_in = min(per_thread, num_runs);
+        plan.passes = merge_passes(num_runs, plan.fan_in);
+        Ok(plan)
+    }
+
+    /// Plan of a rolling sort, which needs a 1GiB sort buffer and at least two 2MiB buffers
+    pub fn rolling(len: usize, memory: usize, pages: &Hugepages) -> Result<Self, Box<dyn Error>> {
+        if pages.free_1g == 0 || memory < HUGE_PAGE_SIZE_1G + 2 * HUGE_PAGE_SIZE_2M {
+            return Err(format!("Memory budget of {} bytes is too small for the rolling sort", memory).into());
+        }
+        let ext_buffers = min(pages.free_2m, min((memory - HUGE_PAGE_SIZE_1G) / HUGE_PAGE_SIZE_2M, max(2, BLOCK_CACHE_SIZE / HUGE_PAGE_SIZE_2M)));
+        if ext_buffers < 2 {
+            return Err(format!("{} free 2MiB hugepages are too few for the rolling sort", pages.free_2m).into());
+        }
+        let run_len = HUGE_PAGE_SIZE_1G / 8;
+        Ok(Self { run_len, num_runs: max(1, len.div_ceil(run_len)), fan_in: 1, buffer_size: 0, passes: 0, ext_buffers })
+    }
+}
+
+// memory budget of `options`, all free and `held` hugepages if none is set
+pub(crate) fn memory_budget(options: &SortOptions, held: Hugepages) -> (usize, Hugepages) {
+    let pages = Hugepages::detect().with_held(held);
+    let memory = options.memory.unwrap_or_else(|| pages.bytes());
+    info!("Memory budget: {} bytes, free hugepages: {:?}", memory, pages);
+    (memory, pages)
+}
+
+pub(crate) fn merge_passes(mut num_runs: usize, fan_in: usize) -> usize {
+    let mut passes = 0;
+    while num_runs > 1 {
+        num_runs = num_runs.div_ceil(fan_in);
+        passes += 1;
+    }
+    passes
+}
@@ -0,0 +1,161 @@
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crossbeam_channel::Sender;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Sorting the hugepages (sort-merge) or the buckets that fit into a hugepage (rolling sort)
+    RunGeneration,
+    /// External classification and permutation of the rolling sort
+    Partitioning,
+    Merging,
+    Verifying,
+    Done,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::RunGeneration => write!(f, "run generation"),
+            Phase::Partitioning => write!(f, "partitioning"),
+            Phase::Merging => write!(f, "merging"),
+            Phase::Verifying => write!(f, "verifying"),
+            Phase::Done => write!(f, "done"),
+        }
+    }
+}
+
+/// Snapshot of a running sort
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub runs_sorted: usize,
+    /// Number of runs, 0 if not known in advance (rolling sort)
+    pub runs: usize,
+    /// Current merge level, counting from 0
+    pub merge_level: usize,
+    pub merge_levels: usize,
+    pub bytes_written: u64,
+    /// Bytes the whole sort is expected to write
+    pub total_bytes: u64,
+    pub elapsed: Duration,
+    /// Extrapolated from the bytes written so far
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Fraction of `total_bytes` written, in [0, 1]
+    pub fn fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        (self.bytes_written as f64 / self.total_bytes as f64).min(1.0)
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>5.1}% {}", self.fraction() * 100.0, self.phase)?;
+        match self.phase {
+            Phase::RunGeneration | Phase::Partitioning if self.runs > 0 => write!(f, " {}/{} runs", self.runs_sorted, self.runs)?,
+            Phase::RunGeneration | Phase::Partitioning => write!(f, " {} runs", self.runs_sorted)?,
+            Phase::Merging => write!(f, " level {}/{}", self.merge_level + 1, self.merge_levels)?,
+            _ => {}
+        }
+        write!(f, ", {:.2} GiB written, {:.0?} elapsed", self.bytes_written as f64 / (1u64 << 30) as f64, self.elapsed)?;
+        if let Some(eta) = self.eta {
+            write!(f, ", ETA {:.0?}", eta)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the progress of a sort. Called from the sorting threads, so implementations should return quickly.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> ProgressSink for F {
+    fn report(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Forwards the progress to a channel, reports are dropped once the receiver is gone
+impl ProgressSink for Sender<Progress> {
+    fn report(&self, progress: &Progress) {
+        let _ = self.send(*progress);
+    }
+}
+
+/// Updates the progress from any thread of a sort and forwards it to the sink
+pub(crate) struct ProgressTracker {
+    sink: Option<Arc<dyn ProgressSink>>,
+    start: Instant,
+    progress: Mutex<Progress>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(sink: Option<Arc<dyn ProgressSink>>, runs: usize, merge_levels: usize, total_bytes: u64) -> Self {
+        ProgressTracker {
+            sink,
+            start: Instant::now(),
+            progress: Mutex::new(Progress {
+                phase: Phase::RunGeneration,
+                runs_sorted: 0,
+                runs,
+                merge_level: 0,
+                merge_levels,
+                bytes_written: 0,
+                total_bytes,
+                elapsed: Duration::ZERO,
+                eta: None,
+            }),
+        }
+    }
+
+    /// Tracker without a sink, for internal callers that do not report progress
+    pub(crate) fn disabled() -> Self {
+        Self::new(None, 0, 0, 0)
+    }
+
+    pub(crate) fn set_phase(&self, phase: Phase) {
+        self.update(|p| p.phase = phase);
+    }
+
+    pub(crate) fn start_merge_level(&self, level: usize) {
+        self.update(|p| {
+            p.phase = Phase::Merging;
+            p.merge_level = level;
+        });
+    }
+
+    pub(crate) fn run_sorted(&self, bytes: u64) {
+        self.update(|p| {
+            p.runs_sorted += 1;
+            p.bytes_written += bytes;
+        });
+    }
+
+    pub(crate) fn written(&self, bytes: u64) {
+        self.update(|p| p.bytes_written += bytes);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let mut progress = self.progress.lock().unwrap();
+        f(&mut progress);
+        progress.elapsed = self.start.elapsed();
+        let fraction = progress.fraction();
+        progress.eta = match progress.phase {
+            Phase::Done => Some(Duration::ZERO),
+            _ if fraction > 0.0 => Some(progress.elapsed.mul_f64((1.0 - fraction) / fraction)),
+            _ => None,
+        };
+        // report under the lock, so the sink sees the snapshots in order
+        sink.report(&progress);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::config::*;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::time::Duration;
+
+// Benchmark output is configured with environment variables, so the positional arguments of the benches stay as they are:
+// BENCH_FORMAT     text | csv | json (default: text)
+// BENCH_OUTPUT     file the results are written to (default: stdout)
+// BENCH_BASELINE   csv file of an earlier run, results with a higher mean duration are reported as regressions
+// BENCH_TOLERANCE  allowed relative slowdown against the baseline (default: 0.05)
+const DEFAULT_TOLERANCE: f64 = 0.05;
+
+const CSV_HEADER: &str = "benchmark,algorithm,distribution,size,threads,k,blocksize,threshold,iterations,mean_s,min_s,max_s,elements_per_s,gb_per_s,durations_s";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+/// Measurements of one benchmark configuration
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub benchmark: String,
+    pub algorithm: String,
+    pub distribution: String,
+    pub size: usize, // number of u64 elements
+    pub threads: usize,
+    pub durations: Vec<Duration>,
+}
+
+impl BenchResult {
+    pub fn new(benchmark: &str, algorithm: &str, distribution: &str, size: usize, threads: usize) -> Self {
+        BenchResult {
+            benchmark: benchmark.to_string(),
+            algorithm: algorithm.to_string(),
+            distribution: distribution.to_string(),
+            size,
+            threads,
+            durations: Vec::new(),
+        }
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.durations.is_empty() {
+            return Duration::ZERO;
+        }
+        self.durations.iter().sum::<Duration>() / self.durations.len() as u32
+    }
+
+    pub fn min(&self) -> Duration {
+        self.durations.iter().min().copied().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.durations.iter().max().copied().unwrap_or_default()
+    }
+
+    pub fn elements_per_second(&self) -> f64 {
+        self.size as f64 / self.mean().as_secs_f64()
+    }
+
+    pub fn gigabytes_per_second(&self) -> f64 {
+        self.elements_per_second() * 8.0 / 1e9
+    }
+
+    // identifies the same configuration across runs
+    fn key(&self) -> String {
+        format!("{}/{}/{}/{}/{}", self.benchmark, self.algorithm, self.distribution, self.size, self.threads)
+    }
+
+    fn to_csv(&self) -> String {
+        let durations: Vec<String> = self.durations.iter().map(|d| format!("{:.9}", d.as_secs_f64())).collect();
+        format!(
+            "{},{},{},{},{},{},{},{},{},{:.9},{:.9},{:.9},{:.1},{:.4},{}",
+            self.benchmark, self.algorithm, self.distribution, self.size, self.threads, K, BLOCKSIZE, THRESHOLD,
+            self.durations.len(), self.mean().as_secs_f64(), self.min().as_secs_f64(), self.max().as_secs_f64(),
+            self.elements_per_second(), self.gigabytes_per_second(), durations.join(";")
+        )
+    }
+
+    fn to_json(&self) -> String {
+        let durations: Vec<String> = self.durations.iter().map(|d| format!("{:.9}", d.as_secs_f64())).collect();
+        format!(
+            "{{\"benchmark\":\"{}\",\"algorithm\":\"{}\",\"distribution\":\"{}\",\"size\":{},\"threads\":{},\
+            \"config\":{{\"k\":{},\"blocksize\":{},\"threshold\":{},\"num_threads\":{}}},\
+            \"iterations\":{},\"mean_s\":{:.9},\"min_s\":{:.9},\"max_s\":{:.9},\"elements_per_s\":{:.1},\"gb_per_s\":{:.4},\"durations_s\":[{}]}}",
+            json_escape(&self.benchmark), json_escape(&self.algorithm), json_escape(&self.distribution), self.size, self.threads, K, BLOCKSIZE, THRESHOLD, NUM_THREADS,
+            self.durations.len(), self.mean().as_secs_f64(), self.min().as_secs_f64(), self.max().as_secs_f64(),
+            self.elements_per_second(), self.gigabytes_per_second(), durations.join(",")
+        )
+    }
+
+    fn to_text(&self) -> String {
+        format!(
+            "{} {} {} size={} threads={}: Avg {:?}, Min {:?}, Max {:?}, {:.2} M elements/s, {:.2} GB/s",
+            self.benchmark, self.algorithm, self.distribution, self.size, self.threads,
+            self.mean(), self.min(), self.max(), self.elements_per_second() / 1e6, self.gigabytes_per_second()
+        )
+    }
+}
+
+/// Result that got slower than its baseline by more than the tolerance
+#[derive(Debug, Clone)]
+pub struct Regression {
+    pub key: String,
+    pub baseline: Duration,
+    pub current: Duration,
+}
+
+impl Regression {
+    pub fn slowdown(&self) -> f64 {
+        self.current.as_secs_f64() / self.baseline.as_secs_f64() - 1.0
+    }
+}
+
+/// Collects the results of a benchmark run and writes them in the format given by BENCH_FORMAT
+pub struct BenchReport {
+    pub results: Vec<BenchResult>,
+    pub format: ReportFormat,
+}
+
+impl BenchReport {
+    pub fn new(format: ReportFormat) -> Self {
+        BenchReport { results: Vec::new(), format }
+    }
+
+    /// Reads the format from BENCH_FORMAT
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let format = match env::var("BENCH_FORMAT").as_deref() {
+            Err(_) | Ok("text") => ReportFormat::Text,
+            Ok("csv") => ReportFormat::Csv,
+            Ok("json") => ReportFormat::Json,
+            Ok(other) => return Err(format!("Unknown BENCH_FORMAT: {}", other).into()),
+        };
+        Ok(BenchReport::new(format))
+    }
+
+    pub fn push(&mut self, result: BenchResult) {
+        self.results.push(result);
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self.format {
+            ReportFormat::Text => {
+                for result in &self.results {
+                    writeln!(writer, "{}", result.to_text())?;
+                }
+            }
+            ReportFormat::Csv => {
+                writeln!(writer, "{}", CSV_HEADER)?;
+                for result in &self.results {
+                    writeln!(writer, "{}", result.to_csv())?;
+                }
+            }
+            ReportFormat::Json => {
+                let results: Vec<String> = self.results.iter().map(|r| format!("  {}", r.to_json())).collect();
+                writeln!(writer, "[\n{}\n]", results.join(",\n"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the mean durations against a baseline written with the csv format.
+    /// Configurations missing in the baseline are skipped.
+    pub fn compare(&self, baseline_csv: &str, tolerance: f64) -> Result<Vec<Regression>, Box<dyn Error>> {
+        let baseline = parse_baseline(baseline_csv)?;
+        let mut regressions = Vec::new();
+        for result in &self.results {
+            if let Some(&mean) = baseline.get(&result.key()) {
+                let current = result.mean();
+                if current.as_secs_f64() > mean.as_secs_f64() * (1.0 + tolerance) {
+                    regressions.push(Regression { key: result.key(), baseline: mean, current });
+                }
+            }
+        }
+        Ok(regressions)
+    }
+
+    /// Writes the results to BENCH_OUTPUT or stdout and compares them against BENCH_BASELINE if set.
+    /// Returns an error if any result regressed.
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        match env::var("BENCH_OUTPUT") {
+            Ok(path) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                self.write(&mut writer)?;
+                writer.flush()?;
+            }
+            Err(_) => self.write(&mut io::stdout().lock())?,
+        }
+
+        let Ok(path) = env::var("BENCH_BASELINE") else {
+            return Ok(());
+        };
+        let tolerance = match env::var("BENCH_TOLERANCE") {
+            Ok(value) => value.parse()?,
+            Err(_) => DEFAULT_TOLERANCE,
+        };
+        let regressions = self.compare(&fs::read_to_string(&path)?, tolerance)?;
+        for regression in &regressions {
+            eprintln!(
+                "Regression {}: {:?} -> {:?} (+{:.1}%)",
+                regression.key, regression.baseline, regression.current, regression.slowdown() * 100.0
+            );
+        }
+        if !regressions.is_empty() {
+            return Err(format!("{} results regressed against {}", regressions.len(), path).into());
+        }
+        Ok(())
+    }
+}
+
+// escapes `s` for a JSON string literal
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// maps the key of every row to its mean duration
+fn parse_baseline(csv: &str) -> Result<HashMap<String, Duration>, Box<dyn Error>> {
+    let mut lines = csv.lines();
+    let header: Vec<&str> = lines.next().ok_or("Baseline is empty")?.split(',').collect();
+    let column = |name: &str| header.iter().position(|&h| h == name).ok_or(format!("Baseline has no column {}", name));
+    let columns = [column("benchmark")?, column("algorithm")?, column("distribution")?, column("size")?, column("threads")?];
+    let mean_column = column("mean_s")?;
+
+    let mut baseline = HashMap::new();
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != header.len() {
+            return Err(format!("Malformed baseline row: {}", line).into());
+        }
+        let key: Vec<&str> = columns.iter().map(|&c| fields[c]).collect();
+        let mean = Duration::from_secs_f64(fields[mean_column].parse()?);
+        baseline.insert(key.join("/"), mean);
+    }
+    Ok(baseline)
+}
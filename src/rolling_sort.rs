@@ -1,51 +1,47 @@
 use log::debug;
 use crate::config::*;
 use crate::conversion::*;
-use crate::sort::{read_write_hugepage_1G};
+use crate::sort::read_write_elements;
+use crate::base_case::insertion_sort;
 use crate::sorter::{ExtTask, IPS2RaSorter, Task};
+use crate::block_cache::BlockCache;
+use crate::progress::ProgressTracker;
+use std::error::Error;
+use std::time::Instant;
+use tracing::debug_span;
 
 
 impl IPS2RaSorter{
-    pub fn sequential_rolling_sort(&mut self, task: &mut ExtTask) {
-        if task.level == 0{
-            debug!("Sampling Task");
-            self.sample(task);
+    pub(crate) fn sequential_rolling_sort(&mut self, task: &mut ExtTask, progress: &ProgressTracker) -> Result<(), Box<dyn Error>> {
+        let _span = debug_span!("ext_task", level = task.level, start_lba = task.start_lba, len = task.size).entered();
+        if self.is_cancelled() {
+            return Ok(());
         }
-        println!("Sequential rolling sort: Start-LBA: {}, Offset: {}, Size: {}, Level: {} ", task.start_lba, task.offset, task.size, task.level);
+        debug!("Sequential rolling sort: Start-LBA: {}, Offset: {}, Size: {}, Level: {} ", task.start_lba, task.offset, task.size, task.level);
 
-        if task.size <= HUGE_PAGE_SIZE_1G/8 {
-            {
-                debug!("Task-Size < Hugepage-Size/8 => Sequential sort");
-                let mut qpair = self.qpair.take().unwrap();
-                let mut buffer = self.sort_buffer.take().unwrap();
-                read_write_hugepage_1G(&mut qpair, task.start_lba, &mut buffer, false);
-
-                let u64slice = u8_to_u64_slice(&mut buffer[0..task.size * 8]);
-                debug!("Read: {:?}", u64slice);
-
-                let mut new_task = Task::new(u64slice, task.level, task.level_end);
-
-                self.sequential_rec(&mut new_task);
-
-                debug!("After sort: {:?}", new_task.arr);
-                // write back to ssd
-                read_write_hugepage_1G(&mut qpair, task.start_lba, &mut buffer, true);
-                self.qpair = Some(qpair);
-                self.sort_buffer = Some(buffer);
-                return;
-            }
+        if task.offset + task.size <= HUGE_PAGE_SIZE_1G/8 {
+            debug!("Task fits into the sort buffer => Sequential sort");
+            self.sort_buckets_in_memory(task, task.offset, &[task.size], task.level, progress);
+            return Ok(());
         }
 
 
-        println!("Classification");
+        debug!("Classification");
+        let start = Instant::now();
         self.classify_ext(task);
+        self.metrics.classification += start.elapsed();
         debug!("Classified elements: {}", self.classified_elements);
 
-        println!("Permutation");
-        self.permutate_blocks_ext(task);
-
-        println!("Cleanup");
-        self.cleanup_ext(task);
+        // the classification buffers are free again, permutation and cleanup share them as block cache
+        let mut qpair = self.qpair.take().unwrap();
+        let mut buffers = self.buffers.take().unwrap();
+        let mut cache = BlockCache::new(&mut qpair, &mut buffers);
+        let partitioned = self.partition_ext(task, &mut cache);
+        drop(cache);
+        // handed back on failure as well, so the caller can release them
+        self.qpair = Some(qpair);
+        self.buffers = Some(buffers);
+        partitioned?;
 
         //read_write_hugepage(self.qpair.as_mut().unwrap(), task.start_lba, self.sort_buffer.as_mut().unwrap(), false);
         //let u64slice= u8_to_u64_slice(&mut self.sort_buffer.as_mut().unwrap()[0..task.size*8]);
@@ -53,25 +49,112 @@ impl IPS2RaSorter{
 
         if task.level + 1 == task.level_end {
             debug!("Last level -> sorted");
-            return;
+            return Ok(());
         }
 
         let element_counts_copy = self.element_counts.clone();
-        // Recursion
+        // Recursion: consecutive buckets that fit into the sort buffer together are sorted with a single read,
+        // larger buckets get another external level
         let mut sum = 0;
+        let mut batch_start = task.offset;
+        let mut batch: Vec<usize> = Vec::new();
         for i in 0..K {
             let new_size = element_counts_copy[i] as usize;
-            if new_size <= 1 {
+            let bucket_start = task.offset + sum;
+            sum += new_size;
+            if bucket_start % (LBA_SIZE/8) + new_size <= HUGE_PAGE_SIZE_1G/8 {
+                if batch_start % (LBA_SIZE/8) + (bucket_start + new_size - batch_start) > HUGE_PAGE_SIZE_1G/8 {
+                    self.sort_buckets_in_memory(task, batch_start, &batch, task.level+1, progress);
+                    batch.clear();
+                }
+                if batch.is_empty() {
+                    batch_start = bucket_start;
+                }
+                batch.push(new_size);
                 continue;
             }
-            let new_start_lba = task.start_lba + (task.offset + sum)*8/LBA_SIZE;
-            let new_offset = (task.offset + sum)%(LBA_SIZE/8);
+            if !batch.is_empty() {
+                self.sort_buckets_in_memory(task, batch_start, &batch, task.level+1, progress);
+                batch.clear();
+            }
+            let new_start_lba = task.start_lba + bucket_start*8/LBA_SIZE;
+            let new_offset = bucket_start%(LBA_SIZE/8);
             let mut new_task = ExtTask::new(new_start_lba, new_offset, new_size, task.level+1, task.level_end);
-            println!("Added new task. Start LBA: {}, Offset: {}, Size: {}, Level: {}", new_start_lba, new_offset, new_size, task.level+1);
+            new_task.key_offset = task.key_offset;
+            debug!("Added new task. Start LBA: {}, Offset: {}, Size: {}, Level: {}", new_start_lba, new_offset, new_size, task.level+1);
+            let _bucket = debug_span!("bucket", bucket = i).entered();
             self.clear();
-            self.sequential_rolling_sort(&mut new_task);
-            sum += element_counts_copy[i] as usize;
+            self.sequential_rolling_sort(&mut new_task, progress)?;
+        }
+        if !batch.is_empty() {
+            self.sort_buckets_in_memory(task, batch_start, &batch, task.level+1, progress);
+        }
+        Ok(())
+    }
+
+    // permutation and cleanup of a classified task through the block cache, waits for its writes
+    fn partition_ext(&mut self, task: &mut ExtTask, cache: &mut BlockCache) -> Result<(), Box<dyn Error>> {
+        debug!("Permutation");
+        let start = Instant::now();
+        self.permutate_blocks_ext(task, cache)?;
+        self.metrics.permutation += start.elapsed();
+
+        debug!("Cleanup");
+        let start = Instant::now();
+        self.cleanup_ext(task, cache)?;
+        // writes back the dirty lines before the buckets are read directly
+        cache.flush()?;
+        self.metrics.cleanup += start.elapsed();
+        Ok(())
+    }
+
+    /// Sorts consecutive buckets of `sizes` elements of `task`, starting `offset` elements after its start lba,
+    /// with one read and one write of exactly the lbas they cover
+    fn sort_buckets_in_memory(&mut self, task: &ExtTask, offset: usize, sizes: &[usize], level: usize, progress: &ProgressTracker) {
+        if self.is_cancelled() {
+            return;
+        }
+        let len: usize = sizes.iter().sum();
+        if len == 0 {
+            return;
         }
+        let lba = task.start_lba + offset / (LBA_SIZE/8);
+        let lead = offset % (LBA_SIZE/8);
+        let _span = debug_span!("in_memory", lba = lba, buckets = sizes.len(), len = len).entered();
+        debug!("Sorting {} buckets with {} elements in memory, lba: {}, offset: {}", sizes.len(), len, lba, lead);
+        let mut qpair = self.qpair.take().unwrap();
+        let mut buffer = self.sort_buffer.take().unwrap();
+        let start = Instant::now();
+        read_write_elements(&mut qpair, &mut buffer, lba, lead, len, false);
+        self.metrics.run_io += start.elapsed();
+
+        // the in-memory levels take their digits from the keys without the offset as well
+        if task.key_offset != 0 {
+            u8_to_u64_slice(&mut buffer[lead * 8..(lead + len) * 8]).iter_mut().for_each(|x| *x -= task.key_offset);
+        }
+        let mut begin = lead;
+        for &size in sizes {
+            let bucket = u8_to_u64_slice(&mut buffer[begin * 8..(begin + size) * 8]);
+            if size > THRESHOLD {
+                let mut new_task = Task::new(bucket, level, task.level_end);
+                self.clear();
+                self.sequential_rec(&mut new_task);
+            } else if size > 1 {
+                insertion_sort(bucket);
+            }
+            begin += size;
+        }
+        if task.key_offset != 0 {
+            u8_to_u64_slice(&mut buffer[lead * 8..(lead + len) * 8]).iter_mut().for_each(|x| *x += task.key_offset);
+        }
+
+        // the partially covered lbas at both ends were read with the buckets, so they are written back unchanged
+        let start = Instant::now();
+        read_write_elements(&mut qpair, &mut buffer, lba, lead, len, true);
+        self.metrics.run_io += start.elapsed();
+        progress.run_sorted((len * 8) as u64);
+        self.qpair = Some(qpair);
+        self.sort_buffer = Some(buffer);
     }
 
     pub fn parallel_rolling_sort() {
@@ -0,0 +1,303 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::context::Workers;
+use crate::parallel::parallel_rec;
+use crate::sort::{read_write_elements, SortOptions};
+use crate::sorter::{IPS2RaSorter, Task};
+use crate::verify::Checksum;
+use crate::progress::ProgressTracker;
+use crate::cancel::Cancelled;
+use vroom::memory::Dma;
+use vroom::NvmeQueuePair;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+use log::{debug, info};
+use tracing::instrument;
+
+/// How the sort-merge forms its sorted runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RunGeneration {
+    /// Runs of one hugepage sorted on one thread, 2MiB runs if the memory budget is below 1GiB
+    #[default]
+    Hugepage,
+    /// Runs spanning this many 1GiB hugepages, sorted with all threads of the context.
+    /// A run buffer of several hugepages needs the VFIO backend.
+    Hugepages(usize),
+    /// Replacement selection through a 1GiB heap, runs of about twice the heap on random input
+    /// and a single run on presorted input
+    ReplacementSelection,
+    /// Hugepages split into their ascending and descending stretches, see `adaptive_runs`. Ascending stretches are kept
+    /// without sorting them, descending ones are reversed and the elements between them are sorted.
+    /// Runs continuing the previous one are joined with it, so the merge gets fewer and longer runs.
+    /// Only the sequential sort-merge supports it, the parallel one rejects it in its plan.
+    Adaptive,
+}
+
+/// Order of the elements of a run before sorting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOrder {
+    Ascending,
+    Descending,
+    Unsorted,
+}
+
+/// Whether `arr` is already sorted in either direction, equal elements count as ascending
+pub fn run_order(arr: &[u64]) -> RunOrder {
+    if arr.windows(2).all(|w| w[0] <= w[1]) {
+        RunOrder::Ascending
+    } else if arr.windows(2).all(|w| w[0] >= w[1]) {
+        RunOrder::Descending
+    } else {
+        RunOrder::Unsorted
+    }
+}
+
+/// Splits `arr` into runs of the adaptive run generation, as (first element, length, order). Maximal ascending and
+/// descending stretches of at least `min_len` elements after cutting them to lba boundaries are runs of their own,
+/// the elements between them form runs as well. Every run starts on an lba boundary of `arr`.
+pub fn adaptive_runs(arr: &[u64], min_len: usize) -> Vec<(usize, usize, RunOrder)> {
+    let unit = LBA_SIZE / 8;
+    let mut runs = Vec::new();
+    let mut covered = 0;
+    let push_gap = |runs: &mut Vec<(usize, usize, RunOrder)>, from: usize, to: usize| {
+        if to > from {
+            runs.push((from, to - from, run_order(&arr[from..to])));
+        }
+    };
+    let mut i = 0;
+    while i < arr.len() {
+        // equal elements continue a stretch in either direction
+        let mut j = i + 1;
+        while j < arr.len() && arr[j] == arr[j - 1] {
+            j += 1;
+        }
+        let descending = j < arr.len() && arr[j] < arr[j - 1];
+        while j < arr.len() && if descending { arr[j] <= arr[j - 1] } else { arr[j] >= arr[j - 1] } {
+            j += 1;
+        }
+        let start = i.next_multiple_of(unit);
+        let end = if j == arr.len() { j } else { j / unit * unit };
+        if end > start && end - start >= min_len {
+            push_gap(&mut runs, covered, start);
+            runs.push((start, end - start, if descending { RunOrder::Descending } else { RunOrder::Ascending }));
+            covered = end;
+        }
+        i = j;
+    }
+    push_gap(&mut runs, covered, arr.len());
+    runs
+}
+
+impl RunGeneration {
+    /// 1GiB hugepages of the run buffer
+    pub fn run_pages(&self) -> usize {
+        match self {
+            RunGeneration::Hugepages(pages) => *pages,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for RunGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunGeneration::Hugepage => write!(f, "hugepage"),
+            RunGeneration::Hugepages(pages) => write!(f, "hugepages:{}", pages),
+            RunGeneration::ReplacementSelection => write!(f, "replacement-selection"),
+            RunGeneration::Adaptive => write!(f, "adaptive"),
+        }
+    }
+}
+
+impl FromStr for RunGeneration {
+    type Err = String;
+
+    /// Parses the names printed by `Display`, `hugepages` takes the number of hugepages per run (e.g. `hugepages:4`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "hugepage" => Ok(RunGeneration::Hugepage),
+            None if s == "replacement-selection" => Ok(RunGeneration::ReplacementSelection),
+            None if s == "adaptive" => Ok(RunGeneration::Adaptive),
+            Some(("hugepages", pages)) => match pages.parse() {
+                Ok(pages) if pages > 0 => Ok(RunGeneration::Hugepages(pages)),
+                _ => Err(format!("Invalid number of hugepages per run: {}", pages)),
+            },
+            _ => Err(format!("Unknown run generation: {}", s)),
+        }
+    }
+}
+
+/// Sorts `arr` with all sorters of the pool, like `SortContext::sort_parallel`
+pub(crate) fn sort_run_parallel(sorters: &Workers, arr: &mut [u64]) {
+    let mut task = Task::new(arr, 0, 8);
+    if task.sample() {
+        sorters.install(|| parallel_rec(sorters, &mut task));
+    }
+}
+
+/// Sorter and bookkeeping of a run generation
+pub(crate) struct RunContext<'a> {
+    pub sorter: &'a mut IPS2RaSorter,
+    pub checksum: &'a mut Checksum,
+    pub options: &'a SortOptions,
+    pub progress: &'a ProgressTracker,
+}
+
+// input and output of the replacement selection, both go through a buffer of `chunk_len` elements
+struct Selection<'a> {
+    qpair: &'a mut NvmeQueuePair,
+    input: &'a mut Dma<u8>,
+    output: &'a mut Dma<u8>,
+    chunk_len: usize,
+    len: usize,
+    // elements read from the device, the next one is at `idx` of the `filled` elements in the input buffer
+    read: usize,
+    idx: usize,
+    filled: usize,
+    // element on the device the output buffer starts at, always on an lba boundary
+    out_base: usize,
+    out_idx: usize,
+    run_start: usize,
+    runs: Vec<(usize, usize)>,
+}
+
+impl Selection<'_> {
+    fn exhausted(&self) -> bool {
+        self.idx == self.filled && self.read == self.len
+    }
+
+    fn next(&mut self, sorter: &mut IPS2RaSorter, checksum: &mut Checksum) -> u64 {
+        if self.idx == self.filled {
+            let n = (self.len - self.read).min(self.chunk_len);
+            let start = Instant::now();
+            read_write_elements(self.qpair, self.input, self.read / (LBA_SIZE / 8), 0, n, false);
+            sorter.metrics.run_io += start.elapsed();
+            if VERIFY_SORT_MERGE {
+                checksum.add_slice(u8_to_u64_slice(&mut self.input[0..n * 8]));
+            }
+            self.read += n;
+            self.idx = 0;
+            self.filled = n;
+        }
+        self.idx += 1;
+        u8_to_u64(&self.input[(self.idx - 1) * 8..self.idx * 8])
+    }
+
+    fn emit(&mut self, value: u64, sorter: &mut IPS2RaSorter) {
+        u8_to_u64_slice(&mut self.output[self.out_idx * 8..self.out_idx * 8 + 8])[0] = value;
+        self.out_idx += 1;
+        if self.out_idx == self.chunk_len {
+            self.flush(sorter);
+        }
+    }
+
+    // writes the output buffer. Until the input is exhausted the runs trail it by the heap less the lba padding
+    // of the runs, which stays far below the heap as every run but the last is at least as long as the heap.
+    fn flush(&mut self, sorter: &mut IPS2RaSorter) {
+        if self.out_idx == 0 {
+            return;
+        }
+        debug_assert!(self.read == self.len || self.out_base + self.out_idx <= self.read, "Run overtook the input");
+        let start = Instant::now();
+        read_write_elements(self.qpair, self.output, self.out_base / (LBA_SIZE / 8), 0, self.out_idx, true);
+        sorter.metrics.run_io += start.elapsed();
+        self.out_base += self.out_idx.div_ceil(LBA_SIZE / 8) * (LBA_SIZE / 8);
+        self.out_idx = 0;
+    }
+
+    fn end_run(&mut self, sorter: &mut IPS2RaSorter, progress: &ProgressTracker) {
+        let run_len = self.out_base + self.out_idx - self.run_start;
+        self.flush(sorter);
+        if run_len > 0 {
+            debug!("Run {} of {} elements at element {}", self.runs.len(), run_len, self.run_start);
+            self.runs.push((self.run_start, run_len));
+            progress.run_sorted((run_len * 8) as u64);
+        }
+        self.run_start = self.out_base;
+    }
+
+    fn emit_sorted(&mut self, arr: &mut [u64], sorter: &mut IPS2RaSorter) {
+        sort_sequential(arr, sorter);
+        for &value in arr.iter() {
+            self.emit(value, sorter);
+        }
+    }
+}
+
+/// Forms runs of the first `len` elements of the device by replacement selection with `heap` as the selection heap.
+/// Elements not smaller than the last output join the current run, the others wait for the next one at the end of the heap.
+/// The runs are written back in place, each one starting on an lba boundary, and returned as (first element, length).
+#[instrument(level = "debug", skip_all, fields(len = len))]
+pub(crate) fn replacement_selection(qpair: &mut NvmeQueuePair, heap: &mut [u64], input: &mut Dma<u8>, output: &mut Dma<u8>, chunk_len: usize, len: usize, ctx: RunContext) -> Result<Vec<(usize, usize)>, Cancelled> {
+    let RunContext { sorter, checksum, options, progress } = ctx;
+    let mut selection = Selection { qpair, input, output, chunk_len, len, read: 0, idx: 0, filled: 0, out_base: 0, out_idx: 0, run_start: 0, runs: Vec::new() };
+
+    let total = heap.len().min(len);
+    for slot in heap[..total].iter_mut() {
+        *slot = selection.next(sorter, checksum);
+    }
+    heapify(&mut heap[..total]);
+    // the heap of the current run is heap[..size], the elements of the next run are heap[size..total]
+    let mut size = total;
+
+    while !selection.exhausted() {
+        let min = heap[0];
+        selection.emit(min, sorter);
+        let value = selection.next(sorter, checksum);
+        if value >= min {
+            heap[0] = value;
+        } else {
+            heap[0] = heap[size - 1];
+            heap[size - 1] = value;
+            size -= 1;
+        }
+        sift_down(&mut heap[..size], 0);
+
+        if size == 0 {
+            selection.end_run(sorter, progress);
+            options.check_cancelled()?;
+            size = total;
+            heapify(&mut heap[..size]);
+        }
+    }
+
+    // input exhausted, the rest of the current run and the next run are sorted in memory
+    selection.emit_sorted(&mut heap[..size], sorter);
+    selection.end_run(sorter, progress);
+    selection.emit_sorted(&mut heap[size..total], sorter);
+    selection.end_run(sorter, progress);
+    info!("Replacement selection formed {} runs of {} elements", selection.runs.len(), len);
+    Ok(selection.runs)
+}
+
+fn sort_sequential(arr: &mut [u64], sorter: &mut IPS2RaSorter) {
+    let mut task = Task::new(arr, 0, 8);
+    if task.sample() {
+        sorter.sequential_rec(&mut task);
+    }
+    sorter.clear();
+}
+
+/// Turns `heap` into a min-heap
+pub fn heapify(heap: &mut [u64]) {
+    for i in (0..heap.len() / 2).rev() {
+        sift_down(heap, i);
+    }
+}
+
+/// Restores the min-heap below `i` after `heap[i]` was replaced
+pub fn sift_down(heap: &mut [u64], mut i: usize) {
+    loop {
+        let left = 2 * i + 1;
+        if left >= heap.len() {
+            return;
+        }
+        let child = if left + 1 < heap.len() && heap[left + 1] < heap[left] { left + 1 } else { left };
+        if heap[i] <= heap[child] {
+            return;
+        }
+        heap.swap(i, child);
+        i = child;
+    }
+}
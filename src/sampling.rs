@@ -1,10 +1,13 @@
 use crate::config::*;
 use crate::conversion::*;
-use crate::sort::{read_write_hugepage_1G};
+use crate::sort::{find_bucket_ips2ra, read_write_elements};
 use crate::sorter::{ExtTask, IPS2RaSorter, Task};
 use std::cmp::{max, min};
 use rand::prelude::StdRng;
 use rand::{Rng, SeedableRng};
+use log::debug;
+use vroom::NvmeQueuePair;
+use vroom::memory::Dma;
 
 
 impl<'a> Task<'_> {
@@ -101,39 +104,91 @@ impl<'a> Task<'_> {
 
 }
 
-pub fn sample_max(max: usize) -> usize{
-    let lz = max.leading_zeros();
-    let klog2 = (K as u64).ilog2();
-    let zero_blocks = (lz as f64 / klog2 as f64).floor() as u32;
-    zero_blocks as usize
+/// Statistics of an external task, gathered in one streaming pass over its elements
+#[derive(Debug, Clone)]
+pub struct ExtStats {
+    pub min: u64,
+    pub max: u64,
+    // bits in which at least one element differs from the first one
+    pub differing_bits: u64,
+    // every `len / EXT_SAMPLE_SIZE`-th element
+    pub samples: Vec<u64>,
+}
+
+impl ExtStats {
+    /// First and end level of the radix, like `Task::sequential_get_levels`, and the offset subtracted from the keys.
+    /// The minimum is subtracted when that needs fewer levels than the common prefix of the elements.
+    pub fn levels(&self) -> (usize, usize, u64) {
+        if self.differing_bits == 0 {
+            return (0, 0, 0);
+        }
+        let level_end = 8 - self.differing_bits.trailing_zeros() as usize / 8;
+        let level = self.differing_bits.leading_zeros() as usize / 8;
+        // the elements agree in their low bits, so the differences to the minimum have the same trailing zeros
+        let narrow_level = (self.max - self.min).leading_zeros() as usize / 8;
+        if narrow_level > level {
+            (narrow_level, level_end, self.min)
+        } else {
+            (level, level_end, 0)
+        }
+    }
+
+    /// Sampled counts of the buckets on `level` after subtracting `key_offset`
+    pub fn histogram(&self, level: usize, key_offset: u64) -> [usize; K] {
+        let mut histogram = [0; K];
+        for &sample in &self.samples {
+            histogram[find_bucket_ips2ra(sample - key_offset, level)] += 1;
+        }
+        histogram
+    }
 }
 
 impl IPS2RaSorter {
-    pub fn sample(&mut self, task: &mut ExtTask) {
-        let mut max = u64::MAX;
-        let mut remaining = task.size;
-        for i in 0..task.size / (HUGE_PAGE_SIZE_1G/8) {
-            read_write_hugepage_1G(self.qpair.as_mut().unwrap(), task.start_lba + i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, self.sort_buffer.as_mut().unwrap(), false);
-            let u64slice = u8_to_u64_slice(&mut self.sort_buffer.as_mut().unwrap()[0..HUGE_PAGE_SIZE_1G]);
-            let tmp_max = u64slice[{
-                if remaining >= HUGE_PAGES_1G / 8 {
-                    remaining -= HUGE_PAGES_1G / 8;
-                    0..HUGE_PAGES_1G
-                } else {
-                    let res = remaining;
-                    remaining = 0;
-                    0..res*8
-                }
-            }].iter().max().unwrap();
-            if *tmp_max < max {
-                max = *tmp_max;
-            }
-            remaining -= HUGE_PAGES_1G / 8;
+    /// Sets the levels and key offset of `task` from the statistics of its elements.
+    /// Returns false if all elements are equal and there is nothing to sort.
+    pub fn sample(&mut self, task: &mut ExtTask) -> bool {
+        let stats = self.ext_stats(task);
+        let (level, level_end, key_offset) = stats.levels();
+        debug!("Statistics: min: {}, max: {}, differing bits: {:#x}", stats.min, stats.max, stats.differing_bits);
+        if level == 0 && level_end == 0 {
+            return false;
         }
-        let lz = max.leading_zeros();
-        let klog2 = (K as u64).ilog2();
-        let zero_blocks = (lz as f64 / klog2 as f64).floor() as u32;
-        task.level = zero_blocks as usize;
+        let histogram = stats.histogram(level, key_offset);
+        debug!("Level: {}, level end: {}, key offset: {}, sampled buckets: {}", level, level_end, key_offset, histogram.iter().filter(|&&count| count > 0).count());
+        task.level = level;
+        task.level_end = level_end;
+        task.key_offset = key_offset;
+        true
+    }
+
+    /// Reads the elements of `task` once through the sort buffer
+    pub fn ext_stats(&mut self, task: &ExtTask) -> ExtStats {
+        ext_stats(self.qpair.as_mut().unwrap(), self.sort_buffer.as_mut().unwrap(), task)
     }
 }
 
+/// Statistics of the elements of `task`, read once through `buffer`
+pub(crate) fn ext_stats(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, task: &ExtTask) -> ExtStats {
+    let stride = max(1, task.size / EXT_SAMPLE_SIZE);
+    let mut stats = ExtStats { min: u64::MAX, max: 0, differing_bits: 0, samples: Vec::with_capacity(min(task.size, EXT_SAMPLE_SIZE + 1)) };
+    let mut reference = None;
+    let mut done = 0;
+    while done < task.size {
+        let position = task.position(done);
+        let lead = position % (LBA_SIZE / 8);
+        let n = min(task.size - done, HUGE_PAGE_SIZE_1G / 8 - lead);
+        read_write_elements(qpair, buffer, position / (LBA_SIZE / 8), lead, n, false);
+        let elements = u8_to_u64_slice(&mut buffer[lead * 8..(lead + n) * 8]);
+        let reference = *reference.get_or_insert(elements[0]);
+        for (i, &element) in elements.iter().enumerate() {
+            stats.min = min(stats.min, element);
+            stats.max = max(stats.max, element);
+            stats.differing_bits |= reference ^ element;
+            if (done + i) % stride == 0 {
+                stats.samples.push(element);
+            }
+        }
+        done += n;
+    }
+    stats
+}
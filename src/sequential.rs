@@ -1,13 +1,17 @@
 use crate::config::*;
 use crate::sorter::{IPS2RaSorter, Task};
+use std::time::Instant;
+use tracing::trace_span;
 
 impl IPS2RaSorter {
     pub fn sequential_rec(&mut self, task: &mut Task) {
+        let _span = trace_span!("task", level = task.level, len = task.arr.len()).entered();
+        if self.is_cancelled() {
+            return;
+        }
 
         // partition
-        self.classify(task);
-        self.permutate_blocks(task);
-        self.cleanup(task);
+        self.partition(task);
 
         if task.level + 1 == task.level_end {
             //println!("Last level -> sorted");
@@ -21,10 +25,24 @@ impl IPS2RaSorter {
             let end = bucket_start[i + 1];
             if (end - start) > THRESHOLD as u64 {
                 //println!("New task: start: {}, end: {}, level: {}", start, end, task.level + 1);
+                let _bucket = trace_span!("bucket", bucket = i).entered();
                 let mut new_task = Task::new(&mut task.arr[start as usize..end as usize], task.level + 1, task.level_end);
                 self.clear();
                 self.sequential_rec(&mut new_task);
             }
         }
     }
+
+    /// Classification, block permutation and cleanup of `task`, timed in `self.metrics`
+    pub fn partition(&mut self, task: &mut Task) {
+        let start = Instant::now();
+        self.classify(task);
+        let classified = Instant::now();
+        self.permutate_blocks(task);
+        let permutated = Instant::now();
+        self.cleanup(task);
+        self.metrics.classification += classified - start;
+        self.metrics.permutation += permutated - classified;
+        self.metrics.cleanup += permutated.elapsed();
+    }
 }
@@ -1,309 +1,314 @@
 use crate::config::*;
 use crate::conversion::*;
-use crate::sort::read_write_hugepage_1G;
+use crate::sort::{deallocate_lbas, read_write_elements, release_resources};
 use crate::sorter::{IPS2RaSorter, Task};
+use crate::base_case::insertion_sort;
+use crate::verify::{verify_sort_merge, Checksum};
+use crate::metrics::SortMetrics;
+use crate::progress::{Phase, ProgressTracker};
+use crate::cancel::Cancelled;
+use crate::sort::SortOptions;
+use crate::planner::{memory_budget, merge_passes, Hugepages, SortPlan};
+use crate::run_generation::{adaptive_runs, replacement_selection, sort_run_parallel, RunContext, RunGeneration, RunOrder};
+use crate::context::Workers;
+use crate::in_place_merge::merge_in_place;
 use vroom::memory::Dma;
 use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
 use std::error::Error;
-use std::io;
+use std::cmp::{min, Reverse};
 use std::collections::BinaryHeap;
-use std::time::Duration;
-use log::{debug, info};
-
-struct HeapEntry{
-    value: u64,
-    hugepage_idx: usize,
-    element_idx: usize,
-    remaining: usize,
-}
-
-impl Eq for HeapEntry {}
-
-impl PartialEq for HeapEntry {
-    fn eq(&self, other: &Self) -> bool {
-        self.value == other.value
-    }
-}
-
-impl Ord for HeapEntry {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.value.cmp(&self.value)
-    }
-}
-
-impl PartialOrd for HeapEntry {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-pub fn sequential_sort_merge(mut nvme: NvmeDevice, len: usize) -> Result<NvmeDevice, Box<dyn Error>> {
+use std::mem;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use tracing::{debug_span, instrument};
+
+pub(crate) fn sequential_sort_merge(nvme: &mut NvmeDevice, sorters: &Workers, len: usize, options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    let total_start = Instant::now();
+    let (memory, pages) = memory_budget(options, Hugepages::default());
+    let plan = SortPlan::sort_merge_with(len, memory, &pages, options.run_generation)?;
+    info!("Sort plan: {:?}, run generation: {}", plan, options.run_generation);
+    // every pass rewrites all elements, an odd number of passes ends with a copy to lba 0,
+    // in place the blocks are moved into order once after the last pass instead
+    let reorder = if options.in_place { min(plan.passes, 1) } else { plan.passes % 2 };
+    let progress = ProgressTracker::new(options.progress.clone(), plan.num_runs, plan.passes, (len * 8 * (1 + plan.passes + reorder)) as u64);
+    progress.set_phase(Phase::RunGeneration);
 
     let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
-    let mut sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G)?;
+    qpair.set_completion_mode(COMPLETION_MODE);
+    let mut sort_buffer = Dma::allocate(plan.run_len * 8)?;
 
     let mut buffers: Vec<Dma<u8>> = Vec::new();
-    for _ in 0..HUGE_PAGES_1G - 1 {
-        buffers.push(Dma::allocate(HUGE_PAGE_SIZE_1G)?);
+    if plan.passes > 0 {
+        for _ in 0..plan.fan_in {
+            buffers.push(Dma::allocate(plan.buffer_size)?);
+        }
     }
 
     let mut sorter = IPS2RaSorter::new_sequential();
-
-    let mut remaining = len;
-    println!("Starting sorting:");
-    let mut sort_times = Vec::new();
-    for i in 0..((len + HUGE_PAGE_SIZE_1G / 8 - 1) / (HUGE_PAGE_SIZE_1G / 8)) {
-        // read hugepage from ssd
-        println!("Reading hugepage {i}");
-        let start = std::time::Instant::now();
-        read_write_hugepage_1G(&mut qpair, i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, &mut sort_buffer, false);
-
-        println!("Done");
-
-        let u64slice = u8_to_u64_slice(&mut sort_buffer[0..{
-            if remaining > HUGE_PAGE_SIZE_1G / 8 {
-                remaining -= HUGE_PAGE_SIZE_1G / 8;
-                HUGE_PAGE_SIZE_1G
-            } else {
-                let res = remaining;
-                remaining = 0;
-                res * 8
-            }
-        }]);
-        println!("Creating and sampling task of length {}", u64slice.len());
-        let mut task = Task::new(u64slice, 0, 0);
-        task.sample();
-        println!("Done");
-
-        println!("Sorting hugepage {i}");
-        sorter.sequential_rec(&mut task);
-        println!("Done");
-
-        println!("Writing hugepage {i}");
-        read_write_hugepage_1G(&mut qpair, i * LBA_PER_CHUNK * CHUNKS_PER_HUGE_PAGE_1G, &mut sort_buffer, true);
-        println!("Done");
-
-        sorter.clear();
-        let duration = start.elapsed();
-        println!("Time elapsed in sorting hugepage {i} is: {:?}", duration);
-        sort_times.push(duration);
-    }
-
-    println!("Total time elapsed in sorting is: {:?}", sort_times.iter().sum::<std::time::Duration>());
-    println!("Starting merge");
-    let start = std::time::Instant::now();
-    merge_sequential(&mut qpair, len, &mut buffers, &mut sort_buffer);
+    sorter.cancel = options.cancel.clone();
+
+    let mut checksum = Checksum::new();
+    info!("Starting sorting");
+    let start = Instant::now();
+    let ctx = RunContext { sorter: &mut sorter, checksum: &mut checksum, options, progress: &progress };
+    let runs = if options.run_generation == RunGeneration::ReplacementSelection && plan.num_runs > 1 {
+        // the first two read buffers of the merge stream the input and the runs
+        let (input, output) = buffers.split_at_mut(1);
+        let heap = u8_to_u64_slice(&mut sort_buffer[0..plan.run_len * 8]);
+        replacement_selection(&mut qpair, heap, &mut input[0], &mut output[0], plan.buffer_size / 8, len, ctx)
+    } else {
+        generate_runs(&mut qpair, &mut sort_buffer, sorters, len, &plan, ctx)
+    };
+    let sort_time = start.elapsed();
+    info!("Total time elapsed in sorting is: {:?}", sort_time);
+
+    let start = Instant::now();
+    let merged = runs.map_err(Into::into).and_then(|runs| {
+        info!("Starting {}merge of {} runs", if options.in_place { "in-place " } else { "" }, runs.len());
+        if options.in_place {
+            merge_in_place(&mut qpair, &runs, &plan, &mut buffers, &mut sort_buffer, options, &progress)
+        } else {
+            Ok(merge_sequential(&mut qpair, &runs, &plan, &mut buffers, &mut sort_buffer, options, &progress)?)
+        }
+    });
+    let merge_io = match merged {
+        Ok(merge_io) => merge_io,
+        Err(e) => {
+            buffers.push(sort_buffer);
+            release_resources(nvme, qpair, buffers)?;
+            return Err(e);
+        }
+    };
     let duration = start.elapsed();
-    println!("Time elapsed in merging is: {:?}", duration);
+    info!("Time elapsed in merging is: {:?}", duration);
+    let mut metrics = sorter.metrics;
+    metrics.merge_io = merge_io;
+    metrics.merge_compute = duration - merge_io;
+
+    if VERIFY_SORT_MERGE {
+        progress.set_phase(Phase::Verifying);
+        verify_sort_merge(&mut qpair, &mut sort_buffer, 0, len, &checksum)?;
+        info!("Output verified");
+    }
 
-    println!("Total time elapsed in sorting and merging is: {:?}", sort_times.iter().sum::<std::time::Duration>() + duration);
-    Ok(nvme)
+    info!("Total time elapsed in sorting and merging is: {:?}", sort_time + duration);
+    progress.set_phase(Phase::Done);
+    metrics.add_queue_pair(qpair.stats);
+    metrics.total = total_start.elapsed();
+    Ok(metrics)
 }
 
-pub fn merge_sequential(qpair: &mut NvmeQueuePair, len: usize, buffer: &mut Vec<Dma<u8>>, output_buffer: &mut Dma<u8>) {
-    assert_eq!(buffer.len(), HUGE_PAGES_1G - 1);
-
-
-    let mut output = u8_to_u64_slice(&mut output_buffer[0..HUGE_PAGE_SIZE_1G]);
-
-    let total_number_hugepages = (len + HUGE_PAGE_SIZE_1G / 8 - 1) / (HUGE_PAGE_SIZE_1G / 8);
-    let last_hugepage_size = (len-1) % (HUGE_PAGE_SIZE_1G / 8) + 1;
-
-    let mut read_offset = 0;
-    let mut write_offset = total_number_hugepages;
-    let mut last_write_offset = write_offset;
-
-    let mut timeForIO= Duration::new(0,0);
-
-    let max = (total_number_hugepages as f64).log((HUGE_PAGES_1G - 1) as f64).ceil() as usize;
-    info!("Total number of hugepages: {total_number_hugepages}, max runs: {max}");
-
-    for i in 0..max {
-        let input_length = (HUGE_PAGES_1G - 1).pow(i as u32);
-        let result_length = input_length * (HUGE_PAGES_1G - 1);
-        info!("i = {i}, input length = {input_length}, result length = {result_length}, read offset = {read_offset}, write offset = {write_offset}\n");
-        info!("j = (0..{})", (total_number_hugepages+result_length-1) / result_length);
-        for j in 0..(total_number_hugepages+result_length-1) / result_length {
-            info!("i = {i}, j = {j}\n");
-            let mut write_idx = 0;
-            let mut written_hugepages = 0;
-            let mut hugepage_increments = [0; HUGE_PAGES_1G - 1];
-            let mut min_heap = BinaryHeap::with_capacity(HUGE_PAGES_1G - 1);
-
-            // Load first HUGE_PAGES-1 hugepages and push first elements to heap
-            for k in 0..HUGE_PAGES_1G - 1 {
-                info!("Initial read: hugepage: {} (offset: {}), index: {k}", j*result_length + k*input_length + read_offset, read_offset);
-
-                let start = std::time::Instant::now();
-                read_write_hugepage_1G(qpair, (j*result_length + k*input_length + read_offset) * LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, &mut buffer[k], false);
-                let duration = start.elapsed();
-                timeForIO+=duration;
-
-                info!("Hugepeage read: {:?}", u8_to_u64_slice(&mut buffer[k][0..HUGE_PAGE_SIZE_1G]));
-
-                { // scope to avoid borrowing issues
-                    let slice = u8_to_u64_slice(&mut buffer[k][0..HUGE_PAGE_SIZE_1G]);
-
-                    if (j * result_length + (k+1)*input_length) >= total_number_hugepages {
-                        // last hugepage block
-
-                        let block_length = total_number_hugepages - j * result_length - k * input_length;
-                        let len = {
-                            if block_length > 1 {
-                                HUGE_PAGE_SIZE_1G / 8
-                            } else {
-                                last_hugepage_size
-                            }
-                        };
-                        info!("Last hugepage block detected! Block length: {}, slice length: {}, index: {}", block_length, len, k);
+// sorts runs of `plan.run_len` elements in place, runs of several hugepages with all sorters of the pool.
+// Adaptive runs are split into their presorted stretches, which are joined with the previous run if they continue it.
+fn generate_runs(qpair: &mut NvmeQueuePair, sort_buffer: &mut Dma<u8>, sorters: &Workers, len: usize, plan: &SortPlan, ctx: RunContext) -> Result<Vec<(usize, usize)>, Cancelled> {
+    let RunContext { sorter, checksum, options, progress } = ctx;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let parallel = matches!(options.run_generation, RunGeneration::Hugepages(_));
+    let adaptive = options.run_generation == RunGeneration::Adaptive;
+    let mut presorted = 0;
+    if parallel {
+        sorters.set_cancellation(options.cancel.clone());
+    }
+    let mut runs: Vec<(usize, usize)> = Vec::with_capacity(plan.num_runs);
+    let mut previous_last = 0;
+    for i in 0..plan.num_runs {
+        if options.is_cancelled() {
+            break;
+        }
+        let _run = debug_span!("run", run = i).entered();
+        let lba = i * plan.run_len / (LBA_SIZE / 8);
+        let run_len = min(plan.run_len, len - i * plan.run_len);
+        // read run from ssd
+        debug!("Reading run {i}");
+        let start = std::time::Instant::now();
+        read_write_elements(qpair, sort_buffer, lba, 0, run_len, false);
+        sorter.metrics.run_io += start.elapsed();
 
-                        // TODO: check if len-1 or len
-                        min_heap.push(HeapEntry {
-                            value: slice[0],
-                            hugepage_idx: k,
-                            element_idx: 0,
-                            remaining: len-1,
-                        });
-                        break;
-                    }
-                    // Push the first element from the slice into the heap
-                    min_heap.push(HeapEntry {
-                        value: slice[0],
-                        hugepage_idx: k,
-                        element_idx: 0,
-                        remaining: slice.len()-1,
-                    });
+        let u64slice = u8_to_u64_slice(&mut sort_buffer[0..run_len * 8]);
+        if VERIFY_SORT_MERGE {
+            checksum.add_slice(u64slice);
+        }
+        let segments = if adaptive { adaptive_runs(u64slice, ADAPTIVE_MIN_STRETCH) } else { vec![(0, run_len, RunOrder::Unsorted)] };
+        let mut modified = false;
+        for &(start, n, order) in &segments {
+            let segment = &mut u64slice[start..start + n];
+            match order {
+                RunOrder::Ascending => presorted += n,
+                RunOrder::Descending => segment.reverse(),
+                // the elements between two stretches may be too few for a partitioning step
+                RunOrder::Unsorted if n <= THRESHOLD => insertion_sort(segment),
+                RunOrder::Unsorted if parallel => sort_run_parallel(sorters, segment),
+                RunOrder::Unsorted => {
+                    debug!("Creating and sampling task of length {}", segment.len());
+                    let mut task = Task::new(segment, 0, 0);
+                    let sampling = Instant::now();
+                    task.sample();
+                    sorter.metrics.sampling += sampling.elapsed();
+                    sorter.sequential_rec(&mut task);
+                    sorter.clear();
                 }
             }
+            modified |= order != RunOrder::Ascending;
+        }
+        // first and last element of every segment, a segment continuing the previous run is joined with it
+        for &(start, n, _) in &segments {
+            let (first, last) = (u64slice[start], u64slice[start + n - 1]);
+            match runs.last_mut() {
+                Some((_, previous_len)) if adaptive && previous_last <= first => *previous_len += n,
+                _ => runs.push((i * plan.run_len + start, n)),
+            }
+            previous_last = last;
+        }
 
-            // check if min_heap is not empty
-            while let Some(HeapEntry { value, hugepage_idx, element_idx, remaining }) = min_heap.pop() {
-                info!("Current min: {value}, hugepage_idx: {hugepage_idx}, element_idx: {element_idx}, remaining: {remaining}, output after: {}", write_idx+1);
-                // Write the value to the output buffer
-                output[write_idx] = value;
-                write_idx += 1;
-
-                // If the output buffer is full, write to SSD and reset index
-                if write_idx % (HUGE_PAGE_SIZE_1G / 8) == 0 {
-                    info!("Output buffer full, writing to SSD hugepage {} (written hugepages: {written_hugepages}):", j * result_length + write_offset + written_hugepages);
-
-                    let start = std::time::Instant::now();
-                    read_write_hugepage_1G(qpair, (j * result_length + write_offset + written_hugepages)*LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, output_buffer, true);
-                    let duration = start.elapsed();
-                    timeForIO+=duration;
-
-                    info!("Hugepage written: {:?}", u8_to_u64_slice(&mut output_buffer[0..HUGE_PAGE_SIZE_1G]));
-                    write_idx = 0;
-                    written_hugepages += 1;
-                    last_write_offset = write_offset;
-
-                    // Recreate the output slice after writing to SSD
-                    output = u8_to_u64_slice(&mut output_buffer[0..HUGE_PAGE_SIZE_1G]);
-                }
-
-                // Read the next element from same slice
-                if remaining > 0 {
-                    let next_value = {
-                        let slice = u8_to_u64_slice(&mut buffer[hugepage_idx][0..HUGE_PAGE_SIZE_1G]);
-                        slice[element_idx + 1]
-                    };
-
-                    // Push next element
-                    min_heap.push(HeapEntry {
-                        value: next_value,
-                        hugepage_idx,
-                        element_idx: element_idx + 1,
-                        remaining: remaining - 1,
-                    });
-                } else {
-                    info!("Hugepage_idx {hugepage_idx} exhausted");
-                    // Check if there are more hugepages in same run
-                    if hugepage_increments[hugepage_idx] + 1 < (result_length/(HUGE_PAGES_1G - 1)) {
-                        info!("Reading next hugepage. Increment is now: {}, resultlength/hugepages-1: {}", hugepage_increments[hugepage_idx] + 1, (result_length/(HUGE_PAGES_1G - 1)));
-                        hugepage_increments[hugepage_idx] += 1;
-
-                        // check if already last hugepage //TODO: double check
-                        if j * result_length + hugepage_idx*input_length + hugepage_increments[hugepage_idx] >= total_number_hugepages {
-                            info!("Last hugepage, not adding any more hugepages");
-                            continue;
-                        }
-
-
-                        info!("Index: {}, Reading hugepage {} (offset: {})", hugepage_idx, j * result_length + hugepage_idx*input_length + hugepage_increments[hugepage_idx] + read_offset, read_offset);
-                        // Read the next hugepage into the buffer
+        if modified {
+            debug!("Writing run {i}");
+            let writing = Instant::now();
+            read_write_elements(qpair, sort_buffer, lba, 0, run_len, true);
+            sorter.metrics.run_io += writing.elapsed();
+        } else {
+            debug!("Run {i} is presorted");
+        }
+        progress.run_sorted((run_len * 8) as u64);
+        info!("Time elapsed in sorting run {i} is: {:?}", start.elapsed());
+    }
+    if parallel {
+        sorters.set_cancellation(None);
+        sorter.metrics.combine(&sorters.take_metrics());
+    }
+    if adaptive {
+        info!("{} of {} elements were presorted, {} hugepages formed {} runs", presorted, len, plan.num_runs, runs.len());
+    }
+    options.check_cancelled()?;
+    Ok(runs)
+}
 
-                        let start = std::time::Instant::now();
-                        read_write_hugepage_1G(qpair, (j * result_length + hugepage_idx*input_length + hugepage_increments[hugepage_idx] + read_offset)*LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, &mut buffer[hugepage_idx], false);
-                        let duration = start.elapsed();
-                        timeForIO+=duration;
+// next unread element of a run and the elements of it in the read buffer
+struct RunCursor {
+    next: usize,
+    end: usize,
+    idx: usize,
+    filled: usize,
+}
 
-                        info!("Hugepeage read: {:?}", u8_to_u64_slice(&mut buffer[hugepage_idx][0..HUGE_PAGE_SIZE_1G]));
+/// Merges the sorted `runs`, given as (first element, length) from lba 0, with a fan-in of `plan.fan_in`
+/// and one read buffer per run. Every run starts on an lba boundary, the result starts at lba 0.
+/// Returns the time spent reading and writing, stops after the current group of runs once `options` is cancelled.
+#[instrument(level = "debug", skip_all, fields(runs = runs.len()))]
+pub(crate) fn merge_sequential(qpair: &mut NvmeQueuePair, runs: &[(usize, usize)], plan: &SortPlan, buffers: &mut [Dma<u8>], output_buffer: &mut Dma<u8>, options: &SortOptions, progress: &ProgressTracker) -> Result<Duration, Cancelled> {
+    let passes = merge_passes(runs.len(), plan.fan_in);
+    assert!(passes == 0 || buffers.len() >= plan.fan_in, "One read buffer per merged run required");
+    let len = runs.iter().map(|&(_, n)| n).sum();
+    // the runs of a replacement selection leave gaps up to the next lba, the scratch region starts behind them
+    let scratch_lba = runs.last().map_or(0, |&(start, n)| (start + n).div_ceil(LBA_SIZE / 8));
+    let mut runs = runs.to_vec();
+    let mut read_lba = 0;
+    let mut write_lba = scratch_lba;
+    let mut time_for_io = Duration::ZERO;
+    info!("Number of runs: {}, fan-in: {}, passes: {}", runs.len(), plan.fan_in, passes);
+
+    for pass in 0..passes {
+        let _round = debug_span!("merge_round", round = pass).entered();
+        progress.start_merge_level(pass);
+        let mut merged = Vec::with_capacity(runs.len().div_ceil(plan.fan_in));
+        for group in runs.chunks(plan.fan_in) {
+            options.check_cancelled()?;
+            // the output of a group starts where its first run starts
+            let group_start = group[0].0;
+            let group_len = group.iter().map(|&(_, n)| n).sum();
+            info!("Pass {pass}: merging {} runs of {group_len} elements at {group_start}", group.len());
+            let mut cursors: Vec<RunCursor> = group.iter()
+                .map(|&(start, n)| RunCursor { next: start, end: start + n, idx: 0, filled: 0 })
+                .collect();
+            time_for_io += merge_group(qpair, &mut cursors, group_start, read_lba, write_lba, plan, buffers, output_buffer, options, progress)?;
+            merged.push((group_start, group_len));
+        }
+        mem::swap(&mut read_lba, &mut write_lba);
+        runs = merged;
+    }
 
-                        let next_value = {
-                            let slice = u8_to_u64_slice(&mut buffer[hugepage_idx][0..HUGE_PAGE_SIZE_1G]);
-                            slice[0]
-                        };
+    if read_lba != 0 {
+        // copying the result to the beginning
+        info!("Merge: Copy needed!");
+        let start = std::time::Instant::now();
+        for done in (0..len).step_by(plan.run_len) {
+            let n = min(plan.run_len, len - done);
+            read_write_elements(qpair, output_buffer, read_lba + done / (LBA_SIZE / 8), 0, n, false);
+            read_write_elements(qpair, output_buffer, done / (LBA_SIZE / 8), 0, n, true);
+            progress.written((n * 8) as u64);
+        }
+        time_for_io += start.elapsed();
+    } else {
+        info!("Merge: No Copy needed!");
+    }
 
-                        //check if newly read hugepage is last hugepage
-                        if j * result_length + hugepage_idx*input_length + hugepage_increments[hugepage_idx] + 1 >= total_number_hugepages {
-                            info!("Last hugepage detected! Slice length: {},index: {}", last_hugepage_size, hugepage_idx);
-                            // Push the first element of new hugepage
-                            min_heap.push(HeapEntry {
-                                value: next_value,
-                                hugepage_idx,
-                                element_idx: 0,
-                                remaining: last_hugepage_size - 1,
-                            });
-                        } else {
-                            // Push the first element of new hugepage
-                            min_heap.push(HeapEntry {
-                                value: next_value,
-                                hugepage_idx,
-                                element_idx: 0,
-                                remaining: HUGE_PAGE_SIZE_1G / 8-1,
-                            });
-                        }
-                    } else {
-                        info!("No more hugepages to read");
-                    }
-                }
-            }
+    if options.trim_scratch && passes > 0 {
+        info!("Deallocating scratch region");
+        // the result is complete, a failed TRIM only leaves the scratch region allocated
+        if let Err(e) = deallocate_lbas(qpair, output_buffer, scratch_lba, scratch_lba) {
+            warn!("{}", e);
+        }
+    }
+    info!("Time for IO: {:?}", time_for_io);
+    Ok(time_for_io)
+}
 
-            // read line from stdin
-            //let mut input = String::new();
-            //io::stdin().read_line(&mut input).unwrap();
+// merges `runs` into the output starting at element `out_start`
+fn merge_group(qpair: &mut NvmeQueuePair, runs: &mut [RunCursor], out_start: usize, read_lba: usize, write_lba: usize, plan: &SortPlan, buffers: &mut [Dma<u8>], output_buffer: &mut Dma<u8>, options: &SortOptions, progress: &ProgressTracker) -> Result<Duration, Cancelled> {
+    let buffer_len = plan.buffer_size / 8;
+    let output_len = plan.run_len;
+    let mut time_for_io = Duration::ZERO;
+    let mut min_heap = BinaryHeap::with_capacity(runs.len());
 
-            if write_idx > 0 {
-                info!("Output buffer not empty at end, writing {} elements to SSD hugepage {} (written hugepages: {written_hugepages}):", write_idx, j * result_length + write_offset + written_hugepages);
-                let start = std::time::Instant::now();
-                read_write_hugepage_1G(qpair, (j * result_length + write_offset + written_hugepages)*LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, output_buffer, true);
-                let duration = start.elapsed();
-                info!("Hugepage written: {:?}", u8_to_u64_slice(&mut output_buffer[0..HUGE_PAGE_SIZE_1G]));
-                write_idx = 0;
-                written_hugepages += 1;
-                last_write_offset = write_offset;
+    for (k, run) in runs.iter_mut().enumerate() {
+        let value = refill(qpair, run, &mut buffers[k], read_lba, buffer_len, &mut time_for_io);
+        min_heap.push(Reverse((value, k)));
+    }
 
-                // Recreate the output slice after writing to SSD
-                output = u8_to_u64_slice(&mut output_buffer[0..HUGE_PAGE_SIZE_1G]);
-            }
+    let mut out_pos = out_start;
+    let mut write_idx = 0;
+    while let Some(Reverse((value, k))) = min_heap.pop() {
+        u8_to_u64_slice(&mut output_buffer[write_idx * 8..write_idx * 8 + 8])[0] = value;
+        write_idx += 1;
+        if write_idx == output_len {
+            let start = Instant::now();
+            read_write_elements(qpair, output_buffer, write_lba + out_pos / (LBA_SIZE / 8), 0, write_idx, true);
+            time_for_io += start.elapsed();
+            progress.written((write_idx * 8) as u64);
+            out_pos += write_idx;
+            write_idx = 0;
+            options.check_cancelled()?;
         }
 
-        info!("Swapping read and write offset");
-        let tmp = read_offset;
-        read_offset = write_offset;
-        write_offset = tmp;
+        let run = &mut runs[k];
+        run.idx += 1;
+        if run.idx < run.filled {
+            let idx = run.idx;
+            min_heap.push(Reverse((u8_to_u64(&buffers[k][idx * 8..idx * 8 + 8]), k)));
+        } else if run.next < run.end {
+            let value = refill(qpair, run, &mut buffers[k], read_lba, buffer_len, &mut time_for_io);
+            min_heap.push(Reverse((value, k)));
+        }
     }
 
-    info!("Last write offset: {last_write_offset}");
-    if last_write_offset != 0 { // TODO: do more efficient or avoid in sorting.
-        // copying all hugepages to the beginning
-        println!("Merge: Copy needed!");
-        for i in 0..total_number_hugepages{
-            read_write_hugepage_1G(qpair, (i + last_write_offset)*LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, output_buffer, false);
-            read_write_hugepage_1G(qpair, i*LBA_PER_CHUNK*CHUNKS_PER_HUGE_PAGE_1G, output_buffer, true);
-        }
-    } else {
-        println!("Merge: No Copy needed!");
+    if write_idx > 0 {
+        let start = Instant::now();
+        read_write_elements(qpair, output_buffer, write_lba + out_pos / (LBA_SIZE / 8), 0, write_idx, true);
+        time_for_io += start.elapsed();
+        progress.written((write_idx * 8) as u64);
     }
-    println!("Time for IO: {:?}", timeForIO);
-}
\ No newline at end of file
+    Ok(time_for_io)
+}
+
+// reads the next part of `run` into its buffer, returns its first element
+fn refill(qpair: &mut NvmeQueuePair, run: &mut RunCursor, buffer: &mut Dma<u8>, read_lba: usize, buffer_len: usize, time_for_io: &mut Duration) -> u64 {
+    let n = min(buffer_len, run.end - run.next);
+    let start = Instant::now();
+    read_write_elements(qpair, buffer, read_lba + run.next / (LBA_SIZE / 8), 0, n, false);
+    *time_for_io += start.elapsed();
+    run.next += n;
+    run.idx = 0;
+    run.filled = n;
+    u8_to_u64(&buffer[0..8])
+}
@@ -1,10 +1,43 @@
 use crate::config::*;
 use crate::conversion::*;
-use vroom::{NvmeQueuePair};
+use crate::sort::deallocate_lbas;
+use crate::verify::Checksum;
+use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
 use vroom::memory::{Dma, DmaSlice};
 use std::cmp::min;
+use std::error::Error;
 
-pub fn setup_array(arr: &mut [u64], qpair: &mut NvmeQueuePair) {
+/// Maximum number of lbas a single Write Zeroes command can clear
+const WRITE_ZEROES_MAX_LBA: usize = 0x1_0000;
+
+/// How lbas are cleared on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearStrategy {
+    /// Write Zeroes command
+    WriteZeroes,
+    /// Dataset Management deallocate, only valid if deallocated lbas read as zeroes
+    Deallocate,
+    /// Writing a zeroed buffer
+    Buffered,
+}
+
+impl ClearStrategy {
+    /// Picks the fastest strategy supported by the controller and namespace 1
+    pub fn detect(nvme: &NvmeDevice) -> ClearStrategy {
+        let read_zeroes = nvme.namespaces.get(&1).map_or(false, |ns| ns.deallocated_read_zeroes);
+        if nvme.supports_write_zeroes() {
+            ClearStrategy::WriteZeroes
+        } else if nvme.supports_dataset_management() && read_zeroes {
+            ClearStrategy::Deallocate
+        } else {
+            ClearStrategy::Buffered
+        }
+    }
+}
+
+/// Writes `arr` to the device starting at lba 0 and returns its checksum
+pub fn setup_array(arr: &mut [u64], qpair: &mut NvmeQueuePair) -> Checksum {
+    let checksum = Checksum::from_slice(arr);
     let mut buffer = Dma::allocate(HUGE_PAGE_SIZE_2M).unwrap();
     let length = arr.len();
     //debug!("Buffer pointer: {:?}, {:?}", buffer.virt, buffer.phys);
@@ -29,19 +62,52 @@ pub fn setup_array(arr: &mut [u64], qpair: &mut NvmeQueuePair) {
         let tmp = qpair.submit_io(&mut buffer.slice(0..slice.len()), (max*HUGE_PAGE_SIZE_2M/LBA_SIZE) as u64, true);
         qpair.complete_io(tmp);
     }
+    checksum
 
 
 }
 
-pub fn clear_chunks(chunks: usize, qpair: &mut NvmeQueuePair) {
-    let mut buffer = Dma::allocate(HUGE_PAGE_SIZE_2M).unwrap();
-    let tmp = [0; LBA_SIZE*LBA_PER_CHUNK];
-    buffer[0..tmp.len()].copy_from_slice(&tmp);
-    for i in 0..chunks  {
-        qpair.submit_io(&buffer.slice(0..tmp.len()), (i*LBA_PER_CHUNK) as u64, true);
-        if i != 0 {
-             qpair.complete_io(1);
+pub fn clear_chunks(chunks: usize, qpair: &mut NvmeQueuePair) -> Result<(), Box<dyn Error>> {
+    clear_lbas(qpair, ClearStrategy::Buffered, 0, chunks*LBA_PER_CHUNK)
+}
+
+/// Zeroes `num_lba` lbas starting at `start_lba` using `strategy`, fails if a command cannot be submitted or fails
+pub fn clear_lbas(qpair: &mut NvmeQueuePair, strategy: ClearStrategy, start_lba: usize, num_lba: usize) -> Result<(), Box<dyn Error>> {
+    let end_lba = start_lba + num_lba;
+    let mut lba = start_lba;
+    match strategy {
+        ClearStrategy::WriteZeroes => {
+            let max_lba_per_queue = (QUEUE_LENGTH - 1) * WRITE_ZEROES_MAX_LBA;
+            while lba < end_lba {
+                let n = min(end_lba - lba, max_lba_per_queue);
+                let submitted = qpair.submit_write_zeroes(lba as u64, n as u64);
+                if submitted == 0 {
+                    return Err(format!("Write Zeroes at lba {} could not be submitted, the queue is full", lba).into());
+                }
+                if qpair.complete_io_checked(submitted).is_none() {
+                    return Err(format!("Write Zeroes of {} lbas at lba {} failed", n, lba).into());
+                }
+                // a short submit only covers its commands of WRITE_ZEROES_MAX_LBA lbas, the rest is submitted again
+                lba += min(n, submitted * WRITE_ZEROES_MAX_LBA);
+            }
+        }
+        ClearStrategy::Deallocate => {
+            let mut buffer = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
+            deallocate_lbas(qpair, &mut buffer, start_lba, num_lba)?;
+        }
+        ClearStrategy::Buffered => {
+            let mut buffer = Dma::allocate(HUGE_PAGE_SIZE_2M)?;
+            buffer[0..HUGE_PAGE_SIZE_2M].fill(0);
+            let max_lba_per_buffer = HUGE_PAGE_SIZE_2M / LBA_SIZE;
+            while lba < end_lba {
+                let n = min(end_lba - lba, max_lba_per_buffer);
+                let tmp = qpair.submit_io(&buffer.slice(0..n*LBA_SIZE), lba as u64, true);
+                if qpair.complete_io_checked(tmp).is_none() {
+                    return Err(format!("Writing zeroes to {} lbas at lba {} failed", n, lba).into());
+                }
+                lba += n;
+            }
         }
     }
-    qpair.complete_io(1);
+    Ok(())
 }
\ No newline at end of file
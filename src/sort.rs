@@ -2,109 +2,231 @@ use crate::config::*;
 use crate::conversion::*;
 use crate::sorter::{ExtTask, IPS2RaSorter, Task};
 use crate::setup::{clear_chunks, setup_array};
-use crate::sequential_sort_merge::sequential_sort_merge;
-use crate::parallel_sort_merge::{bench_parallel_sort_merge, initialize_thread_local, parallel_sort_merge, prepare_benchmark_parallel};
-use crate::parallel::parallel_rec;
+use crate::context::SortContext;
+use crate::planner::{memory_budget, Hugepages, SortPlan};
+use crate::run_generation::RunGeneration;
+use crate::distribution_sort::distribution_sort as distribution_sort_ext;
+use crate::metrics::SortMetrics;
+use crate::output::SortedOutput;
+use crate::progress::{Phase, ProgressSink, ProgressTracker};
+use crate::cancel::{CancellationToken, Cancelled};
 use vroom::{NvmeDevice, NvmeQueuePair, QUEUE_LENGTH};
 use vroom::memory::{Dma, DmaSlice};
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::{io, thread};
+use std::sync::atomic::AtomicUsize;
+use std::{io, mem, thread};
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use rand::prelude::{SliceRandom, StdRng};
 use rand::SeedableRng;
 use log::{debug, error, info};
-use crate::sampling::sample_max;
-//use tracing::{instrument, span, Level};
+use tracing::instrument;
 
-static THREAD_POOL_INITIALIZED: AtomicBool = AtomicBool::new(false);
-static EXT_MERGE_SORTERS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+pub fn sort(arr: &mut [u64]) -> SortMetrics {
+    sort_with(arr, &SortOptions::new()).expect("Sort without cancellation token failed")
+}
 
-pub fn sort(arr: &mut [u64]) {
+#[instrument(level = "debug", skip_all, fields(len = arr.len()))]
+pub fn sort_with(arr: &mut [u64], options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    let start = Instant::now();
     let mut task = Task::new(arr, 0, 8);
-    if !task.sample(){
-        return;
+    let sampled = task.sample();
+    let sampling = start.elapsed();
+    if !sampled {
+        return Ok(SortMetrics { sampling, total: start.elapsed(), ..SortMetrics::new() });
     }
     let mut s = IPS2RaSorter::new_sequential();
+    s.cancel = options.cancel.clone();
     debug!("Task after sampling: {:?}", task.arr);
     info!("Level: {:?}", task.level);
     s.sequential_rec(&mut task);
+    options.check_cancelled()?;
+    s.metrics.sampling = sampling;
+    s.metrics.total = start.elapsed();
+    Ok(s.metrics)
 }
 
-//#[instrument]
-pub fn sort_parallel(arr: &mut [u64]) {
-    //read line from stdin
-    //let mut input = String::new();
-    //io::stdin().read_line(&mut input).unwrap();
-    //println!("Thread: {} starting parallel sort", rayon::current_thread_index().unwrap());
-    initialize_thread_pool();
-    let mut initial_task = Task::new(arr, 0, 8);
-    if !initial_task.sample(){
-        return;
-    }
-    //println!("Starting recursive sort");
-    parallel_rec(&mut initial_task);
+pub fn sort_parallel(arr: &mut [u64]) -> SortMetrics {
+    sort_parallel_with(arr, &SortOptions::new()).expect("Sort without cancellation token failed")
+}
+
+/// Sorts with a `SortContext` created for this call, keep a context to sort repeatedly
+pub fn sort_parallel_with(arr: &mut [u64], options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    SortContext::new()?.sort_parallel_with(arr, options)
 }
 
 
-pub fn sort_merge(mut nvme: NvmeDevice, len: usize, parallel: bool) -> Result<NvmeDevice, Box<dyn Error>>{
-    if !parallel {
-        sequential_sort_merge(nvme, len)
-    } else {
-        nvme = sort_merge_initialize_thread_local(nvme);
-        parallel_sort_merge(nvme, len)
-    }
+/// Options of the `*_with` entry points
+#[derive(Clone, Default)]
+pub struct SortOptions {
+    pub progress: Option<Arc<dyn ProgressSink>>,
+    pub cancel: Option<CancellationToken>,
+    // bytes of hugepage memory the external sorts plan with, all free hugepages if not set
+    pub memory: Option<usize>,
+    // how the sort-merge forms its runs
+    pub run_generation: RunGeneration,
+    // merge the runs of the sort-merge in place instead of through a scratch region as large as the input,
+    // a cancelled or failed in-place merge leaves the input permuted
+    pub in_place: bool,
+    // deallocate (TRIM) the scratch region of the external sorts once the result is complete
+    pub trim_scratch: bool,
 }
 
+impl SortOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-pub fn initialize_thread_pool() {
-    println!("Atomic bool: {}", THREAD_POOL_INITIALIZED.load(std::sync::atomic::Ordering::Relaxed));
-    if !THREAD_POOL_INITIALIZED.fetch_or(true, std::sync::atomic::Ordering::Relaxed) {
-        println!("Initializing thread pool with {} threads", NUM_THREADS);
-        rayon::ThreadPoolBuilder::new().
-            num_threads(NUM_THREADS).
-            build_global().
-            unwrap();
-        println!("Atomic bool: {}", THREAD_POOL_INITIALIZED.load(std::sync::atomic::Ordering::Relaxed));
+    pub fn with_progress(mut self, sink: impl ProgressSink + 'static) -> Self {
+        self.progress = Some(Arc::new(sink));
+        self
     }
 
-}
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn with_memory(mut self, bytes: usize) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
 
+    pub fn with_run_generation(mut self, run_generation: RunGeneration) -> Self {
+        self.run_generation = run_generation;
+        self
+    }
+
+    pub fn with_in_place(mut self, in_place: bool) -> Self {
+        self.in_place = in_place;
+        self
+    }
 
-pub fn sort_merge_initialize_thread_local(mut nvme: NvmeDevice) -> NvmeDevice {
-    initialize_thread_pool();
-    if !EXT_MERGE_SORTERS_INITIALIZED.fetch_or(true, std::sync::atomic::Ordering::Relaxed) {
-        nvme = initialize_thread_local(nvme, NUM_THREADS);
+    pub fn with_trim_scratch(mut self, trim_scratch: bool) -> Self {
+        self.trim_scratch = trim_scratch;
+        self
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled())
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+        Ok(())
     }
-    nvme
 }
 
+pub fn sort_merge(nvme: NvmeDevice, len: usize, parallel: bool) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>>{
+    sort_merge_with(nvme, len, parallel, &SortOptions::new())
+}
+
+/// Sorts with a `SortContext` created for this call, keep a context to sort repeatedly.
+/// The returned `SortedOutput` streams the result from the returned device.
+pub fn sort_merge_with(nvme: NvmeDevice, len: usize, parallel: bool, options: &SortOptions) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>>{
+    let mut context = SortContext::with_device(nvme)?;
+    let (output, metrics) = context.sort_merge_with(len, parallel, options)?;
+    Ok((context.into_device()?, output, metrics))
+}
 
-pub fn rolling_sort(mut nvme: NvmeDevice, len: usize, max: usize) -> Result<NvmeDevice, Box<dyn Error>> {
-    println!("Rolling sort - Preparation");
+
+pub fn rolling_sort(nvme: NvmeDevice, len: usize) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>> {
+    rolling_sort_with(nvme, len, &SortOptions::new())
+}
+
+pub fn rolling_sort_with(mut nvme: NvmeDevice, len: usize, options: &SortOptions) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>> {
+    let metrics = rolling_sort_ext(&mut nvme, len, options)?;
+    Ok((nvme, SortedOutput::new(0, len), metrics))
+}
+
+pub fn distribution_sort(nvme: NvmeDevice, len: usize) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>> {
+    distribution_sort_with(nvme, len, &SortOptions::new())
+}
+
+pub fn distribution_sort_with(mut nvme: NvmeDevice, len: usize, options: &SortOptions) -> Result<(NvmeDevice, SortedOutput, SortMetrics), Box<dyn Error>> {
+    let metrics = distribution_sort_ext(&mut nvme, len, options)?;
+    Ok((nvme, SortedOutput::new(0, len), metrics))
+}
+
+#[instrument(level = "debug", skip(nvme, options))]
+pub(crate) fn rolling_sort_ext(nvme: &mut NvmeDevice, len: usize, options: &SortOptions) -> Result<SortMetrics, Box<dyn Error>> {
+    info!("Rolling sort - Preparation");
+    // only the hugepage sized buckets are counted, the external levels rewrite the data in place
+    let progress = ProgressTracker::new(options.progress.clone(), 0, 0, (len * 8) as u64);
+    progress.set_phase(Phase::Partitioning);
+    let start = Instant::now();
     let mut qpair = nvme.create_io_queue_pair(QUEUE_LENGTH)?;
-    let mut sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G)?;
+    qpair.set_completion_mode(COMPLETION_MODE);
+    let (memory, pages) = memory_budget(options, Hugepages::default());
+    let plan = SortPlan::rolling(len, memory, &pages)?;
+    info!("Rolling sort with {} 2MiB buffers", plan.ext_buffers);
+    let sort_buffer = Dma::allocate(HUGE_PAGE_SIZE_1G)?;
     let mut buffers: Vec<Dma<u8>> = Vec::new();
-    for _ in 0..HUGE_PAGES_2M {
+    for _ in 0..plan.ext_buffers {
         buffers.push(Dma::allocate(HUGE_PAGE_SIZE_2M)?);
     }
     let mut sorter = IPS2RaSorter::new_ext_sequential(qpair, buffers, sort_buffer);
-    let mut task = ExtTask::new(0, 0, len, sample_max(max), 8);
-    println!("Starting rolling sort: Start-LBA: {}, Offset: {}, Size: {} ", task.start_lba, task.offset, task.size);
-    sorter.sequential_rolling_sort(&mut task);
+    let mut task = ExtTask::new(0, 0, len, 0, 8);
+    sorter.cancel = options.cancel.clone();
+    let sampling = Instant::now();
+    let sampled = sorter.sample(&mut task);
+    sorter.metrics.sampling += sampling.elapsed();
+    info!("Starting rolling sort: Start-LBA: {}, Offset: {}, Size: {}, Level: {}..{} ", task.start_lba, task.offset, task.size, task.level, task.level_end);
+    let sorted = if sampled { sorter.sequential_rolling_sort(&mut task, &progress) } else { Ok(()) };
+    if let Err(e) = sorted.and_then(|_| Ok(options.check_cancelled()?)) {
+        let mut buffers = sorter.buffers.take().unwrap();
+        buffers.extend(sorter.sort_buffer.take());
+        release_resources(nvme, sorter.qpair.take().unwrap(), buffers)?;
+        return Err(e);
+    }
+    progress.set_phase(Phase::Done);
 
-    Ok(nvme)
+    let mut metrics = mem::take(&mut sorter.metrics);
+    metrics.add_queue_pair(sorter.qpair.as_ref().unwrap().stats);
+    metrics.total = start.elapsed();
+    Ok(metrics)
 }
 
 
+/// Deletes the queue pair and frees the buffers of a cancelled external sort right away instead of on drop,
+/// so failures are reported to the caller.
+pub(crate) fn release_resources(nvme: &mut NvmeDevice, qpair: NvmeQueuePair, buffers: Vec<Dma<u8>>) -> Result<(), Box<dyn Error>> {
+    nvme.delete_io_queue_pair(qpair)?;
+    for buffer in buffers {
+        buffer.free()?;
+    }
+    Ok(())
+}
+
 pub fn find_bucket_ips2ra(input: u64, level: usize) -> usize {
     let bits_needed = (K as f64).log2().ceil() as u64;
     let shift = 8 * (7 - level as u64); // Adjust shift so that level 0 extracts the highest 8 bits
     ((input >> shift) & ((1 << bits_needed) - 1)) as usize
 }
 
+/// Deallocates (TRIM) `num_lba` lbas starting at `start_lba`, `buffer` is used for the range list.
+/// Fails if a command cannot be submitted or fails.
+pub fn deallocate_lbas(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, start_lba: usize, num_lba: usize) -> Result<(), Box<dyn Error>> {
+    let end_lba = start_lba + num_lba;
+    let mut lba = start_lba;
+    while lba < end_lba {
+        // one range per command, the longest a range can be
+        let n = std::cmp::min(end_lba - lba, u32::MAX as usize);
+        let submitted = qpair.submit_deallocate(buffer, &[(lba as u64, n as u64)]);
+        if submitted == 0 {
+            return Err(format!("Deallocating lba {} could not be submitted, the queue is full", lba).into());
+        }
+        if qpair.complete_io_checked(submitted).is_none() {
+            return Err(format!("Deallocating {} lbas starting at lba {} failed", n, lba).into());
+        }
+        lba += n;
+    }
+    Ok(())
+}
+
 pub fn read_write_elements(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, target_lba: usize, target_offset: usize, num_elements: usize, write: bool) {
     //println!("starting read_write_elements");
     let num_lba = (target_offset*8 + num_elements*8 + LBA_SIZE - 1) / LBA_SIZE;
@@ -160,12 +282,10 @@ pub fn read_write_elements(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, targ
 
 }
 
-//#[instrument]
 pub fn read_write_hugepage_1G(qpair: &mut NvmeQueuePair, lba_offset: usize, segment: &mut Dma<u8>, write: bool){
     read_write_elements(qpair, segment, lba_offset, 0, HUGE_PAGE_SIZE_1G/8, write);
 }
 
-//#[instrument]
 pub fn read_write_hugepage_2M(qpair: &mut NvmeQueuePair, lba_offset: usize, segment: &mut Dma<u8>, write: bool){
     read_write_elements(qpair, segment, lba_offset, 0, HUGE_PAGE_SIZE_2M/8, write);
 }
@@ -187,17 +307,3 @@ impl IPS2RaSorter{
         read_write_elements(qpair, sort_buffer, lba_offset, 0, HUGE_PAGE_SIZE_2M/8, write);
     }
 }
-
-pub fn prepare_benchmark(mut nvme: NvmeDevice, num_hugepages: usize, seed: usize) -> NvmeDevice {
-    nvme = sort_merge_initialize_thread_local(nvme);
-    prepare_benchmark_parallel(num_hugepages, seed);
-    nvme
-}
-
-// like parallel_sort_merge, only with time measurements
-// Mode 0: only sort
-// Mode 1: merge (sort required)
-pub fn benchmark_parallel_sort_merge(mut nvme: NvmeDevice, len: usize, mode: usize) -> Result<(NvmeDevice, Duration), Box<dyn Error>> {
-    nvme = sort_merge_initialize_thread_local(nvme);
-    bench_parallel_sort_merge(nvme, len, mode)
-}
\ No newline at end of file
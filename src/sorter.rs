@@ -1,4 +1,7 @@
 use crate::config::*;
+use crate::metrics::SortMetrics;
+use crate::cancel::CancellationToken;
+use crate::sort::find_bucket_ips2ra;
 use vroom::memory::Dma;
 use vroom::{NvmeQueuePair};
 use std::fmt;
@@ -48,6 +51,8 @@ pub struct ExtTask<> {
     pub size:  usize,
     pub level: usize,
     pub level_end: usize,
+    // subtracted from every element before its digits are taken, set by sampling when the key range is narrow
+    pub key_offset: u64,
 }
 
 impl ExtTask {
@@ -58,8 +63,19 @@ impl ExtTask {
             size,
             level,
             level_end,
+            key_offset: 0,
         }
     }
+
+    /// Bucket of `element` on the level of the task
+    pub fn bucket(&self, element: u64) -> usize {
+        find_bucket_ips2ra(element - self.key_offset, self.level)
+    }
+
+    /// Position of element `index` of the task on the device, counted in elements from lba 0
+    pub fn position(&self, index: usize) -> usize {
+        self.start_lba * (LBA_SIZE / 8) + self.offset + index
+    }
 }
 
 
@@ -84,9 +100,17 @@ pub struct IPS2RaSorter {
     // DMA
     pub qpair: Option<NvmeQueuePair>,
     pub buffers: Option<Vec<Dma<u8>>>,
-    pub sort_buffer: Option<Dma<u8>>
+    pub sort_buffer: Option<Dma<u8>>,
 
+    // accumulated over all tasks, not reset by clear()
+    pub metrics: SortMetrics,
+    // checked before every task, set by the *_with entry points
+    pub cancel: Option<CancellationToken>,
 }
+
+// The queue pair and buffers are owned by the sorter alone, a SortContext hands each sorter to one thread at a time
+unsafe impl Send for IPS2RaSorter {}
+
 impl IPS2RaSorter {
     pub fn new_sequential() -> Box<IPS2RaSorter> {
         Box::new(IPS2RaSorter {
@@ -104,9 +128,15 @@ impl IPS2RaSorter {
             qpair: None,
             buffers: None,
             sort_buffer: None,
+            metrics: SortMetrics::new(),
+            cancel: None,
         })
     }
 
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled())
+    }
+
     pub fn clear(&mut self) {
         for i in self.block_counts.iter_mut() {
             *i = 0;
@@ -122,7 +152,6 @@ impl IPS2RaSorter {
     }
 
     pub fn new_parallel() -> Box<Self> {
-        println!("Creating new parallel sorter");
         //read line from stdin
         //let mut line = String::new();
         //std::io::stdin().read_line(&mut line).unwrap();
@@ -141,6 +170,8 @@ impl IPS2RaSorter {
             qpair: None,
             buffers: None,
             sort_buffer: None,
+            metrics: SortMetrics::new(),
+            cancel: None,
         })
     }
 
@@ -160,6 +191,8 @@ impl IPS2RaSorter {
             qpair: Some(qpair),
             buffers: Some(buffers),
             sort_buffer: Some(sort_buffer),
+            metrics: SortMetrics::new(),
+            cancel: None,
         })
     }
 
@@ -0,0 +1,15 @@
+use std::error::Error;
+use std::path::Path;
+use tracing::Level;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+
+/// Records the spans up to `max_level` into a Chrome trace file at `path` (open with ui.perfetto.dev).
+/// Hugepage runs and merge rounds are `DEBUG`, the in-memory tasks and buckets `TRACE`.
+/// The trace is written when the returned guard is dropped. Log records are not affected.
+pub fn chrome_trace(path: &Path, max_level: Level) -> Result<FlushGuard, Box<dyn Error>> {
+    let (layer, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer.with_filter(LevelFilter::from_level(max_level))))?;
+    Ok(guard)
+}
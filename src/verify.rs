@@ -0,0 +1,109 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::sort::read_write_elements;
+use vroom::NvmeQueuePair;
+use vroom::memory::Dma;
+use std::cmp::min;
+use std::error::Error;
+
+/// Order independent checksum of a multiset of elements.
+/// Two permutations of the same elements have the same checksum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksum {
+    pub count: u64,
+    pub sum: u64,
+    pub xor: u64,
+    // sum of the mixed elements, catches changes that cancel out in sum and xor
+    pub hash: u64,
+}
+
+// finalizer of splitmix64
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_slice(arr: &[u64]) -> Self {
+        let mut checksum = Self::new();
+        checksum.add_slice(arr);
+        checksum
+    }
+
+    pub fn add(&mut self, x: u64) {
+        self.count += 1;
+        self.sum = self.sum.wrapping_add(x);
+        self.xor ^= x;
+        self.hash = self.hash.wrapping_add(mix(x));
+    }
+
+    pub fn add_slice(&mut self, arr: &[u64]) {
+        for &x in arr {
+            self.add(x);
+        }
+    }
+
+    /// Adds the elements of `other`, e.g. of the checksums computed by different threads
+    pub fn combine(&mut self, other: &Checksum) {
+        self.count += other.count;
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.xor ^= other.xor;
+        self.hash = self.hash.wrapping_add(other.hash);
+    }
+}
+
+/// Streaming check for non-decreasing order over consecutive slices, accumulates the checksum of all elements.
+#[derive(Debug, Default)]
+pub struct SortedVerifier {
+    prev: u64,
+    pub checksum: Checksum,
+}
+
+impl SortedVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, elements: &[u64]) -> Result<(), Box<dyn Error>> {
+        for (i, &value) in elements.iter().enumerate() {
+            if value < self.prev {
+                return Err(format!("Not sorted at index {}: {} > {}", self.checksum.count as usize + i, self.prev, value).into());
+            }
+            self.prev = value;
+        }
+        self.checksum.add_slice(elements);
+        Ok(())
+    }
+}
+
+/// Reads `len` elements starting at `start_lba` and checks that they are sorted.
+/// Returns the checksum of the elements read.
+pub fn verify_sorted_ext(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, start_lba: usize, len: usize) -> Result<Checksum, Box<dyn Error>> {
+    let elements_per_read = min(buffer.size, HUGE_PAGE_SIZE_1G) / 8;
+    let mut verifier = SortedVerifier::new();
+    let mut lba = start_lba;
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = min(remaining, elements_per_read);
+        read_write_elements(qpair, buffer, lba, 0, n, false);
+        verifier.push(u8_to_u64_slice(&mut buffer[0..n*8]))?;
+        lba += n*8 / LBA_SIZE;
+        remaining -= n;
+    }
+    Ok(verifier.checksum)
+}
+
+/// Checks that the `len` elements at `start_lba` are sorted and a permutation of the input with checksum `expected`
+pub fn verify_sort_merge(qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, start_lba: usize, len: usize, expected: &Checksum) -> Result<(), Box<dyn Error>> {
+    let checksum = verify_sorted_ext(qpair, buffer, start_lba, len)?;
+    if checksum != *expected {
+        return Err(format!("Output is not a permutation of the input. Expected {:?}, got {:?}", expected, checksum).into());
+    }
+    Ok(())
+}
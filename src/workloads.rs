@@ -0,0 +1,301 @@
+use crate::config::*;
+use crate::conversion::*;
+use crate::sort::read_write_elements;
+use crate::verify::Checksum;
+use vroom::NvmeQueuePair;
+use vroom::memory::Dma;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Random distributions are generated in blocks with their own rng, so every element only depends on
+// the seed and its index. Parts of the input can be generated independently and in any batch size.
+const WORKLOAD_BLOCK: usize = 4096;
+
+/// Input distributions used in the benchmarks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// 0, 1, 2, ...
+    Sorted,
+    /// n-1, n-2, ..., 0
+    ReverseSorted,
+    /// Sorted with n/20 swaps of random pairs anywhere in the input
+    AlmostSorted,
+    Uniform,
+    /// Element i is uniform in [2^k, 2^(k+1)) with k = i mod log2(n)
+    Exponential,
+    /// A[i] = i mod floor(sqrt(n))
+    RootDup,
+    /// A[i] = i^2 + n/2 mod n
+    TwoDup,
+    /// A[i] = i^8 + n/2 mod n
+    EightDup,
+    /// Uniform in [0, range)
+    Range(u64),
+    /// Zipf distributed ranks in [0, n) with exponent s
+    Zipf(f64),
+    /// NUM_THREADS blocks, block j is uniform in the value range of block 2j+1 (first half) or 2j-NUM_THREADS (second half)
+    Staggered,
+    /// 0, 1, ..., n/2, ..., 1, 0
+    OrganPipe,
+    /// Sorted with 5% of the elements swapped within blocks of 4096 elements
+    BlockAlmostSorted,
+}
+
+impl Distribution {
+    /// All distributions with default parameters
+    pub const ALL: [Distribution; 13] = [
+        Distribution::Sorted,
+        Distribution::ReverseSorted,
+        Distribution::AlmostSorted,
+        Distribution::Uniform,
+        Distribution::Exponential,
+        Distribution::RootDup,
+        Distribution::TwoDup,
+        Distribution::EightDup,
+        Distribution::Range(u32::MAX as u64),
+        Distribution::Zipf(1.0),
+        Distribution::Staggered,
+        Distribution::OrganPipe,
+        Distribution::BlockAlmostSorted,
+    ];
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Distribution::Sorted => write!(f, "sorted"),
+            Distribution::ReverseSorted => write!(f, "reverse-sorted"),
+            Distribution::AlmostSorted => write!(f, "almost-sorted"),
+            Distribution::Uniform => write!(f, "uniform"),
+            Distribution::Exponential => write!(f, "exponential"),
+            Distribution::RootDup => write!(f, "root-dup"),
+            Distribution::TwoDup => write!(f, "two-dup"),
+            Distribution::EightDup => write!(f, "eight-dup"),
+            Distribution::Range(range) => write!(f, "range:{}", range),
+            Distribution::Zipf(s) => write!(f, "zipf:{}", s),
+            Distribution::Staggered => write!(f, "staggered"),
+            Distribution::OrganPipe => write!(f, "organ-pipe"),
+            Distribution::BlockAlmostSorted => write!(f, "block-almost-sorted"),
+        }
+    }
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    /// Parses the names printed by `Display`, `range` and `zipf` take an optional parameter (e.g. `zipf:1.2`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param)),
+            None => (s, None),
+        };
+        let invalid = || format!("Invalid parameter for distribution {}", name);
+        match (name, param) {
+            ("sorted", None) => Ok(Distribution::Sorted),
+            ("reverse-sorted", None) => Ok(Distribution::ReverseSorted),
+            ("almost-sorted", None) => Ok(Distribution::AlmostSorted),
+            ("uniform", None) => Ok(Distribution::Uniform),
+            ("exponential", None) => Ok(Distribution::Exponential),
+            ("root-dup", None) => Ok(Distribution::RootDup),
+            ("two-dup", None) => Ok(Distribution::TwoDup),
+            ("eight-dup", None) => Ok(Distribution::EightDup),
+            ("range", None) => Ok(Distribution::Range(u32::MAX as u64)),
+            ("range", Some(range)) => Ok(Distribution::Range(range.parse().map_err(|_| invalid())?)),
+            ("zipf", None) => Ok(Distribution::Zipf(1.0)),
+            ("zipf", Some(s)) => Ok(Distribution::Zipf(s.parse().map_err(|_| invalid())?)),
+            ("staggered", None) => Ok(Distribution::Staggered),
+            ("organ-pipe", None) => Ok(Distribution::OrganPipe),
+            ("block-almost-sorted", None) => Ok(Distribution::BlockAlmostSorted),
+            _ => Err(format!("Unknown distribution: {}", s)),
+        }
+    }
+}
+
+/// Seeded generator for `len` elements of a distribution
+#[derive(Debug, Clone, Copy)]
+pub struct Workload {
+    pub distribution: Distribution,
+    pub len: usize,
+    pub seed: u64,
+}
+
+impl Workload {
+    pub fn new(distribution: Distribution, len: usize, seed: u64) -> Self {
+        if let Distribution::Range(range) = distribution {
+            assert!(range > 0, "Range must not be empty");
+        }
+        if let Distribution::Zipf(s) = distribution {
+            assert!(s >= 0.0, "Zipf exponent must not be negative");
+        }
+        Workload { distribution, len, seed }
+    }
+
+    /// Generates all elements
+    pub fn generate(&self) -> Vec<u64> {
+        let mut data = vec![0; self.len];
+        self.fill(0, &mut data);
+        data
+    }
+
+    /// Fills `out` with the elements at index `start..start + out.len()`.
+    /// `AlmostSorted` replays all its n/20 swaps for every call, so it is only practical filled at once.
+    pub fn fill(&self, start: usize, out: &mut [u64]) {
+        assert!(start + out.len() <= self.len, "Workload has only {} elements", self.len);
+        if self.distribution == Distribution::AlmostSorted {
+            self.fill_almost_sorted(start, out);
+            return;
+        }
+        let zipf = match self.distribution {
+            Distribution::Zipf(s) => Some(rand_distr::Zipf::new(self.len as u64, s).unwrap()),
+            _ => None,
+        };
+        let mut block = [0u64; WORKLOAD_BLOCK];
+        let mut i = start;
+        while i < start + out.len() {
+            let block_idx = i / WORKLOAD_BLOCK;
+            let block_start = block_idx * WORKLOAD_BLOCK;
+            let block_len = min(WORKLOAD_BLOCK, self.len - block_start);
+            self.generate_block(block_idx, &mut block[..block_len], zipf.as_ref());
+
+            let from = i - block_start;
+            let to = min(block_len, start + out.len() - block_start);
+            out[i - start..i - start + to - from].copy_from_slice(&block[from..to]);
+            i += to - from;
+        }
+    }
+
+    // the swaps span the whole input, for a part of it only the displaced elements are tracked
+    fn fill_almost_sorted(&self, start: usize, out: &mut [u64]) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let n = self.len;
+        if start == 0 && out.len() == n {
+            out.iter_mut().enumerate().for_each(|(i, x)| *x = i as u64);
+            for _ in 0..n / 20 { // swap 5% of data
+                let a = rng.gen_range(0..n);
+                let b = rng.gen_range(0..n);
+                out.swap(a, b);
+            }
+            return;
+        }
+        let mut displaced: HashMap<usize, u64> = HashMap::new();
+        for _ in 0..n / 20 {
+            let a = rng.gen_range(0..n);
+            let b = rng.gen_range(0..n);
+            let value_a = displaced.get(&a).copied().unwrap_or(a as u64);
+            let value_b = displaced.get(&b).copied().unwrap_or(b as u64);
+            displaced.insert(a, value_b);
+            displaced.insert(b, value_a);
+        }
+        out.iter_mut().enumerate().for_each(|(j, x)| *x = (start + j) as u64);
+        for (position, value) in displaced {
+            if (start..start + out.len()).contains(&position) {
+                out[position - start] = value;
+            }
+        }
+    }
+
+    // `zipf` is the distribution of `Zipf`, built once per fill
+    fn generate_block(&self, block_idx: usize, block: &mut [u64], zipf: Option<&rand_distr::Zipf<f64>>) {
+        // mix the block index into the seed so neighbouring blocks get unrelated streams
+        let mut rng = StdRng::seed_from_u64(self.seed ^ (block_idx as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let n = self.len as u64;
+        let first = (block_idx * WORKLOAD_BLOCK) as u64;
+
+        match self.distribution {
+            Distribution::Sorted => {
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = first + j as u64);
+            }
+            Distribution::ReverseSorted => {
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = n - 1 - (first + j as u64));
+            }
+            Distribution::AlmostSorted => unreachable!("AlmostSorted is not generated in blocks"),
+            Distribution::BlockAlmostSorted => {
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = first + j as u64);
+                for _ in 0..block.len() / 20 { // swap 5% of data
+                    let a = rng.gen_range(0..block.len());
+                    let b = rng.gen_range(0..block.len());
+                    block.swap(a, b);
+                }
+            }
+            Distribution::Uniform => {
+                block.iter_mut().for_each(|x| *x = rng.gen());
+            }
+            Distribution::Exponential => {
+                let log_n = (n as f64).log2().ceil().max(1.0) as u64;
+                block.iter_mut().enumerate().for_each(|(j, x)| {
+                    let k = ((first + j as u64) % log_n) as f64;
+                    *x = rng.gen_range(2f64.powf(k)..2f64.powf(k + 1.0)) as u64;
+                });
+            }
+            Distribution::RootDup => {
+                let sqrt_n = ((n as f64).sqrt() as u64).max(1);
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = (first + j as u64) % sqrt_n);
+            }
+            Distribution::TwoDup => {
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = (pow_mod(first + j as u64, 2, n) + n / 2) % n);
+            }
+            Distribution::EightDup => {
+                block.iter_mut().enumerate().for_each(|(j, x)| *x = (pow_mod(first + j as u64, 8, n) + n / 2) % n);
+            }
+            Distribution::Range(range) => {
+                block.iter_mut().for_each(|x| *x = rng.gen_range(0..range));
+            }
+            Distribution::Zipf(_) => {
+                let zipf = zipf.expect("Zipf distribution not built");
+                block.iter_mut().for_each(|x| *x = rng.sample(zipf) as u64 - 1);
+            }
+            Distribution::Staggered => {
+                let p = NUM_THREADS as u64;
+                let per_block = n.div_ceil(p);
+                block.iter_mut().enumerate().for_each(|(j, x)| {
+                    let b = (first + j as u64) / per_block;
+                    let target = if 2 * b < p { 2 * b + 1 } else { 2 * b - p };
+                    *x = target * per_block + rng.gen_range(0..per_block);
+                });
+            }
+            Distribution::OrganPipe => {
+                block.iter_mut().enumerate().for_each(|(j, x)| {
+                    let i = first + j as u64;
+                    *x = if i < n / 2 { i } else { n - 1 - i };
+                });
+            }
+        }
+    }
+
+    /// Writes the workload to the device starting at `start_lba` in batches of the buffer size (up to 1GiB).
+    /// Returns the checksum of the written elements. `AlmostSorted` swaps across the whole input and is refused
+    /// if it needs more than one batch, `BlockAlmostSorted` streams.
+    pub fn write_to_device(&self, qpair: &mut NvmeQueuePair, buffer: &mut Dma<u8>, start_lba: usize) -> Result<Checksum, Box<dyn Error>> {
+        let elements_per_write = min(buffer.size, HUGE_PAGE_SIZE_1G) / 8;
+        if self.distribution == Distribution::AlmostSorted && self.len > elements_per_write {
+            return Err(format!("{} elements of {} do not fit into one batch, use {}", self.len, self.distribution, Distribution::BlockAlmostSorted).into());
+        }
+        let mut checksum = Checksum::new();
+        let mut lba = start_lba;
+        let mut written = 0;
+        while written < self.len {
+            let n = min(self.len - written, elements_per_write);
+            let slice = u8_to_u64_slice(&mut buffer[0..n*8]);
+            self.fill(written, slice);
+            checksum.add_slice(slice);
+            read_write_elements(qpair, buffer, lba, 0, n, true);
+            lba += n*8 / LBA_SIZE;
+            written += n;
+        }
+        Ok(checksum)
+    }
+}
+
+fn pow_mod(base: u64, exp: u32, modulus: u64) -> u64 {
+    let mut result = 1u128 % modulus as u128;
+    let base = base as u128 % modulus as u128;
+    for _ in 0..exp {
+        result = result * base % modulus as u128;
+    }
+    result as u64
+}
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod block_cache {
+    use bachelorthesis::ClockPolicy;
+
+    // fills all slots in order, as the cache does on its first misses
+    fn filled(num_slots: usize) -> ClockPolicy {
+        let mut clock = ClockPolicy::new(num_slots);
+        for line in 0..num_slots {
+            assert_eq!(clock.lookup(line), None);
+            let slot = clock.victim();
+            assert_eq!(slot, line);
+            assert_eq!(clock.insert(slot, line), None);
+        }
+        clock
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut clock = filled(4);
+        assert_eq!(clock.lookup(2), Some(2));
+        assert_eq!(clock.lookup(3), Some(3));
+        assert_eq!(clock.lookup(7), None);
+        assert!(clock.contains(0));
+        assert!(!clock.contains(7));
+        assert_eq!((clock.hits(), clock.misses()), (2, 5));
+    }
+
+    #[test]
+    fn second_chance_eviction() {
+        let mut clock = filled(4);
+        // all lines were just used, the hand takes their chances and evicts the oldest one
+        let slot = clock.victim();
+        assert_eq!(slot, 0);
+        assert_eq!(clock.insert(slot, 4), Some(0));
+        assert!(!clock.contains(0));
+        assert_eq!(clock.line(slot), Some(4));
+
+        // a hit protects line 1 once, line 2 goes next
+        assert_eq!(clock.lookup(1), Some(1));
+        let slot = clock.victim();
+        assert_eq!(clock.insert(slot, 5), Some(2));
+        assert_eq!(clock.lookup(1), Some(1));
+        assert_eq!(clock.lookup(5), Some(2));
+    }
+}
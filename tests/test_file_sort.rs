@@ -0,0 +1,33 @@
+#[cfg(test)]
+mod file_sort {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{BufReader, BufWriter, Write};
+    use bachelorthesis::{read_elements, sort_file, write_elements, Distribution, Workload};
+
+    #[test]
+    fn output_with_runs_extension() {
+        let dir = env::temp_dir().join(format!("file-sort-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.bin");
+        let output = dir.join("sorted.runs");
+
+        let mut data = Workload::new(Distribution::Uniform, 50_000, 3).generate();
+        let mut writer = BufWriter::new(File::create(&input).unwrap());
+        write_elements(&mut writer, &mut data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let (len, _) = sort_file(&input, &output, 20_000, false).unwrap();
+        let mut sorted = vec![0u64; len + 1];
+        let n = read_elements(&mut BufReader::new(File::open(&output).unwrap()), &mut sorted).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        data.sort_unstable();
+        assert_eq!(n, data.len());
+        assert_eq!(&sorted[..n], &data[..]);
+        // the scratch file is removed after the merge
+        assert_eq!(files, 2);
+    }
+}
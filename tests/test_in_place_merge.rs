@@ -0,0 +1,96 @@
+#[cfg(test)]
+mod in_place_merge {
+    use bachelorthesis::{arrange_chains, InPlaceBlocks, IN_PLACE_BLOCK_SIZE};
+
+    const BLOCK: usize = IN_PLACE_BLOCK_SIZE / 8;
+
+    #[test]
+    fn blocks_free_when_read() {
+        // the second run starts in the middle of block 1
+        let runs = [(0, BLOCK + BLOCK / 2), (BLOCK + BLOCK / 2, BLOCK)];
+        let mut blocks = InPlaceBlocks::new(&runs, 2);
+        assert_eq!((blocks.len(), blocks.free_blocks()), (5, 2));
+
+        blocks.consumed(0, BLOCK);
+        assert_eq!(blocks.allocate(), Some(0));
+        // block 1 still holds the head of the second run
+        blocks.consumed(BLOCK, BLOCK / 2);
+        assert_eq!(blocks.allocate(), Some(3));
+        blocks.consumed(BLOCK + BLOCK / 2, BLOCK / 2);
+        assert_eq!(blocks.allocate(), Some(1));
+        assert_eq!(blocks.allocate(), Some(4));
+        assert_eq!(blocks.allocate(), None);
+    }
+
+    #[test]
+    fn reserve_suffices_for_interleaved_runs() {
+        // the runs take turns, so each of them holds a partially read block the whole pass
+        let fan_in = 4;
+        let run_len = 2 * BLOCK + BLOCK / 3;
+        let runs: Vec<(usize, usize)> = (0..fan_in).map(|i| (i * run_len, run_len)).collect();
+        let mut blocks = InPlaceBlocks::new(&runs, fan_in + 2);
+        // next element to read, end and elements left in the read buffer of each run
+        let mut cursors: Vec<(usize, usize, usize)> = runs.iter().map(|&(start, n)| (start, start + n, 0)).collect();
+        let refill = |blocks: &mut InPlaceBlocks, cursor: &mut (usize, usize, usize)| {
+            let n = cursor.1.min((cursor.0 / BLOCK + 1) * BLOCK) - cursor.0;
+            blocks.consumed(cursor.0, n);
+            cursor.0 += n;
+            cursor.2 = n;
+        };
+        cursors.iter_mut().for_each(|cursor| refill(&mut blocks, cursor));
+
+        let mut out = 0;
+        let mut written = 0;
+        while cursors.iter().any(|cursor| cursor.2 > 0) {
+            for cursor in cursors.iter_mut().filter(|cursor| cursor.2 > 0) {
+                cursor.2 -= 1;
+                out += 1;
+                if out == BLOCK {
+                    let block = blocks.allocate().expect("Reserve too small");
+                    blocks.written(block, BLOCK);
+                    out = 0;
+                    written += 1;
+                }
+                if cursor.2 == 0 && cursor.0 < cursor.1 {
+                    refill(&mut blocks, cursor);
+                }
+            }
+        }
+        assert!(blocks.allocate().is_some());
+        assert_eq!(written, fan_in * run_len / BLOCK);
+    }
+
+    // moves the blocks along the chains, returns the number of moves
+    fn apply(map: &[usize], num_blocks: usize) -> usize {
+        let mut physical: Vec<Option<usize>> = vec![None; num_blocks];
+        for (logical, &block) in map.iter().enumerate() {
+            physical[block] = Some(logical);
+        }
+        let mut moves = 0;
+        for chain in arrange_chains(map) {
+            let mut carried = physical[chain[0]].take();
+            for &block in &chain[1..] {
+                let displaced = physical[block].take();
+                physical[block] = carried;
+                carried = displaced;
+                moves += 1;
+            }
+            // the end of a chain is free
+            assert_eq!(carried, None);
+        }
+        for (logical, &block) in physical[..map.len()].iter().enumerate() {
+            assert_eq!(block, Some(logical));
+        }
+        moves
+    }
+
+    #[test]
+    fn arrange_permutation() {
+        assert_eq!(apply(&[0, 1, 2], 3), 0);
+        // a cycle without a free block
+        assert_eq!(apply(&[1, 2, 0], 3), 3);
+        // output blocks spread over the input and the reserve
+        assert_eq!(apply(&[5, 0, 3, 1, 7, 2], 8), 6);
+        assert_eq!(apply(&[4, 1, 6, 0], 7), 3);
+    }
+}
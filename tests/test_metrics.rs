@@ -0,0 +1,28 @@
+#[cfg(test)]
+mod metrics {
+    use bachelorthesis::{QueuePairStats, SortMetrics};
+
+    fn stats(reads: u64) -> QueuePairStats {
+        QueuePairStats { read_commands: reads, bytes_read: reads * 4096, ..Default::default() }
+    }
+
+    #[test]
+    fn queue_pairs_per_worker() {
+        // two workers in the run phase and the merge phase, plus the queue pair of the sort
+        let mut runs = SortMetrics::new();
+        runs.add_queue_pair(stats(1));
+        runs.add_queue_pair(stats(2));
+        let mut merge = SortMetrics::new();
+        merge.add_queue_pair(stats(10));
+        merge.add_queue_pair(stats(20));
+
+        let mut metrics = runs.clone();
+        metrics.combine(&merge);
+        metrics.add_queue_pair(stats(100));
+
+        let reads: Vec<u64> = metrics.queue_pairs.iter().map(|q| q.read_commands).collect();
+        assert_eq!(reads, vec![11, 22, 100]);
+        assert_eq!(metrics.io.read_commands, 133);
+        assert_eq!(metrics.io.bytes_read, 133 * 4096);
+    }
+}
@@ -0,0 +1,80 @@
+#[cfg(test)]
+mod planner {
+    use bachelorthesis::{Hugepages, RunGeneration, SortPlan, HUGE_PAGE_SIZE_1G, HUGE_PAGE_SIZE_2M};
+
+    const GIB: usize = HUGE_PAGE_SIZE_1G;
+
+    #[test]
+    fn single_pass_with_1g_buffers() {
+        let pages = Hugepages { free_2m: 0, free_1g: 40 };
+        let plan = SortPlan::sort_merge(30 * GIB / 8, 40 * GIB, &pages).unwrap();
+        assert_eq!(plan.run_len, GIB / 8);
+        assert_eq!(plan.num_runs, 30);
+        assert_eq!(plan.fan_in, 30);
+        assert_eq!(plan.buffer_size, GIB);
+        assert_eq!(plan.passes, 1);
+    }
+
+    #[test]
+    fn fan_in_not_tied_to_1g_buffers() {
+        // 4 GiB leave three 1 GiB read buffers, but 1536 2 MiB buffers merge 100 runs in one pass
+        let pages = Hugepages { free_2m: 2000, free_1g: 40 };
+        let plan = SortPlan::sort_merge(100 * GIB / 8, 4 * GIB, &pages).unwrap();
+        assert_eq!(plan.num_runs, 100);
+        assert_eq!(plan.fan_in, 100);
+        assert_eq!(plan.buffer_size, HUGE_PAGE_SIZE_2M);
+        assert_eq!(plan.passes, 1);
+
+        let plan = SortPlan::sort_merge(100 * GIB / 8, 4 * GIB, &Hugepages { free_2m: 0, free_1g: 40 }).unwrap();
+        assert_eq!(plan.fan_in, 3);
+        assert_eq!(plan.passes, 5);
+    }
+
+    #[test]
+    fn parallel_fan_in_not_tied_to_threads() {
+        // 20 threads with 24 2 MiB buffers each merge 400 runs in two passes instead of three
+        let pages = Hugepages { free_2m: 480, free_1g: 20 };
+        let plan = SortPlan::parallel_sort_merge(400 * GIB / 8, 21 * GIB, &pages, 20).unwrap();
+        assert_eq!(plan.fan_in, 24);
+        assert_eq!(plan.passes, 2);
+
+        let pages = Hugepages { free_2m: 8000, free_1g: 20 };
+        let plan = SortPlan::parallel_sort_merge(400 * GIB / 8, 40 * GIB, &pages, 20).unwrap();
+        assert_eq!(plan.fan_in, 400);
+        assert_eq!(plan.passes, 1);
+    }
+
+    #[test]
+    fn runs_of_several_hugepages() {
+        let runs: RunGeneration = "hugepages:4".parse().unwrap();
+        assert_eq!(runs, RunGeneration::Hugepages(4));
+        assert_eq!(runs.to_string().parse::<RunGeneration>().unwrap(), runs);
+
+        let pages = Hugepages { free_2m: 0, free_1g: 40 };
+        let plan = SortPlan::sort_merge_with(100 * GIB / 8, 40 * GIB, &pages, runs).unwrap();
+        assert_eq!(plan.run_len, 4 * GIB / 8);
+        assert_eq!(plan.num_runs, 25);
+        assert_eq!(plan.passes, 1);
+        assert!(SortPlan::sort_merge_with(100 * GIB / 8, 3 * GIB, &pages, runs).is_err());
+        assert!(SortPlan::parallel_sort_merge_with(100 * GIB / 8, 40 * GIB, &pages, 20, RunGeneration::ReplacementSelection).is_err());
+    }
+
+    #[test]
+    fn adaptive_runs_only_sequential() {
+        let runs: RunGeneration = "adaptive".parse().unwrap();
+        assert_eq!(runs, RunGeneration::Adaptive);
+        // like hugepage runs, adaptive ones fall back to 2 MiB runs on a small budget
+        let pages = Hugepages { free_2m: 2000, free_1g: 0 };
+        let plan = SortPlan::sort_merge_with(GIB / 8, 100 * HUGE_PAGE_SIZE_2M, &pages, runs).unwrap();
+        assert_eq!(plan.run_len, HUGE_PAGE_SIZE_2M / 8);
+        assert!(SortPlan::parallel_sort_merge_with(GIB / 8, 40 * GIB, &Hugepages { free_2m: 2000, free_1g: 40 }, 20, runs).is_err());
+    }
+
+    #[test]
+    fn budget_too_small() {
+        let pages = Hugepages { free_2m: 2000, free_1g: 40 };
+        assert!(SortPlan::sort_merge(10 * GIB / 8, HUGE_PAGE_SIZE_2M, &pages).is_err());
+        assert!(SortPlan::rolling(10 * GIB / 8, GIB, &pages).is_err());
+        assert_eq!(SortPlan::sort_merge(GIB / 8, GIB, &pages).unwrap().passes, 0);
+    }
+}
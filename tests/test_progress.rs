@@ -0,0 +1,37 @@
+#[cfg(test)]
+mod progress {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{BufWriter, Write};
+    use bachelorthesis::{sort_file_with, write_elements, Distribution, Phase, Progress, SortOptions, Workload};
+
+    #[test]
+    fn file_sort_reports_progress() {
+        let dir = env::temp_dir().join(format!("progress-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.bin");
+        let output = dir.join("output.bin");
+
+        let mut data = Workload::new(Distribution::Uniform, 100_000, 1).generate();
+        let mut writer = BufWriter::new(File::create(&input).unwrap());
+        write_elements(&mut writer, &mut data).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let (sender, receiver) = crossbeam_channel::unbounded::<Progress>();
+        let options = SortOptions::new().with_progress(sender);
+        sort_file_with(&input, &output, 30_000, false, &options).unwrap();
+        drop(options);
+        let reports: Vec<Progress> = receiver.iter().collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.phase, Phase::Done);
+        assert_eq!(last.runs, 4);
+        assert_eq!(last.runs_sorted, 4);
+        assert_eq!(last.merge_levels, 1);
+        assert_eq!(last.bytes_written, last.total_bytes);
+        assert!(reports.iter().any(|p| p.phase == Phase::Merging));
+        assert!(reports.windows(2).all(|w| w[0].bytes_written <= w[1].bytes_written));
+    }
+}
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod report {
+    use std::time::Duration;
+
+    use bachelorthesis::{BenchReport, BenchResult, ReportFormat};
+
+    fn result(algorithm: &str, millis: &[u64]) -> BenchResult {
+        let mut result = BenchResult::new("test", algorithm, "uniform", 1_000_000, 4);
+        result.durations = millis.iter().map(|&ms| Duration::from_millis(ms)).collect();
+        result
+    }
+
+    #[test]
+    fn compare_against_csv_baseline() {
+        let mut baseline = BenchReport::new(ReportFormat::Csv);
+        baseline.push(result("a", &[100, 100]));
+        baseline.push(result("b", &[100, 100]));
+        let mut csv = Vec::new();
+        baseline.write(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+
+        let mut current = BenchReport::new(ReportFormat::Csv);
+        current.push(result("a", &[103, 103])); // within tolerance
+        current.push(result("b", &[120, 120]));
+        current.push(result("c", &[500])); // not in the baseline
+        let regressions = current.compare(&csv, 0.05).unwrap();
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].key, "test/b/uniform/1000000/4");
+        assert!((regressions[0].slowdown() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        let mut report = BenchReport::new(ReportFormat::Json);
+        report.push(result("quote\"back\\slash\n", &[100]));
+        let mut json = Vec::new();
+        report.write(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains(r#""algorithm":"quote\"back\\slash\n""#), "{}", json);
+    }
+
+    #[test]
+    fn throughput() {
+        let result = result("a", &[500, 1500]);
+        assert_eq!(result.mean(), Duration::from_secs(1));
+        assert_eq!(result.elements_per_second(), 1e6);
+        assert_eq!(result.gigabytes_per_second(), 0.008);
+    }
+}
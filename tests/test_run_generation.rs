@@ -0,0 +1,66 @@
+#[cfg(test)]
+mod run_generation {
+    use bachelorthesis::{adaptive_runs, heapify, run_order, sift_down, Distribution, RunOrder, Workload};
+
+    fn is_heap(heap: &[u64]) -> bool {
+        (1..heap.len()).all(|i| heap[(i - 1) / 2] <= heap[i])
+    }
+
+    #[test]
+    fn heap_pops_in_order() {
+        let mut heap = Workload::new(Distribution::Uniform, 1000, 7).generate();
+        let mut expected = heap.clone();
+        expected.sort_unstable();
+        heapify(&mut heap);
+        assert!(is_heap(&heap));
+
+        // pops the minimum by moving the last element to the root
+        let mut popped = Vec::new();
+        for size in (1..=heap.len()).rev() {
+            popped.push(heap[0]);
+            heap[0] = heap[size - 1];
+            sift_down(&mut heap[..size - 1], 0);
+            assert!(is_heap(&heap[..size - 1]));
+        }
+        assert_eq!(popped, expected);
+    }
+
+    #[test]
+    fn sift_down_replaced_root() {
+        let mut heap = vec![5, 3, 8, 1, 9, 2];
+        heapify(&mut heap);
+        heap[0] = 10;
+        sift_down(&mut heap, 0);
+        assert!(is_heap(&heap));
+        assert_eq!(heap[0], 2);
+    }
+
+    #[test]
+    fn orders() {
+        assert_eq!(run_order(&[]), RunOrder::Ascending);
+        assert_eq!(run_order(&[4, 4, 4]), RunOrder::Ascending);
+        assert_eq!(run_order(&[1, 2, 2, 7]), RunOrder::Ascending);
+        assert_eq!(run_order(&[7, 2, 2, 1]), RunOrder::Descending);
+        assert_eq!(run_order(&[1, 3, 2]), RunOrder::Unsorted);
+    }
+
+    #[test]
+    fn adaptive_stretches() {
+        // ascending, descending and a few unsorted elements, cut to lbas of 64 elements
+        let mut arr: Vec<u64> = (0..200).collect();
+        arr.extend((0..200).map(|k| 1000 - k));
+        arr.extend([5, 3, 9, 1].repeat(4));
+        let runs = adaptive_runs(&arr, 64);
+        assert_eq!(runs, vec![
+            (0, 192, RunOrder::Ascending),
+            (192, 64, RunOrder::Unsorted),
+            (256, 128, RunOrder::Descending),
+            (384, 32, RunOrder::Unsorted),
+        ]);
+
+        let sorted: Vec<u64> = (0..1000).collect();
+        assert_eq!(adaptive_runs(&sorted, 64), vec![(0, 1000, RunOrder::Ascending)]);
+        // stretches shorter than the minimum are not split off
+        assert_eq!(adaptive_runs(&arr, 256), vec![(0, arr.len(), RunOrder::Unsorted)]);
+    }
+}
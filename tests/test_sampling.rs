@@ -0,0 +1,46 @@
+#[cfg(test)]
+mod sampling {
+    use bachelorthesis::ExtStats;
+
+    fn stats(elements: &[u64]) -> ExtStats {
+        ExtStats {
+            min: *elements.iter().min().unwrap(),
+            max: *elements.iter().max().unwrap(),
+            differing_bits: elements.iter().fold(0, |bits, &x| bits | (x ^ elements[0])),
+            samples: elements.to_vec(),
+        }
+    }
+
+    #[test]
+    fn equal_elements() {
+        assert_eq!(stats(&[42, 42, 42]).levels(), (0, 0, 0));
+    }
+
+    #[test]
+    fn common_prefix_and_suffix() {
+        // only the bytes 5 and 6 differ
+        let elements = [0xAB00_0000_0000_0100, 0xAB00_0000_00FF_FF00, 0xAB00_0000_0012_3400];
+        assert_eq!(stats(&elements).levels(), (5, 7, 0));
+    }
+
+    #[test]
+    fn narrowed_by_minimum() {
+        // no common prefix byte, but all elements are within 0x1FF of the minimum
+        let elements = [0x00FF_FFFF_FFFF_FF00, 0x0100_0000_0000_00FF, 0x0100_0000_0000_0000];
+        let (level, level_end, key_offset) = stats(&elements).levels();
+        assert_eq!((level, level_end, key_offset), (6, 8, 0x00FF_FFFF_FFFF_FF00));
+        // the keys without the offset fit into the levels from `level` on
+        assert!(elements.iter().all(|&x| (x - key_offset) >> (8 * (8 - level)) == 0));
+    }
+
+    #[test]
+    fn histogram_of_level() {
+        let elements = [0x0100_0000_0000_0000, 0x0100_0000_0000_00FF, 0x0300_0000_0000_0000];
+        let histogram = stats(&elements).histogram(0, 0);
+        assert_eq!((histogram[1], histogram[3]), (2, 1));
+        assert_eq!(histogram.iter().sum::<usize>(), elements.len());
+        // the digits are taken after subtracting the key offset
+        let histogram = stats(&elements).histogram(7, 0x0100_0000_0000_0000);
+        assert_eq!((histogram[0], histogram[0xFF]), (2, 1));
+    }
+}
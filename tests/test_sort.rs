@@ -1,12 +1,13 @@
 #[cfg(test)]
 mod sequential_sort {
     use std::env;
+    use std::time::Duration;
     use rand::prelude::SliceRandom;
     use rand::rngs::{StdRng};
     use rand::{thread_rng, Rng, SeedableRng};
     use lazy_static::lazy_static;
 
-    use bachelorthesis::{sort, HUGE_PAGE_SIZE_2M};
+    use bachelorthesis::{sort, sort_with, CancellationToken, Cancelled, Checksum, SortContext, SortOptions, HUGE_PAGE_SIZE_2M};
 
     lazy_static! {
         static ref SEED: u64 = initialize_seed();
@@ -36,6 +37,41 @@ mod sequential_sort {
         verify_sorted(&arr);
     }
 
+    #[test]
+    fn sequential_metrics() {
+        let mut arr: Vec<u64> = (1..=100_000).collect();
+        arr.shuffle(&mut StdRng::seed_from_u64(*SEED));
+        let metrics = sort(&mut arr);
+        verify_sorted(&arr);
+        assert!(metrics.classification > Duration::ZERO);
+        assert!(metrics.compute_time() <= metrics.total);
+        assert_eq!(metrics.io_time(), Duration::ZERO);
+        assert_eq!(metrics.io.commands(), 0);
+    }
+
+    #[test]
+    fn cancelled_sequential() {
+        let mut arr: Vec<u64> = (1..=100_000).collect();
+        arr.shuffle(&mut StdRng::seed_from_u64(*SEED));
+        let checksum = Checksum::from_slice(&arr);
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = sort_with(&mut arr, &SortOptions::new().with_cancellation(cancel));
+        assert!(result.unwrap_err().downcast_ref::<Cancelled>().is_some());
+        assert_eq!(Checksum::from_slice(&arr), checksum);
+    }
+
+    #[test]
+    fn reused_context() {
+        let mut context = SortContext::new().unwrap();
+        for i in 0..2 {
+            let mut arr: Vec<u64> = (1..=100_000).collect();
+            arr.shuffle(&mut StdRng::seed_from_u64(*SEED + i));
+            context.sort_parallel(&mut arr);
+            verify_sorted(&arr);
+        }
+    }
+
     #[test]
     fn random_sequential(){
         let mut rng = StdRng::seed_from_u64(*SEED);
@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod verify {
+    use rand::prelude::SliceRandom;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use bachelorthesis::{sort, Checksum, SortedVerifier};
+
+    #[test]
+    fn checksum_permutation() {
+        let mut rng = StdRng::seed_from_u64(12345);
+        let mut arr: Vec<u64> = (0..100_000).map(|_| rng.gen()).collect();
+        let checksum = Checksum::from_slice(&arr);
+        arr.shuffle(&mut rng);
+        assert_eq!(checksum, Checksum::from_slice(&arr));
+        sort(&mut arr);
+        assert_eq!(checksum, Checksum::from_slice(&arr));
+
+        // split into chunks like the threads of the sort-merge
+        let mut combined = Checksum::new();
+        for chunk in arr.chunks(4096) {
+            combined.combine(&Checksum::from_slice(chunk));
+        }
+        assert_eq!(checksum, combined);
+    }
+
+    #[test]
+    fn checksum_detects_changes() {
+        let arr: Vec<u64> = (0..1024).collect();
+        let checksum = Checksum::from_slice(&arr);
+
+        // sum and xor stay the same when swapping bits between two elements
+        let mut modified = arr.clone();
+        modified[1] = 0;
+        modified[2] = 3;
+        assert_eq!(Checksum::from_slice(&modified).count, checksum.count);
+        assert_ne!(Checksum::from_slice(&modified), checksum);
+
+        let mut duplicated = arr.clone();
+        duplicated[5] = duplicated[4];
+        assert_ne!(Checksum::from_slice(&duplicated), checksum);
+    }
+
+    #[test]
+    fn sorted_verifier_chunks() {
+        let arr: Vec<u64> = (0..10_000).map(|i| i / 3).collect();
+        let mut verifier = SortedVerifier::new();
+        for chunk in arr.chunks(1000) {
+            verifier.push(chunk).unwrap();
+        }
+        assert_eq!(verifier.checksum, Checksum::from_slice(&arr));
+
+        // unsorted across the chunk boundary
+        let mut verifier = SortedVerifier::new();
+        verifier.push(&[1, 2, 5]).unwrap();
+        assert!(verifier.push(&[4, 6]).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod workloads {
+    use bachelorthesis::{Distribution, Workload};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn fill_is_deterministic() {
+        for distribution in Distribution::ALL {
+            let workload = Workload::new(distribution, 20_000, 12345);
+            let data = workload.generate();
+            assert_eq!(data, workload.generate(), "{}", distribution);
+
+            // generating in odd sized batches gives the same elements
+            let mut batched = vec![0; data.len()];
+            for (i, chunk) in batched.chunks_mut(3001).enumerate() {
+                workload.fill(i * 3001, chunk);
+            }
+            assert_eq!(data, batched, "{}", distribution);
+        }
+    }
+
+    #[test]
+    fn distribution_shapes() {
+        let n = 10_000;
+        let sorted = Workload::new(Distribution::Sorted, n, 1).generate();
+        assert!(sorted.windows(2).all(|w| w[0] <= w[1]));
+
+        let reverse = Workload::new(Distribution::ReverseSorted, n, 1).generate();
+        assert!(reverse.windows(2).all(|w| w[0] >= w[1]));
+
+        let range = Workload::new(Distribution::Range(100), n, 1).generate();
+        assert!(range.iter().all(|&x| x < 100));
+
+        let organ_pipe = Workload::new(Distribution::OrganPipe, n, 1).generate();
+        assert!(organ_pipe[..n / 2].windows(2).all(|w| w[0] <= w[1]));
+        assert!(organ_pipe[n / 2..].windows(2).all(|w| w[0] >= w[1]));
+
+        let zipf = Workload::new(Distribution::Zipf(1.0), n, 1).generate();
+        assert!(zipf.iter().all(|&x| x < n as u64));
+        assert!(zipf.iter().filter(|&&x| x == 0).count() > zipf.iter().filter(|&&x| x == 1).count());
+    }
+
+    #[test]
+    fn almost_sorted_swaps_anywhere() {
+        // the generator of the earlier benchmarks
+        let n = 50_000;
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut expected: Vec<u64> = (0..n as u64).collect();
+        for _ in 0..n / 20 {
+            let i = rng.gen_range(0..n);
+            let j = rng.gen_range(0..n);
+            expected.swap(i, j);
+        }
+        assert_eq!(Workload::new(Distribution::AlmostSorted, n, 7).generate(), expected);
+
+        let block = Workload::new(Distribution::BlockAlmostSorted, n, 7).generate();
+        assert!(block.chunks(4096).enumerate().all(|(i, chunk)| chunk.iter().all(|&x| x as usize / 4096 == i)));
+    }
+
+    #[test]
+    fn parse_names() {
+        for distribution in Distribution::ALL {
+            assert_eq!(distribution.to_string().parse::<Distribution>().unwrap(), distribution);
+        }
+        assert_eq!("range:42".parse::<Distribution>().unwrap(), Distribution::Range(42));
+        assert_eq!("zipf:1.5".parse::<Distribution>().unwrap(), Distribution::Zipf(1.5));
+        assert!("range:abc".parse::<Distribution>().is_err());
+        assert!("normal".parse::<Distribution>().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::{env, process};
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args();
+    args.next();
+
+    let pci_addr = match args.next() {
+        Some(arg) => arg,
+        None => {
+            eprintln!("Usage: cargo run --example smart_log <pci bus id>");
+            process::exit(1);
+        }
+    };
+
+    let mut nvme = vroom::init(&pci_addr)?;
+
+    let smart = nvme.get_smart_log()?;
+    println!("Critical warning: 0x{:x}", smart.critical_warning);
+    println!("Temperature: {} °C", smart.temperature_celsius());
+    println!("Available spare: {}% (threshold: {}%)", smart.available_spare, smart.available_spare_threshold);
+    println!("Percentage used: {}%", smart.percentage_used);
+    println!("Data read: {} GB", smart.bytes_read() / 1_000_000_000);
+    println!("Data written: {} GB", smart.bytes_written() / 1_000_000_000);
+    println!("Media errors: {}", smart.media_errors);
+    println!("Thermal management transitions: {:?}", smart.thermal_transition_counts);
+
+    for entry in nvme.get_error_log(64)? {
+        println!("{:?}", entry);
+    }
+
+    Ok(())
+}
@@ -31,6 +31,22 @@ pub struct NvmeCommand {
     pub cdw15: u32,
 }
 
+/// NVMe spec 6.7.1
+/// Dataset Management range definition
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C, packed)]
+pub struct DsmRange {
+    /// Context attributes
+    pub context_attributes: u32,
+    /// Length in logical blocks
+    pub length: u32,
+    /// Starting LBA
+    pub slba: u64,
+}
+
+/// maximum number of ranges per dataset management command
+pub const DSM_MAX_RANGES: usize = 256;
+
 impl NvmeCommand {
     pub fn create_io_completion_queue(c_id: u16, qid: u16, ptr: usize, size: u16) -> Self {
         Self {
@@ -229,8 +245,10 @@ impl NvmeCommand {
         }
     }
 
+    /// `numd` is the 0's based number of dwords to transfer
     pub(crate) fn get_log_page(
         c_id: u16,
+        ns_id: u32,
         numd: u32,
         ptr0: u64,
         ptr1: u64,
@@ -238,7 +256,9 @@ impl NvmeCommand {
         lpid: u16,
     ) -> Self {
         Self {
+            opcode: 0x2,
             c_id,
+            ns_id,
             d_ptr: [ptr0, ptr1],
             cdw10: (numd << 16) | lid as u32,
             cdw11: ((lpid as u32) << 16) | numd >> 16,
@@ -246,6 +266,32 @@ impl NvmeCommand {
         }
     }
 
+    /// `nr` is the 0's based number of ranges in the range list at `ptr0`
+    pub fn dataset_management(
+        c_id: u16,
+        ns_id: u32,
+        ptr0: u64,
+        ptr1: u64,
+        nr: u8,
+        deallocate: bool,
+    ) -> Self {
+        Self {
+            opcode: 9,
+            flags: 0,
+            c_id,
+            ns_id,
+            _rsvd: 0,
+            md_ptr: 0,
+            d_ptr: [ptr0, ptr1],
+            cdw10: nr as u32,
+            cdw11: (deallocate as u32) << 2, // Attribute - Deallocate (AD)
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
     // not supported by samsung
     pub fn write_zeroes(c_id: u16, ns_id: u32, slba: u64, nlb: u16, deac: bool) -> Self {
         Self {
@@ -9,14 +9,42 @@ mod nvme;
 mod pci;
 #[allow(dead_code)]
 mod queues;
+mod vfio;
 
 pub use memory::HUGE_PAGE_SIZE_2M;
 pub use nvme::{NvmeDevice, NvmeQueuePair};
 use pci::*;
-pub use queues::QUEUE_LENGTH;
+pub use queues::{CompletionMode, QUEUE_LENGTH};
 use std::error::Error;
+use std::time::Duration;
+
+/// How the device registers and DMA memory are accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// VFIO if the device is bound to vfio-pci, sysfs otherwise
+    #[default]
+    Auto,
+    /// BAR0 through sysfs, physical addresses through /proc/self/pagemap; requires root
+    Sysfs,
+    /// BAR0 through the VFIO device, DMA buffers mapped into the IOMMU
+    Vfio,
+}
+
+impl Backend {
+    pub(crate) fn resolve(self, pci_addr: &str) -> Backend {
+        match self {
+            Backend::Auto if vfio::is_bound_to_vfio(pci_addr) => Backend::Vfio,
+            Backend::Auto => Backend::Sysfs,
+            backend => backend,
+        }
+    }
+}
 
 pub fn init(pci_addr: &str) -> Result<NvmeDevice, Box<dyn Error>> {
+    init_with_backend(pci_addr, Backend::Auto)
+}
+
+pub fn init_with_backend(pci_addr: &str, backend: Backend) -> Result<NvmeDevice, Box<dyn Error>> {
     let mut vendor_file = pci_open_resource_ro(pci_addr, "vendor").expect("wrong pci address");
     let mut device_file = pci_open_resource_ro(pci_addr, "device").expect("wrong pci address");
     let mut config_file = pci_open_resource_ro(pci_addr, "config").expect("wrong pci address");
@@ -31,7 +59,13 @@ pub fn init(pci_addr: &str) -> Result<NvmeDevice, Box<dyn Error>> {
         return Err(format!("device {} is not a block device", pci_addr).into());
     }
 
-    let mut nvme = NvmeDevice::init(pci_addr)?;
+    // hugepage files of crashed runs would otherwise keep their memory reserved
+    let reclaimed = memory::reclaim_stale_hugepages();
+    if reclaimed > 0 {
+        println!("Removed {reclaimed} stale hugepage files");
+    }
+
+    let mut nvme = NvmeDevice::init(pci_addr, backend)?;
     nvme.identify_controller()?;
     let ns = nvme.identify_namespace_list(0);
     for n in ns {
@@ -46,6 +80,8 @@ pub struct NvmeNamespace {
     pub id: u32,
     pub blocks: u64,
     pub block_size: u64,
+    /// Deallocated logical blocks read back as all zeroes
+    pub deallocated_read_zeroes: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -53,3 +89,123 @@ pub struct NvmeStats {
     pub completions: u64,
     pub submissions: u64,
 }
+
+/// I/O issued on a single queue pair
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueuePairStats {
+    pub read_commands: u64,
+    pub write_commands: u64,
+    /// Dataset management and write zeroes commands
+    pub other_commands: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub completions: u64,
+    /// Time spent waiting in `complete_io`
+    pub completion_wait: Duration,
+}
+
+impl QueuePairStats {
+    pub fn add(&mut self, other: &QueuePairStats) {
+        self.read_commands += other.read_commands;
+        self.write_commands += other.write_commands;
+        self.other_commands += other.other_commands;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.completions += other.completions;
+        self.completion_wait += other.completion_wait;
+    }
+
+    pub fn commands(&self) -> u64 {
+        self.read_commands + self.write_commands + self.other_commands
+    }
+}
+
+/// SMART / Health Information log page (Log Identifier 02h)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeSmartLog {
+    pub critical_warning: u8,
+    /// Composite temperature in Kelvin
+    pub temperature: u16,
+    pub available_spare: u8,
+    pub available_spare_threshold: u8,
+    pub percentage_used: u8,
+    /// In units of 1000 * 512 bytes
+    pub data_units_read: u128,
+    /// In units of 1000 * 512 bytes
+    pub data_units_written: u128,
+    pub host_read_commands: u128,
+    pub host_write_commands: u128,
+    /// In minutes
+    pub controller_busy_time: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub error_log_entries: u128,
+    /// Minutes spent above the warning composite temperature threshold
+    pub warning_temperature_time: u32,
+    /// Minutes spent above the critical composite temperature threshold
+    pub critical_temperature_time: u32,
+    /// Temperature sensors 1-8 in Kelvin, 0 if not implemented
+    pub temperature_sensors: [u16; 8],
+    /// Thermal management temperature 1/2 transition counts
+    pub thermal_transition_counts: [u32; 2],
+    /// Total seconds spent in thermal management temperature 1/2
+    pub thermal_transition_times: [u32; 2],
+}
+
+impl NvmeSmartLog {
+    pub fn temperature_celsius(&self) -> i32 {
+        self.temperature as i32 - 273
+    }
+
+    pub fn has_critical_warning(&self) -> bool {
+        self.critical_warning != 0
+    }
+
+    /// Available spare capacity has fallen below the threshold
+    pub fn spare_below_threshold(&self) -> bool {
+        self.critical_warning & (1 << 0) != 0
+    }
+
+    /// Temperature is outside of the over/under temperature threshold
+    pub fn temperature_exceeded(&self) -> bool {
+        self.critical_warning & (1 << 1) != 0
+    }
+
+    /// NVM subsystem reliability has been degraded due to media or internal errors
+    pub fn reliability_degraded(&self) -> bool {
+        self.critical_warning & (1 << 2) != 0
+    }
+
+    /// Media has been placed in read only mode
+    pub fn read_only(&self) -> bool {
+        self.critical_warning & (1 << 3) != 0
+    }
+
+    /// The controller has entered thermal management since the counters were last cleared
+    pub fn thermal_throttled(&self) -> bool {
+        self.thermal_transition_counts.iter().any(|&c| c != 0)
+    }
+
+    pub fn bytes_read(&self) -> u128 {
+        self.data_units_read * 1000 * 512
+    }
+
+    pub fn bytes_written(&self) -> u128 {
+        self.data_units_written * 1000 * 512
+    }
+}
+
+/// Error Information log page entry (Log Identifier 01h)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeErrorLogEntry {
+    pub error_count: u64,
+    pub sq_id: u16,
+    pub c_id: u16,
+    pub status: u16,
+    pub parameter_error_location: u16,
+    pub lba: u64,
+    pub ns_id: u32,
+    pub command_specific: u64,
+}
@@ -1,3 +1,4 @@
+use crate::vfio::{vfio_map_dma, vfio_unmap_dma};
 use lazy_static::lazy_static;
 use std::slice;
 // use std::rc::Rc;
@@ -9,6 +10,7 @@ use std::os::fd::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::{fs, mem, process, ptr};
+use std::path::Path;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut, Index, IndexMut, Range, RangeTo, RangeFull};
 
@@ -36,6 +38,10 @@ pub struct Dma<T> {
     pub virt: *mut T,
     pub phys: usize,
     pub size: usize,
+    // hugetlbfs file backing the mapping, None for slices of another buffer
+    file: Option<String>,
+    // open handle holding a shared flock on the file, tells `reclaim_stale_hugepages` it is in use
+    lock: Option<fs::File>,
 }
 
 impl Debug for Dma<u8> {
@@ -126,7 +132,9 @@ impl DmaSlice for Dma<u8> {
             Dma {
                 virt: self.virt.add(index.start),
                 phys: self.phys + index.start,
-                size: (index.end - index.start)
+                size: (index.end - index.start),
+                file: None,
+                lock: None,
             }
         }
 
@@ -201,6 +209,11 @@ impl<T> Dma<T> {
 
         //println!("Allocating DMA memory of size: {} (input: {}) with page size: {}", size, size, page_size_str);
 
+        // without the iommu the physical address is that of the first page, the pages after it are not contiguous
+        if size > huge_page_size && !vfio_enabled() {
+            return Err(format!("DMA buffers of {} bytes span several hugepages and need the VFIO backend", size).into());
+        }
+
         let id = HUGEPAGE_ID.fetch_add(1, Ordering::SeqCst);
         // Path for 1 GiB huge pages
         let path = {
@@ -219,8 +232,18 @@ impl<T> Dma<T> {
             .create(true)
             .open(&path)?;
 
+        // held until the buffer is released, the lock is dropped with the process if it crashes
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) } != 0 {
+            let e = io::Error::last_os_error();
+            fs::remove_file(&path)?;
+            return Err(e.into());
+        }
+
         // Set the file size to the allocated size
-        file.set_len(size as u64)?;
+        if let Err(e) = file.set_len(size as u64) {
+            fs::remove_file(&path)?;
+            return Err(e.into());
+        }
 
         let fd = file.as_raw_fd();
         let ptr = unsafe {
@@ -239,32 +262,112 @@ impl<T> Dma<T> {
         };
 
         if ptr == libc::MAP_FAILED {
+            fs::remove_file(&path)?;
             return Err("failed to mmap huge page - are huge pages enabled and free?".into());
         }
 
+        // owns the mapping from here on, so it is released if one of the following steps fails
+        let mut dma = Dma {
+            virt: ptr as *mut T,
+            phys: 0,
+            size,
+            file: Some(path),
+            lock: Some(file),
+        };
+
+        // the iommu pins the mapped pages, the io virtual address is contiguous over the whole buffer
+        if vfio_enabled() {
+            dma.phys = vfio_map_dma(ptr as usize, size)?;
+            return Ok(dma);
+        }
+
         // Lock the memory
         if unsafe { libc::mlock(ptr, size) } != 0 {
             return Err("failed to memory lock huge page".into());
         }
 
-        Ok(Dma {
-            virt: ptr as *mut T,
-            phys: virt_to_phys(ptr as usize)?, // Implement this function as needed
-            size,
-        })
+        dma.phys = virt_to_phys(ptr as usize)?; // Implement this function as needed
+        Ok(dma)
     }
 
-    pub fn free(&self) -> Result<(), Box<dyn Error>> {
+    /// Unmaps the buffer and deletes its hugetlbfs file, same as dropping it but reports errors
+    pub fn free(mut self) -> Result<(), Box<dyn Error>> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = self.file.take() else {
+            return Ok(());
+        };
+        if vfio_enabled() && self.phys != 0 {
+            vfio_unmap_dma(self.phys, self.size)?;
+        }
         unsafe {
             if libc::munmap(self.virt as *mut libc::c_void, self.size) != 0 {
                 return Err("failed to munmap huge page".into());
             }
         }
-
+        fs::remove_file(path)?;
+        self.lock = None;
         Ok(())
     }
 }
 
+impl<T> Drop for Dma<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            eprintln!("Failed to free dma buffer: {}", e);
+        }
+    }
+}
+
+/// Deletes the hugetlbfs files left behind by processes that no longer exist, e.g. after a crash.
+/// A file is only removed if its process is gone and no one holds a lock on it, errors are logged
+/// and the file skipped. Returns the number of files removed.
+pub fn reclaim_stale_hugepages() -> usize {
+    let mut removed = 0;
+    for dir in ["/mnt/huge2M", "/mnt/huge1G"] {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                eprintln!("Skipping stale hugepages in {dir}: {e}");
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            // files are named nvme-<pid>-<id>
+            let name = entry.file_name();
+            let Some(pid) = name.to_str().and_then(|name| name.strip_prefix("nvme-")).and_then(|rest| rest.split('-').next()) else {
+                continue;
+            };
+            let Ok(pid) = pid.parse::<u32>() else {
+                continue;
+            };
+            if pid == process::id() || Path::new(&format!("/proc/{}", pid)).exists() {
+                continue;
+            }
+            match remove_unlocked(&entry.path()) {
+                Ok(true) => removed += 1,
+                Ok(false) => {}
+                Err(e) => eprintln!("Skipping stale hugepage file {:?}: {e}", entry.path()),
+            }
+        }
+    }
+    removed
+}
+
+// removes the file if no buffer holds its lock, the pid of another pid namespace may not be in /proc
+fn remove_unlocked(path: &Path) -> io::Result<bool> {
+    let file = fs::File::open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        return if e.kind() == io::ErrorKind::WouldBlock { Ok(false) } else { Err(e) };
+    }
+    fs::remove_file(path)?;
+    Ok(true)
+}
+
 /// Translates a virtual address to its physical counterpart
 pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
     let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
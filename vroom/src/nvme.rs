@@ -1,12 +1,17 @@
-use crate::cmd::NvmeCommand;
+use crate::cmd::{DsmRange, NvmeCommand, DSM_MAX_RANGES};
 use crate::memory::{Dma, DmaSlice};
 use crate::pci::pci_map_resource;
+use crate::vfio::{vfio_close, vfio_init, vfio_map_region, VFIO_PCI_BAR0_REGION_INDEX};
+use std::os::fd::RawFd;
 use crate::queues::*;
-use crate::{NvmeNamespace, NvmeStats, HUGE_PAGE_SIZE_2M};
+use crate::{Backend, NvmeErrorLogEntry, NvmeNamespace, NvmeSmartLog, NvmeStats, QueuePairStats, HUGE_PAGE_SIZE_2M};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Formatter};
 use std::hint::spin_loop;
+use std::mem::ManuallyDrop;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 // clippy doesnt like this
 #[allow(unused, clippy::upper_case_acronyms)]
@@ -93,10 +98,83 @@ struct IdentifyNamespaceData {
     vendor_specific: [u8; 3712],
 }
 
+/// NVMe spec 5.16.1.3
+/// SMART / Health Information log page
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+struct SmartLogData {
+    critical_warning: u8,
+    composite_temperature: u16,
+    available_spare: u8,
+    available_spare_threshold: u8,
+    percentage_used: u8,
+    endurance_group_critical_warning: u8,
+    _rsvd1: [u8; 25],
+    data_units_read: u128,
+    data_units_written: u128,
+    host_read_commands: u128,
+    host_write_commands: u128,
+    controller_busy_time: u128,
+    power_cycles: u128,
+    power_on_hours: u128,
+    unsafe_shutdowns: u128,
+    media_errors: u128,
+    num_err_log_entries: u128,
+    warning_temp_time: u32,
+    critical_temp_time: u32,
+    temp_sensors: [u16; 8],
+    thermal_mgmt_transition_count: [u32; 2],
+    thermal_mgmt_total_time: [u32; 2],
+    _rsvd2: [u8; 280],
+}
+
+/// NVMe spec 5.16.1.2
+/// Error Information log page entry
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+struct ErrorLogData {
+    error_count: u64,
+    sq_id: u16,
+    c_id: u16,
+    status: u16,
+    param_error_location: u16,
+    lba: u64,
+    ns_id: u32,
+    vendor_specific: u8,
+    transport_type: u8,
+    _rsvd1: [u8; 2],
+    command_specific: u64,
+    transport_specific: u16,
+    _rsvd2: [u8; 22],
+}
+
+const _: () = {
+    assert!(std::mem::size_of::<SmartLogData>() == 512);
+    assert!(std::mem::size_of::<ErrorLogData>() == 64);
+};
+
+const LOG_PAGE_ERROR_INFORMATION: u8 = 0x01;
+const LOG_PAGE_SMART: u8 = 0x02;
+
 pub struct NvmeQueuePair {
     pub id: u16,
-    pub sub_queue: NvmeSubQueue,
-    comp_queue: NvmeCompQueue,
+    // moved to the retired queue pairs on drop
+    pub sub_queue: ManuallyDrop<NvmeSubQueue>,
+    comp_queue: ManuallyDrop<NvmeCompQueue>,
+    pub stats: QueuePairStats,
+    // submitted commands that were not completed yet
+    outstanding: usize,
+    // dropped queue pairs, deleted by the device on its next admin operation
+    retired: Arc<Mutex<Vec<RetiredQueuePair>>>,
+}
+
+// a dropped queue pair, its memory stays allocated until the controller deleted the queues
+struct RetiredQueuePair {
+    id: u16,
+    _sub_queue: NvmeSubQueue,
+    _comp_queue: NvmeCompQueue,
 }
 
 impl Debug for NvmeQueuePair {
@@ -105,6 +183,7 @@ impl Debug for NvmeQueuePair {
             .field("id", &self.id)
             .field("sub_queue", &self.sub_queue)
             .field("comp_queue", &self.comp_queue)
+            .field("stats", &self.stats)
             .finish()
     }
 }
@@ -154,16 +233,122 @@ impl NvmeQueuePair {
                 return reqs;
             }
 
+            self.outstanding += 1;
+            if write {
+                self.stats.write_commands += 1;
+                self.stats.bytes_written += bytes;
+            } else {
+                self.stats.read_commands += 1;
+                self.stats.bytes_read += bytes;
+            }
             lba += blocks;
             reqs += 1;
         }
         reqs
     }
 
+    /// deallocates the lba `ranges` given as (start lba, number of lbas)
+    /// the range lists are written into `buffer`, 4KiB per command
+    /// returns amount of requests pushed into submission queue
+    pub fn submit_deallocate(&mut self, buffer: &mut Dma<u8>, ranges: &[(u64, u64)]) -> usize {
+        let mut dsm_ranges = Vec::new();
+        for &(mut lba, mut blocks) in ranges {
+            while blocks > 0 {
+                let length = std::cmp::min(blocks, u32::MAX as u64);
+                dsm_ranges.push(DsmRange {
+                    context_attributes: 0,
+                    length: length as u32,
+                    slba: lba,
+                });
+                lba += length;
+                blocks -= length;
+            }
+        }
+
+        let mut reqs = 0;
+        for (i, chunk) in dsm_ranges.chunks(DSM_MAX_RANGES).enumerate() {
+            let offset = i * 4096;
+            assert!(offset + 4096 <= buffer.size, "range buffer too small");
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    chunk.as_ptr() as *const u8,
+                    buffer.virt.add(offset),
+                    std::mem::size_of_val(chunk),
+                );
+            }
+
+            let entry = NvmeCommand::dataset_management(
+                self.id << 11 | self.sub_queue.tail as u16,
+                1,
+                (buffer.phys + offset) as u64,
+                0,
+                (chunk.len() - 1) as u8,
+                true,
+            );
+
+            if let Some(tail) = self.sub_queue.submit_checked(entry) {
+                unsafe {
+                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
+                }
+            } else {
+                eprintln!("queue full");
+                return reqs;
+            }
+            self.stats.other_commands += 1;
+            self.outstanding += 1;
+            reqs += 1;
+        }
+        reqs
+    }
+
+    /// zeroes `blocks` lbas starting at `lba`, at most 65536 lbas per command
+    /// returns amount of requests pushed into submission queue
+    pub fn submit_write_zeroes(&mut self, mut lba: u64, mut blocks: u64) -> usize {
+        let mut reqs = 0;
+        while blocks > 0 {
+            let nlb = std::cmp::min(blocks, 0x1_0000);
+            let entry = NvmeCommand::write_zeroes(
+                self.id << 11 | self.sub_queue.tail as u16,
+                1,
+                lba,
+                (nlb - 1) as u16,
+                false,
+            );
+
+            if let Some(tail) = self.sub_queue.submit_checked(entry) {
+                unsafe {
+                    std::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
+                }
+            } else {
+                eprintln!("queue full");
+                return reqs;
+            }
+
+            self.stats.other_commands += 1;
+            self.outstanding += 1;
+            lba += nlb;
+            blocks -= nlb;
+            reqs += 1;
+        }
+        reqs
+    }
+
+    pub fn set_completion_mode(&mut self, mode: CompletionMode) {
+        self.comp_queue.mode = mode;
+    }
+
+    pub fn completion_mode(&self) -> CompletionMode {
+        self.comp_queue.mode
+    }
+
     // TODO: maybe return result
     pub fn complete_io(&mut self, n: usize) -> Option<u16> {
         assert!(n > 0);
+        let start = Instant::now();
         let (tail, c_entry, _) = self.comp_queue.complete_n(n);
+        self.stats.completion_wait += start.elapsed();
+        self.stats.completions += n as u64;
+        self.outstanding = self.outstanding.saturating_sub(n);
         unsafe {
             std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
         }
@@ -182,11 +367,48 @@ impl NvmeQueuePair {
         Some(c_entry.sq_head)
     }
 
+    /// Like `complete_io`, but checks the status of every one of the `n` completions instead of the last one only
+    pub fn complete_io_checked(&mut self, n: usize) -> Option<u16> {
+        assert!(n > 0);
+        let start = Instant::now();
+        let mut failed = false;
+        let mut last = None;
+        for _ in 0..n {
+            let (tail, c_entry, _) = self.comp_queue.complete_spin();
+            let status = c_entry.status >> 1;
+            if status != 0 {
+                eprintln!(
+                    "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
+                    status,
+                    status & 0xFF,
+                    (status >> 8) & 0x7
+                );
+                eprintln!("{:?}", c_entry);
+                failed = true;
+            }
+            last = Some((tail, c_entry));
+        }
+        self.stats.completion_wait += start.elapsed();
+        self.stats.completions += n as u64;
+        self.outstanding = self.outstanding.saturating_sub(n);
+        let (tail, c_entry) = last.unwrap();
+        unsafe {
+            std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
+        }
+        self.sub_queue.head = c_entry.sq_head as usize;
+        if failed {
+            return None;
+        }
+        Some(c_entry.sq_head)
+    }
+
     pub fn quick_poll(&mut self) -> Option<()> {
         if let Some((tail, c_entry, _)) = self.comp_queue.complete() {
             unsafe {
                 std::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
             }
+            self.stats.completions += 1;
+            self.outstanding = self.outstanding.saturating_sub(1);
             self.sub_queue.head = c_entry.sq_head as usize;
             let status = c_entry.status >> 1;
             if status != 0 {
@@ -202,6 +424,23 @@ impl NvmeQueuePair {
         }
         None
     }
+
+    /// Waits for all submitted commands to complete
+    pub fn drain(&mut self) {
+        if self.outstanding > 0 {
+            self.complete_io(self.outstanding);
+        }
+    }
+}
+
+impl Drop for NvmeQueuePair {
+    // the controller keeps the queues until they are deleted, so their memory is handed over to the device
+    fn drop(&mut self) {
+        self.drain();
+        // the queues are taken exactly once, the pair is not used afterwards
+        let (sub_queue, comp_queue) = unsafe { (ManuallyDrop::take(&mut self.sub_queue), ManuallyDrop::take(&mut self.comp_queue)) };
+        self.retired.lock().unwrap().push(RetiredQueuePair { id: self.id, _sub_queue: sub_queue, _comp_queue: comp_queue });
+    }
 }
 
 #[allow(unused)]
@@ -209,6 +448,8 @@ pub struct NvmeDevice {
     pci_addr: String,
     addr: *mut u8,
     len: usize,
+    // vfio device the registers are mapped from, closed on drop
+    device_fd: Option<RawFd>,
     // Doorbell stride
     dstrd: u16,
     admin_sq: NvmeSubQueue,
@@ -220,6 +461,11 @@ pub struct NvmeDevice {
     pub namespaces: HashMap<u32, NvmeNamespace>,
     pub stats: NvmeStats,
     q_id: u16,
+    // ids of deleted queue pairs, reused before new ones
+    free_q_ids: Vec<u16>,
+    retired_q_pairs: Arc<Mutex<Vec<RetiredQueuePair>>>,
+    // Optional NVM Command Support
+    oncs: u16,
 }
 
 
@@ -240,21 +486,51 @@ impl Debug for NvmeDevice {
             .field("namespaces", &self.namespaces)
             .field("stats", &self.stats)
             .field("q_id", &self.q_id)
+            .field("oncs", &self.oncs)
             .finish()
     }
 }
 
+impl Drop for NvmeDevice {
+    // queue pairs still alive at this point are not deleted, their memory is freed when they are dropped
+    fn drop(&mut self) {
+        if let Err(e) = self.delete_retired_queue_pairs() {
+            eprintln!("Failed to delete i/o queue pairs: {}", e);
+        }
+        if let Some(fd) = self.device_fd.take() {
+            vfio_close(fd);
+        }
+    }
+}
+
 // TODO
 unsafe impl Send for NvmeDevice {}
 unsafe impl Sync for NvmeDevice {}
 
 #[allow(unused)]
 impl NvmeDevice {
-    pub fn init(pci_addr: &str) -> Result<Self, Box<dyn Error>> {
-        let (addr, len) = pci_map_resource(pci_addr)?;
+    pub fn init(pci_addr: &str, backend: Backend) -> Result<Self, Box<dyn Error>> {
+        // the vfio container has to be set up before any dma memory is allocated
+        let (addr, len, device_fd) = match backend.resolve(pci_addr) {
+            Backend::Vfio => {
+                let device_fd = vfio_init(pci_addr)?;
+                match vfio_map_region(device_fd, VFIO_PCI_BAR0_REGION_INDEX) {
+                    Ok((addr, len)) => (addr, len, Some(device_fd)),
+                    Err(e) => {
+                        vfio_close(device_fd);
+                        return Err(e);
+                    }
+                }
+            }
+            _ => {
+                let (addr, len) = pci_map_resource(pci_addr)?;
+                (addr, len, None)
+            }
+        };
         let mut dev = Self {
             pci_addr: pci_addr.to_string(),
             addr,
+            device_fd,
             dstrd: {
                 unsafe {
                     ((std::ptr::read_volatile(
@@ -273,6 +549,9 @@ impl NvmeDevice {
             namespaces: HashMap::new(),
             stats: NvmeStats::default(),
             q_id: 1,
+            free_q_ids: Vec::new(),
+            retired_q_pairs: Arc::new(Mutex::new(Vec::new())),
+            oncs: 0,
         };
 
         for i in 1..512 {
@@ -337,12 +616,12 @@ impl NvmeDevice {
 
         let q_id = dev.q_id;
         let addr = dev.io_cq.get_addr();
-        println!("Requesting i/o completion queue");
+        log::debug!("Requesting i/o completion queue");
         let comp = dev.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::create_io_completion_queue(c_id, q_id, addr, (QUEUE_LENGTH - 1) as u16)
         })?;
         let addr = dev.io_sq.get_addr();
-        println!("Requesting i/o submission queue");
+        log::debug!("Requesting i/o submission queue");
         let comp = dev.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::create_io_submission_queue(
                 c_id,
@@ -388,20 +667,34 @@ impl NvmeDevice {
             firmware.push(b as char);
         }
 
+        let oncs = u16::from_le_bytes(data[520..522].try_into().unwrap());
+
         println!(
             "  - Model: {} Serial: {} Firmware: {}",
             model.trim(),
             serial.trim(),
             firmware.trim()
         );
+        self.oncs = oncs;
 
         Ok(())
     }
 
+    /// controller supports the Dataset Management command (ONCS bit 2)
+    pub fn supports_dataset_management(&self) -> bool {
+        self.oncs & (1 << 2) != 0
+    }
+
+    /// controller supports the Write Zeroes command (ONCS bit 3)
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.oncs & (1 << 3) != 0
+    }
+
     // 1 to 1 Submission/Completion Queue Mapping
     pub fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair, Box<dyn Error>> {
-        let q_id = self.q_id;
-        println!("Requesting i/o queue pair with id {q_id}");
+        self.delete_retired_queue_pairs()?;
+        let q_id = self.free_q_ids.pop().unwrap_or(self.q_id);
+        log::debug!("Requesting i/o queue pair with id {q_id}");
 
         let offset = 0x1000 + ((4 << self.dstrd) * (2 * q_id + 1) as usize);
         assert!(offset <= self.len - 4, "SQ doorbell offset out of bounds");
@@ -430,21 +723,107 @@ impl NvmeDevice {
             )
         })?;
 
-        self.q_id += 1;
+        if q_id == self.q_id {
+            self.q_id += 1;
+        }
         Ok(NvmeQueuePair {
             id: q_id,
-            sub_queue,
-            comp_queue,
+            sub_queue: ManuallyDrop::new(sub_queue),
+            comp_queue: ManuallyDrop::new(comp_queue),
+            stats: QueuePairStats::default(),
+            outstanding: 0,
+            retired: Arc::clone(&self.retired_q_pairs),
         })
     }
 
+    /// Waits for the outstanding commands of `qpair` and deletes it, dropping it does the same lazily
     pub fn delete_io_queue_pair(&mut self, qpair: NvmeQueuePair) -> Result<(), Box<dyn Error>> {
-        println!("Deleting i/o queue pair with id {}", qpair.id);
-        self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::delete_io_submission_queue(c_id, qpair.id)
-        })?;
-        self.submit_and_complete_admin(|c_id, _| {
-            NvmeCommand::delete_io_completion_queue(c_id, qpair.id)
+        drop(qpair);
+        self.delete_retired_queue_pairs()
+    }
+
+    // deletes the queue pairs that were dropped since the last call and frees their memory,
+    // a queue pair that failed to be deleted is kept with its memory
+    fn delete_retired_queue_pairs(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            let Some(qpair) = self.retired_q_pairs.lock().unwrap().pop() else {
+                return Ok(());
+            };
+            let q_id = qpair.id;
+            log::debug!("Deleting i/o queue pair with id {}", q_id);
+            let deleted = self
+                .submit_and_complete_admin(|c_id, _| NvmeCommand::delete_io_submission_queue(c_id, q_id))
+                .and_then(|_| self.submit_and_complete_admin(|c_id, _| NvmeCommand::delete_io_completion_queue(c_id, q_id)));
+            if let Err(e) = deleted {
+                self.retired_q_pairs.lock().unwrap().push(qpair);
+                return Err(e);
+            }
+            self.free_q_ids.push(q_id);
+        }
+    }
+
+    /// Reads the controller wide SMART / Health Information log page
+    pub fn get_smart_log(&mut self) -> Result<NvmeSmartLog, Box<dyn Error>> {
+        self.get_log_page(LOG_PAGE_SMART, 0xFFFF_FFFF, std::mem::size_of::<SmartLogData>())?;
+        let data: SmartLogData = unsafe { *(self.buffer.virt as *const SmartLogData) };
+
+        Ok(NvmeSmartLog {
+            critical_warning: data.critical_warning,
+            temperature: data.composite_temperature,
+            available_spare: data.available_spare,
+            available_spare_threshold: data.available_spare_threshold,
+            percentage_used: data.percentage_used,
+            data_units_read: data.data_units_read,
+            data_units_written: data.data_units_written,
+            host_read_commands: data.host_read_commands,
+            host_write_commands: data.host_write_commands,
+            controller_busy_time: data.controller_busy_time,
+            power_cycles: data.power_cycles,
+            power_on_hours: data.power_on_hours,
+            unsafe_shutdowns: data.unsafe_shutdowns,
+            media_errors: data.media_errors,
+            error_log_entries: data.num_err_log_entries,
+            warning_temperature_time: data.warning_temp_time,
+            critical_temperature_time: data.critical_temp_time,
+            temperature_sensors: data.temp_sensors,
+            thermal_transition_counts: data.thermal_mgmt_transition_count,
+            thermal_transition_times: data.thermal_mgmt_total_time,
+        })
+    }
+
+    /// Reads up to `entries` (max. 128) entries of the Error Information log page.
+    /// Unused entries (error count 0) are skipped.
+    pub fn get_error_log(&mut self, entries: usize) -> Result<Vec<NvmeErrorLogEntry>, Box<dyn Error>> {
+        let entry_size = std::mem::size_of::<ErrorLogData>();
+        let entries = entries.clamp(1, 2 * 4096 / entry_size);
+        self.get_log_page(LOG_PAGE_ERROR_INFORMATION, 0xFFFF_FFFF, entries * entry_size)?;
+
+        let data: &[ErrorLogData] =
+            unsafe { std::slice::from_raw_parts(self.buffer.virt as *const ErrorLogData, entries) };
+
+        Ok(data
+            .iter()
+            .filter(|e| e.error_count != 0)
+            .map(|e| NvmeErrorLogEntry {
+                error_count: e.error_count,
+                sq_id: e.sq_id,
+                c_id: e.c_id,
+                status: e.status,
+                parameter_error_location: e.param_error_location,
+                lba: e.lba,
+                ns_id: e.ns_id,
+                command_specific: e.command_specific,
+            })
+            .collect())
+    }
+
+    /// Transfers `bytes` (max. 8KiB) of log page `lid` into `self.buffer`
+    fn get_log_page(&mut self, lid: u8, ns_id: u32, bytes: usize) -> Result<(), Box<dyn Error>> {
+        assert!(bytes > 0 && bytes % 4 == 0 && bytes <= 2 * 4096);
+        let numd = (bytes / 4 - 1) as u32;
+        self.submit_and_complete_admin(|c_id, addr| {
+            let ptr1 = if bytes <= 4096 { 0 } else { (addr + 4096) as u64 };
+            NvmeCommand::get_log_page(c_id, ns_id, numd, addr as u64, ptr1, lid, 0)
         })?;
         Ok(())
     }
@@ -488,10 +867,14 @@ impl NvmeDevice {
         // TODO: check metadata?
         println!("Namespace {id}, Size: {size}, Blocks: {blocks}, Block size: {block_size}");
 
+        // DLFEAT bits 2:0 = 001b -> deallocated blocks read as zeroes
+        let deallocated_read_zeroes = namespace_data.dlfeat & 0x7 == 1;
+
         let namespace = NvmeNamespace {
             id,
             blocks,
             block_size,
+            deallocated_read_zeroes,
         };
         self.namespaces.insert(id, namespace);
         namespace
@@ -545,6 +928,42 @@ impl NvmeDevice {
         Ok(())
     }
 
+    /// deallocates `blocks` lbas starting at `lba`, afterwards reads of the range are undefined
+    // TODO: currently namespace 1 is hardcoded
+    pub fn deallocate(&mut self, mut lba: u64, mut blocks: u64) -> Result<(), Box<dyn Error>> {
+        let q_id = 1;
+        while blocks > 0 {
+            let length = std::cmp::min(blocks, u32::MAX as u64);
+            let range = DsmRange {
+                context_attributes: 0,
+                length: length as u32,
+                slba: lba,
+            };
+            unsafe {
+                std::ptr::write_unaligned(self.buffer.virt as *mut DsmRange, range);
+            }
+
+            let entry = NvmeCommand::dataset_management(
+                self.io_sq.tail as u16,
+                1,
+                self.buffer.phys as u64,
+                0,
+                0,
+                true,
+            );
+            let tail = self.io_sq.submit(entry);
+            self.stats.submissions += 1;
+            self.write_reg_idx(NvmeArrayRegs::SQyTDBL, q_id as u16, tail as u32);
+            self.io_sq.head = self
+                .complete_io(1)
+                .ok_or("dataset management command failed")? as usize;
+
+            lba += length;
+            blocks -= length;
+        }
+        Ok(())
+    }
+
     fn submit_io(
         &mut self,
         ns: &NvmeNamespace,
@@ -3,6 +3,7 @@ use crate::memory::*;
 use std::error::Error;
 use std::fmt::Debug;
 use std::hint::spin_loop;
+use std::time::{Duration, Instant};
 
 /// NVMe spec 4.6
 /// Completion queue entry
@@ -27,6 +28,19 @@ pub struct NvmeCompletion {
 /// maximum amount of submission entries on a 2MiB huge page
 pub const QUEUE_LENGTH: usize = 1024;
 
+/// waits shorter than this are always polled, sleeping has a granularity of ~50us
+pub const HYBRID_MIN_SLEEP: Duration = Duration::from_micros(50);
+
+/// How a completion queue waits for completions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompletionMode {
+    /// Busy-poll the completion queue
+    #[default]
+    Poll,
+    /// Sleep for half of the average wait time, then busy-poll
+    Hybrid,
+}
+
 /// Submission queue
 pub struct NvmeSubQueue {
     // TODO: switch to mempool for larger queue
@@ -96,6 +110,9 @@ pub struct NvmeCompQueue {
     phase: bool,
     len: usize,
     pub doorbell: usize,
+    pub mode: CompletionMode,
+    // exponentially weighted moving average of the time spent waiting
+    mean_wait: Duration,
 }
 
 impl Debug for NvmeCompQueue {
@@ -105,6 +122,8 @@ impl Debug for NvmeCompQueue {
             .field("phase", &self.phase)
             .field("len", &self.len)
             .field("doorbell", &self.doorbell)
+            .field("mode", &self.mode)
+            .field("mean_wait", &self.mean_wait)
             .finish()
     }
 }
@@ -118,6 +137,8 @@ impl NvmeCompQueue {
             phase: true,
             len: len.min(QUEUE_LENGTH),
             doorbell,
+            mode: CompletionMode::Poll,
+            mean_wait: Duration::ZERO,
         })
     }
 
@@ -153,6 +174,30 @@ impl NvmeCompQueue {
 
     #[inline(always)]
     pub fn complete_spin(&mut self) -> (usize, NvmeCompletion, usize) {
+        match self.mode {
+            CompletionMode::Poll => self.complete_poll(),
+            CompletionMode::Hybrid => self.complete_hybrid(),
+        }
+    }
+
+    fn complete_hybrid(&mut self) -> (usize, NvmeCompletion, usize) {
+        if let Some(val) = self.complete() {
+            return val;
+        }
+
+        let start = Instant::now();
+        let sleep = self.mean_wait / 2;
+        if sleep >= HYBRID_MIN_SLEEP {
+            std::thread::sleep(sleep);
+        }
+        let val = self.complete_poll();
+
+        self.mean_wait = (self.mean_wait * 7 + start.elapsed()) / 8;
+        val
+    }
+
+    #[inline(always)]
+    fn complete_poll(&mut self) -> (usize, NvmeCompletion, usize) {
         loop {
             if let Some(val) = self.complete() {
                 return val;
@@ -0,0 +1,295 @@
+use crate::memory::{HUGE_PAGE_SIZE_1G, IOVA_WIDTH, VFIO_CONTAINER_FILE_DESCRIPTOR, VFIO_GROUP_FILE_DESCRIPTORS};
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::fd::{IntoRawFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+
+// from linux/vfio.h, _IO(VFIO_TYPE, VFIO_BASE + n) with VFIO_TYPE = ';' and VFIO_BASE = 100
+const VFIO_GET_API_VERSION: u64 = 0x3B64;
+const VFIO_CHECK_EXTENSION: u64 = 0x3B65;
+const VFIO_SET_IOMMU: u64 = 0x3B66;
+const VFIO_GROUP_GET_STATUS: u64 = 0x3B67;
+const VFIO_GROUP_SET_CONTAINER: u64 = 0x3B68;
+const VFIO_GROUP_GET_DEVICE_FD: u64 = 0x3B6A;
+const VFIO_DEVICE_GET_REGION_INFO: u64 = 0x3B6C;
+const VFIO_IOMMU_MAP_DMA: u64 = 0x3B71;
+const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3B72;
+
+const VFIO_API_VERSION: i32 = 0;
+const VFIO_TYPE1_IOMMU: u64 = 1;
+const VFIO_GROUP_FLAGS_VIABLE: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_READ: u32 = 1 << 0;
+const VFIO_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+pub(crate) const VFIO_PCI_BAR0_REGION_INDEX: u32 = 0;
+pub(crate) const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+
+// write to the command register (offset 4) in the PCIe config space
+const COMMAND_REGISTER_OFFSET: u64 = 4;
+// bit 2: "bus master enable", see PCIe 3.0 specification section 7.5.1.1
+const BUS_MASTER_ENABLE_BIT: u16 = 2;
+
+// iovas of the mappings, the first one handed out is 1GiB as 0 is kept unmapped to catch null pointers
+static IOVA_SPACE: Mutex<IovaSpace> = Mutex::new(IovaSpace { next: HUGE_PAGE_SIZE_1G, free: Vec::new() });
+
+/// Bump allocator of iovas. Ranges of unmapped memory are reused by mappings of the same size,
+/// which keeps their alignment, and given back to the bump pointer if they are at its end.
+struct IovaSpace {
+    next: usize,
+    free: Vec<(usize, usize)>,
+}
+
+impl IovaSpace {
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        if let Some(i) = self.free.iter().position(|&(_, free)| free == size) {
+            return Some(self.free.swap_remove(i).0);
+        }
+        // keep iovas aligned to the mapping size so huge pages stay aligned
+        let align = size.next_power_of_two();
+        let iova = (self.next + align - 1) & !(align - 1);
+        if iova + size > 1 << IOVA_WIDTH {
+            return None;
+        }
+        self.next = iova + size;
+        Some(iova)
+    }
+
+    fn release(&mut self, iova: usize, size: usize) {
+        self.free.push((iova, size));
+        while let Some(i) = self.free.iter().position(|&(free, size)| free + size == self.next) {
+            self.next = self.free.swap_remove(i).0;
+        }
+    }
+}
+
+#[repr(C)]
+struct VfioGroupStatus {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct VfioIommuType1DmaMap {
+    argsz: u32,
+    flags: u32,
+    vaddr: u64,
+    iova: u64,
+    size: u64,
+}
+
+#[repr(C)]
+struct VfioIommuType1DmaUnmap {
+    argsz: u32,
+    flags: u32,
+    iova: u64,
+    size: u64,
+}
+
+#[repr(C)]
+struct VfioRegionInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+/// Returns true if the device at `pci_addr` is bound to the vfio-pci driver.
+pub fn is_bound_to_vfio(pci_addr: &str) -> bool {
+    let path = format!("/sys/bus/pci/devices/{}/driver", pci_addr);
+    fs::read_link(path)
+        .ok()
+        .and_then(|driver| driver.file_name().map(|name| name == "vfio-pci"))
+        .unwrap_or(false)
+}
+
+/// Attaches the iommu group of the device at `pci_addr` to the vfio container and returns the device file descriptor.
+/// Opens the container and configures the type 1 iommu on first use.
+pub(crate) fn vfio_init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
+    let group_link = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr))?;
+    let group: i32 = group_link
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("invalid iommu group")?
+        .parse()?;
+
+    let first_container = unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.is_none();
+    let container_fd = match unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR } {
+        Some(fd) => fd,
+        None => {
+            let fd = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/vfio/vfio")?
+                .into_raw_fd();
+
+            if unsafe { libc::ioctl(fd, VFIO_GET_API_VERSION as _) } != VFIO_API_VERSION {
+                return Err("unknown vfio api version".into());
+            }
+            if unsafe { libc::ioctl(fd, VFIO_CHECK_EXTENSION as _, VFIO_TYPE1_IOMMU) } != 1 {
+                return Err("vfio type 1 iommu is not supported".into());
+            }
+            fd
+        }
+    };
+
+    let mut groups = VFIO_GROUP_FILE_DESCRIPTORS.lock().unwrap();
+    let group_fd = match groups.get(&group) {
+        Some(&fd) => fd,
+        None => {
+            let fd = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(format!("/dev/vfio/{}", group))?
+                .into_raw_fd();
+
+            let mut status = VfioGroupStatus {
+                argsz: std::mem::size_of::<VfioGroupStatus>() as u32,
+                flags: 0,
+            };
+            if unsafe { libc::ioctl(fd, VFIO_GROUP_GET_STATUS as _, &mut status) } == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            if status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
+                return Err(format!(
+                    "iommu group {} is not viable, are all devices in the group bound to vfio?",
+                    group
+                )
+                .into());
+            }
+
+            if unsafe { libc::ioctl(fd, VFIO_GROUP_SET_CONTAINER as _, &container_fd) } == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            groups.insert(group, fd);
+            fd
+        }
+    };
+
+    // the iommu can only be set after the first group has been added to the container
+    if first_container {
+        if unsafe { libc::ioctl(container_fd, VFIO_SET_IOMMU as _, VFIO_TYPE1_IOMMU) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        unsafe {
+            VFIO_CONTAINER_FILE_DESCRIPTOR = Some(container_fd);
+        }
+    }
+
+    let name = CString::new(pci_addr)?;
+    let device_fd = unsafe { libc::ioctl(group_fd, VFIO_GROUP_GET_DEVICE_FD as _, name.as_ptr()) };
+    if device_fd == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    if let Err(e) = vfio_enable_dma(device_fd) {
+        vfio_close(device_fd);
+        return Err(e);
+    }
+
+    Ok(device_fd)
+}
+
+fn vfio_region_info(device_fd: RawFd, index: u32) -> Result<VfioRegionInfo, Box<dyn Error>> {
+    let mut info = VfioRegionInfo {
+        argsz: std::mem::size_of::<VfioRegionInfo>() as u32,
+        flags: 0,
+        index,
+        cap_offset: 0,
+        size: 0,
+        offset: 0,
+    };
+    if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_GET_REGION_INFO as _, &mut info) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(info)
+}
+
+/// Enables direct memory access through the config region of the vfio device.
+fn vfio_enable_dma(device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let info = vfio_region_info(device_fd, VFIO_PCI_CONFIG_REGION_INDEX)?;
+    let offset = (info.offset + COMMAND_REGISTER_OFFSET) as libc::off_t;
+
+    let mut command = 0u16;
+    if unsafe { libc::pread(device_fd, &mut command as *mut u16 as *mut libc::c_void, 2, offset) } != 2 {
+        return Err(io::Error::last_os_error().into());
+    }
+    command |= 1 << BUS_MASTER_ENABLE_BIT;
+    if unsafe { libc::pwrite(device_fd, &command as *const u16 as *const libc::c_void, 2, offset) } != 2 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Mmaps the region `index` of the vfio device and returns a pointer to the mapped memory.
+pub(crate) fn vfio_map_region(device_fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dyn Error>> {
+    let info = vfio_region_info(device_fd, index)?;
+    let len = info.size as usize;
+
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            device_fd,
+            info.offset as libc::off_t,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED || len == 0 {
+        Err("vfio region mapping failed".into())
+    } else {
+        Ok((ptr as *mut u8, len))
+    }
+}
+
+/// Maps `size` bytes at `vaddr` into the iommu and returns the allocated iova.
+/// The iova range is contiguous, even if the memory is not physically contiguous.
+pub(crate) fn vfio_map_dma(vaddr: usize, size: usize) -> Result<usize, Box<dyn Error>> {
+    let container_fd = unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.ok_or("vfio is not initialized")?;
+
+    let iova = IOVA_SPACE.lock().unwrap().allocate(size).ok_or("iova space exhausted")?;
+
+    let mut map = VfioIommuType1DmaMap {
+        argsz: std::mem::size_of::<VfioIommuType1DmaMap>() as u32,
+        flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+        vaddr: vaddr as u64,
+        iova: iova as u64,
+        size: size as u64,
+    };
+    if unsafe { libc::ioctl(container_fd, VFIO_IOMMU_MAP_DMA as _, &mut map) } == -1 {
+        let error = io::Error::last_os_error();
+        IOVA_SPACE.lock().unwrap().release(iova, size);
+        return Err(error.into());
+    }
+    Ok(iova)
+}
+
+/// Removes the iommu mapping of `size` bytes at `iova`.
+pub(crate) fn vfio_unmap_dma(iova: usize, size: usize) -> Result<(), Box<dyn Error>> {
+    let container_fd = unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR }.ok_or("vfio is not initialized")?;
+
+    let mut unmap = VfioIommuType1DmaUnmap {
+        argsz: std::mem::size_of::<VfioIommuType1DmaUnmap>() as u32,
+        flags: 0,
+        iova: iova as u64,
+        size: size as u64,
+    };
+    if unsafe { libc::ioctl(container_fd, VFIO_IOMMU_UNMAP_DMA as _, &mut unmap) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    IOVA_SPACE.lock().unwrap().release(iova, size);
+    Ok(())
+}
+
+/// Closes a device file descriptor returned by `vfio_init`.
+pub(crate) fn vfio_close(device_fd: RawFd) {
+    if unsafe { libc::close(device_fd) } == -1 {
+        eprintln!("Failed to close vfio device: {}", io::Error::last_os_error());
+    }
+}